@@ -0,0 +1,28 @@
+//! 构建时把 data/emoji.tsv 中的短代码表生成为排好序的静态数组, 运行时无需读取文件
+
+use std::{env, fs, path::Path};
+
+fn main() {
+    let source = "data/emoji.tsv";
+    println!("cargo:rerun-if-changed={}", source);
+    let table = fs::read_to_string(source).expect("无法读取 emoji 短代码表");
+    let mut entries: Vec<(&str, &str)> = table
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once('\t')
+                .unwrap_or_else(|| panic!("emoji 短代码表格式错误: {}", line))
+        })
+        .collect();
+    entries.sort_unstable_by_key(|&(name, _)| name);
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        panic!("emoji 短代码重复: {}", pair[0].0);
+    }
+    let mut code = String::from("/// 按短代码排序的 emoji 表, 由 build.rs 生成\npub static EMOJI: &[(&str, &str)] = &[\n");
+    for (name, emoji) in entries {
+        code.push_str(&format!("    ({:?}, {:?}),\n", name, emoji));
+    }
+    code.push_str("];\n");
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("emoji.rs");
+    fs::write(out, code).expect("无法写入 emoji 短代码表");
+}
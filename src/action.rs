@@ -0,0 +1,139 @@
+use anyhow::{bail, Result};
+use regex::{Captures, Regex};
+use std::{
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::template::{Counts, Template};
+
+/// 限制动作的执行频率: 每个间隔内最多执行一次, 期间的其余匹配直接跳过
+struct RateLimit {
+    interval: Duration,
+    last_run: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_run: Mutex::new(None),
+        }
+    }
+
+    /// 距上次执行已超过间隔时记录本次执行并返回 true
+    fn allow(&self) -> bool {
+        let mut last_run = self.last_run.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if last_run.is_some_and(|last| now.duration_since(last) < self.interval) {
+            return false;
+        }
+        *last_run = Some(now);
+        true
+    }
+}
+
+/// 规则匹配时在后台运行的 on_match 命令
+///
+/// 命令不影响输出, 匹配的文本通过环境变量 `CLITHEME_MATCH`、`CLITHEME_CAPTURE_1` 等
+/// (命名捕获组为 `CLITHEME_CAPTURE_<名称>`) 传入; 每条规则在 interval 内最多运行一次
+pub struct MatchHook {
+    argv: Vec<String>,
+    limit: RateLimit,
+    /// 每条规则只警告一次, 避免每处匹配都输出警告
+    warned: AtomicBool,
+}
+
+impl MatchHook {
+    const DEFAULT_INTERVAL: Duration = Duration::from_millis(1000);
+
+    pub fn new(argv: &[String], interval_ms: Option<u64>) -> Result<Self> {
+        if argv.is_empty() {
+            bail!("on_match 不能为空");
+        }
+        Ok(Self {
+            argv: argv.to_vec(),
+            limit: RateLimit::new(interval_ms.map_or(Self::DEFAULT_INTERVAL, Duration::from_millis)),
+            warned: AtomicBool::new(false),
+        })
+    }
+
+    /// 启动命令后立即返回, 距上次运行不足 interval 时跳过
+    pub fn fire(&self, pattern: &Regex, caps: &Captures, label: &str) {
+        if !self.limit.allow() {
+            return;
+        }
+        let mut command = Command::new(&self.argv[0]);
+        command
+            .args(&self.argv[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .env("CLITHEME_MATCH", &caps[0]);
+        for (index, group) in caps.iter().enumerate().skip(1) {
+            command.env(format!("CLITHEME_CAPTURE_{}", index), group.map_or("", |m| m.as_str()));
+        }
+        for name in pattern.capture_names().flatten() {
+            command.env(format!("CLITHEME_CAPTURE_{}", name), caps.name(name).map_or("", |m| m.as_str()));
+        }
+        match command.spawn() {
+            // 在单独的线程中等待命令结束, 避免留下僵尸进程
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => {
+                if !self.warned.swap(true, Ordering::Relaxed) {
+                    eprintln!("警告: 规则 {} 的 on_match 命令 {} 无法运行: {}", label, self.argv[0], e);
+                }
+            }
+        }
+    }
+}
+
+/// 桌面通知不可用 (如没有通知服务) 时不再尝试, 所有规则共用
+static NOTIFY_UNAVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// 规则匹配时发送的桌面通知
+pub struct Notifier {
+    title: Template,
+    body: Template,
+    limit: RateLimit,
+}
+
+impl Notifier {
+    const DEFAULT_INTERVAL: Duration = Duration::from_millis(10_000);
+
+    pub fn new(title: Template, body: Template, interval_ms: Option<u64>) -> Self {
+        Self {
+            title,
+            body,
+            limit: RateLimit::new(interval_ms.map_or(Self::DEFAULT_INTERVAL, Duration::from_millis)),
+        }
+    }
+
+    /// 标题和正文中用到的模板, 用于检查捕获引用
+    pub fn templates(&self) -> [&Template; 2] {
+        [&self.title, &self.body]
+    }
+
+    /// 在后台线程中发送通知, 失败时在标准错误输出一次通知内容, 之后不再尝试
+    pub fn fire(&self, caps: &Captures, counts: Counts) {
+        if NOTIFY_UNAVAILABLE.load(Ordering::Relaxed) || !self.limit.allow() {
+            return;
+        }
+        let title = self.title.render(caps, counts, false);
+        let body = self.body.render(caps, counts, false);
+        std::thread::spawn(move || {
+            let result = notify_rust::Notification::new().summary(&title).body(&body).show();
+            if let Err(e) = result
+                && !NOTIFY_UNAVAILABLE.swap(true, Ordering::Relaxed)
+            {
+                eprintln!("警告: 无法发送桌面通知, 不再尝试 ({}): {}: {}", e, title, body);
+            }
+        });
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::Write;
+use std::time::Duration;
+
+use crate::config::{LocalizedText, Theme};
+use crate::filter::locale_chain;
+use crate::rule::Stream;
+use crate::segment::OutputEncoding;
+use crate::style;
+
+/// 主题的 header 或 footer, 已按语言环境选好文本
+#[derive(Debug, Clone)]
+pub struct Banner {
+    text: String,
+    stream: Stream,
+}
+
+impl Banner {
+    /// 按主题顺序收集所有主题的 header 和 footer
+    pub fn collect(themes: &[Theme], locale: &str, fallback: bool) -> (Vec<Banner>, Vec<Banner>) {
+        let select = |text: &Option<LocalizedText>, stream: Option<Stream>| {
+            let text = select_text(text.as_ref()?, locale, fallback)?;
+            Some(Banner {
+                text: text.to_string(),
+                stream: stream.unwrap_or(Stream::Stdout),
+            })
+        };
+        let headers = themes
+            .iter()
+            .filter_map(|theme| select(&theme.metadata.header, theme.metadata.banner_stream))
+            .collect();
+        let footers = themes
+            .iter()
+            .filter_map(|theme| select(&theme.metadata.footer, theme.metadata.banner_stream))
+            .collect();
+        (headers, footers)
+    }
+
+    pub fn stream(&self) -> Stream {
+        self.stream
+    }
+
+    /// 展开占位符后按输出编码输出, 末尾没有换行时补上换行; 返回输出的内容, 用于录像
+    pub fn print(&self, values: &[(&str, String)], color: bool, encoding: Option<&OutputEncoding>) -> Vec<u8> {
+        let mut text = render(&self.text, values, color);
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let bytes = match encoding {
+            Some(encoding) => {
+                let mut encoder = encoding.encoder();
+                let mut bytes = Vec::new();
+                encoder.encode(&text, &mut bytes);
+                encoder.finish(&mut bytes);
+                bytes
+            }
+            None => text.into_bytes(),
+        };
+        let _ = match self.stream {
+            Stream::Stdout => std::io::stdout().write_all(&bytes),
+            Stream::Stderr => std::io::stderr().write_all(&bytes),
+        };
+        bytes
+    }
+}
+
+/// 按回退链选择最具体的语言环境版本, "*" 适用于所有语言环境
+fn select_text<'a>(text: &'a LocalizedText, locale: &str, fallback: bool) -> Option<&'a str> {
+    match text {
+        LocalizedText::One(text) => Some(text),
+        LocalizedText::Localized(texts) => locale_chain(locale, fallback)
+            .iter()
+            .map(String::as_str)
+            .chain(["*"])
+            .find_map(|locale| texts.get(locale))
+            .map(String::as_str),
+    }
+}
+
+/// 展开 `{command}` 等占位符和 `{red}` 等颜色标签, `{{` 表示字面的 `{`, 未知的占位符原样保留
+fn render(text: &str, values: &[(&str, String)], color: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{{") {
+            result.push('{');
+            rest = after;
+            continue;
+        }
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[1..end];
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => result.push_str(value),
+            None => match style::tag(name) {
+                Ok(Some(escape)) => {
+                    if color {
+                        result.push_str(&escape);
+                    }
+                }
+                _ => result.push_str(&rest[..=end]),
+            },
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// 把耗时转换为 `3.2s` 或 `2m05s` 形式
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    if seconds < 60.0 {
+        format!("{:.1}s", seconds)
+    } else {
+        let seconds = elapsed.as_secs();
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    }
+}
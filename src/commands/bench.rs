@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::config::{load_themes, LoadOptions, MatchMode};
+use crate::filter::RuleFilters;
+use crate::rule::{apply_replacements, CommandContext, Counters, ReplaceState, ReplacementRule, SharedRules, Stream};
+use crate::segment::{parse_encoding, DEFAULT_MAX_LINE_LENGTH};
+use crate::{collect_rules, process_stream, ColorChoice, RunOptions, DEFAULT_MULTILINE_HOLD};
+
+/// bench 子命令的选项
+pub struct BenchOptions {
+    /// 作为哪个命令的输出处理, 用于匹配 filter_commands 和 args_pattern
+    pub as_command: Option<String>,
+    /// 每项测量运行的次数, 取最快的一次
+    pub iterations: usize,
+    /// 以 JSON 输出结果
+    pub json: bool,
+    pub locale: String,
+    pub fallback: bool,
+}
+
+/// 一次完整处理的吞吐量
+#[derive(Serialize)]
+struct Throughput {
+    seconds: f64,
+    lines_per_sec: f64,
+    mb_per_sec: f64,
+}
+
+/// 单独应用一条规则的耗时
+#[derive(Serialize)]
+struct RuleTime<'a> {
+    rule: &'a str,
+    seconds: f64,
+    /// 占所有规则耗时之和的比例
+    share: f64,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    input: &'a Path,
+    lines: usize,
+    bytes: usize,
+    iterations: usize,
+    /// 没有规则时的处理
+    baseline: Throughput,
+    theme: Throughput,
+    rules: Vec<RuleTime<'a>>,
+}
+
+/// 不运行子进程, 把文件当作标准输出经过完整的处理 (输出丢弃), 与没有规则时的处理比较吞吐量,
+/// 并分别测量每条单行规则的耗时; on_match、notify 等动作不会执行
+pub async fn run(input: &Path, themes: &[PathBuf], load: &LoadOptions, options: BenchOptions) -> Result<i32> {
+    let data = std::fs::read(input).with_context(|| format!("无法读取 {}", input.display()))?;
+    let themes = load_themes(themes, load)?;
+    let filters = RuleFilters {
+        only: Vec::new(),
+        skip: Vec::new(),
+        enable_groups: Vec::new(),
+        disable_groups: Vec::new(),
+        locale: options.locale,
+        fallback: options.fallback,
+        now: None,
+        verbose: false,
+    };
+    let input_encoding = themes
+        .iter()
+        .find_map(|theme| theme.metadata.input_encoding.as_deref())
+        .and_then(|label| parse_encoding(label).ok());
+    let run_options = RunOptions {
+        color: ColorChoice::Always,
+        mode: MatchMode::of(&themes),
+        strip_ansi: themes.iter().any(|theme| theme.metadata.strip_ansi == Some(true)),
+        max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        multiline_hold: DEFAULT_MULTILINE_HOLD,
+        input_encoding,
+        stderr_input_encoding: input_encoding,
+        no_actions: true,
+        ..Default::default()
+    };
+    let rules = collect_rules(themes, &filters)?;
+    let command = options.as_command.unwrap_or_default();
+    let (name, args) = command.split_once(' ').unwrap_or((&command, ""));
+    let context = CommandContext::new(name, args.to_string());
+
+    let text = String::from_utf8_lossy(&data);
+    let lines: Vec<&str> = text.lines().collect();
+    let iterations = options.iterations;
+    let pipeline = |rules: SharedRules| {
+        let (data, context, run_options) = (&data, &context, &run_options);
+        async move {
+            let (abort, _) = mpsc::unbounded_channel();
+            let start = Instant::now();
+            process_stream(&data[..], tokio::io::sink(), context, rules, Stream::Stdout, run_options, abort).await?;
+            Ok::<_, anyhow::Error>(start.elapsed())
+        }
+    };
+    let mut baseline = Duration::MAX;
+    let mut themed = Duration::MAX;
+    let shared = SharedRules::new(rules.clone());
+    for _ in 0..iterations {
+        baseline = baseline.min(pipeline(SharedRules::new(Vec::new())).await?);
+        themed = themed.min(pipeline(shared.clone()).await?);
+    }
+
+    let single_line: Vec<&ReplacementRule> = rules.iter().filter(|rule| rule.window.is_none()).collect();
+    let times: Vec<Duration> = single_line.iter().map(|rule| time_rule(rule, &lines, &context, iterations)).collect();
+    let total: f64 = times.iter().map(Duration::as_secs_f64).sum();
+    let report = Report {
+        input,
+        lines: lines.len(),
+        bytes: data.len(),
+        iterations,
+        baseline: throughput(baseline, lines.len(), data.len()),
+        theme: throughput(themed, lines.len(), data.len()),
+        rules: single_line
+            .iter()
+            .zip(&times)
+            .map(|(rule, time)| RuleTime {
+                rule: rule.label(),
+                seconds: time.as_secs_f64(),
+                share: if total > 0.0 { time.as_secs_f64() / total } else { 0.0 },
+            })
+            .collect(),
+    };
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_table(&report);
+    }
+    Ok(0)
+}
+
+/// 对每一行单独应用一条规则, 取最快的一次
+fn time_rule(rule: &ReplacementRule, lines: &[&str], context: &CommandContext, iterations: usize) -> Duration {
+    let rules = std::slice::from_ref(rule);
+    let mut state = ReplaceState::new(Some(0), Counters::default(), true).with_actions(false);
+    (0..iterations)
+        .map(|_| {
+            let start = Instant::now();
+            for line in lines {
+                std::hint::black_box(apply_replacements(line, context, rules, &mut state));
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn throughput(elapsed: Duration, lines: usize, bytes: usize) -> Throughput {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    Throughput {
+        seconds,
+        lines_per_sec: lines as f64 / seconds,
+        mb_per_sec: bytes as f64 / (1024.0 * 1024.0) / seconds,
+    }
+}
+
+fn print_table(report: &Report) {
+    println!(
+        "输入: {} ({} 行, {:.1} MB), 每项运行 {} 次, 取最快的一次",
+        report.input.display(),
+        report.lines,
+        report.bytes as f64 / (1024.0 * 1024.0),
+        report.iterations
+    );
+    println!("  {:<10} {:>12} {:>10} {:>10}", "", "行/秒", "MB/秒", "耗时");
+    for (name, throughput) in [("baseline", &report.baseline), ("theme", &report.theme)] {
+        println!(
+            "  {:<10} {:>12.0} {:>10.1} {:>9.3}s",
+            name, throughput.lines_per_sec, throughput.mb_per_sec, throughput.seconds
+        );
+    }
+    println!("  主题的耗时是没有规则时的 {:.2} 倍", report.theme.seconds / report.baseline.seconds);
+    if !report.rules.is_empty() {
+        println!("各规则的耗时占比 (单独应用每条单行规则):");
+        for rule in &report.rules {
+            println!("  {:>6.1}% {:>9.3}s  {}", rule.share * 100.0, rule.seconds, rule.rule);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{
+    load_themes, parse_theme_file, removal_id, resolve_theme, theme_search_path, LoadOptions, MatchMode,
+    RawTheme,
+};
+use crate::conflict;
+use crate::position::rule_positions;
+use crate::rule::ReplacementRule;
+use crate::template::CaptureRef;
+
+/// 检查主题中的每一条规则, 发现问题时返回 false
+pub fn run(themes: &[PathBuf], options: &LoadOptions) -> Result<bool> {
+    let mut checker = Checker::default();
+    for theme in themes {
+        let path = resolve_theme(theme, &theme_search_path())?;
+        checker.check_file(&path, options);
+    }
+
+    // 规则之间的冲突只作为警告, 不影响检查结果; 加载失败的原因已在上面报告
+    if let Ok(themes) = load_themes(themes, options) {
+        let rules: Vec<_> = themes.iter().flat_map(|theme| &theme.rules).collect();
+        let conflicts = conflict::find(&rules, MatchMode::of(&themes));
+        for conflict in &conflicts {
+            eprintln!("警告: {}", conflict);
+        }
+        if !conflicts.is_empty() {
+            eprintln!("发现 {} 处可能的规则冲突", conflicts.len());
+        }
+    }
+
+    if checker.problems == 0 {
+        eprintln!("检查通过: 共 {} 条规则", checker.rules);
+    } else {
+        eprintln!("发现 {} 个问题 (共 {} 条规则)", checker.problems, checker.rules);
+    }
+    Ok(checker.problems == 0)
+}
+
+#[derive(Default)]
+struct Checker {
+    visited: HashSet<PathBuf>,
+    rules: usize,
+    problems: usize,
+}
+
+impl Checker {
+    fn report(&mut self, location: &str, message: &str) {
+        self.problems += 1;
+        eprintln!("{}: {}", location, message);
+    }
+
+    fn check_file(&mut self, path: &Path, options: &LoadOptions) {
+        let key = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if !self.visited.insert(key) {
+            return;
+        }
+
+        let raw = match parse_theme_file(path, options) {
+            Ok(raw) => raw,
+            Err(e) => {
+                self.report(&path.display().to_string(), &format!("{:#}", e));
+                return;
+            }
+        };
+        for include in raw.extends.iter().chain(&raw.includes) {
+            self.check_file(include, options);
+        }
+
+        let positions = rule_positions(raw.format, &raw.source);
+        for (index, value) in raw.rules.iter().enumerate() {
+            // 删除继承规则的条目在加载时检查, 这里不当作规则
+            if removal_id(value).is_some() {
+                continue;
+            }
+            self.rules += 1;
+            let location = match positions.get(index) {
+                Some((line, column)) => {
+                    format!("{}:{}:{}: 规则 #{}", raw.path.display(), line, column, index + 1)
+                }
+                None => format!("{}: 规则 #{}", raw.path.display(), index + 1),
+            };
+            for problem in check_rule(&raw, value, options) {
+                self.report(&location, &problem);
+            }
+        }
+    }
+}
+
+/// 检查单条规则, 返回发现的所有问题
+fn check_rule(raw: &RawTheme, value: &serde_json::Value, options: &LoadOptions) -> Vec<String> {
+    let config = match raw.rule_config(value, options) {
+        Ok(config) => config,
+        Err(e) => return vec![format!("格式错误: {:#}", e)],
+    };
+
+    let mut problems = Vec::new();
+    if config.commands.iter().any(|c| c.trim().is_empty()) {
+        problems.push("filter_commands 中包含空字符串".to_string());
+    }
+    if config.exclude_commands.iter().any(|c| c.trim().is_empty()) {
+        problems.push("exclude_commands 中包含空字符串".to_string());
+    }
+
+    let rule = match ReplacementRule::from_config(&config) {
+        Ok(rule) => rule,
+        Err(e) => {
+            problems.push(format!("{:#}", e));
+            return problems;
+        }
+    };
+
+    let names: HashSet<&str> = rule.pattern.capture_names().flatten().collect();
+    let notify = rule.notify.iter().flat_map(|notifier| notifier.templates());
+    let templates = rule
+        .replacement
+        .templates()
+        .iter()
+        .chain(&rule.insert_before)
+        .chain(&rule.insert_after)
+        .chain(notify);
+    let references = templates.flat_map(|t| t.references());
+    for reference in references.cloned() {
+        match reference {
+            CaptureRef::Index(index) if index >= rule.pattern.captures_len() => {
+                problems.push(format!(
+                    "replacement 引用了不存在的捕获组 ${} (pattern 只有 {} 个捕获组): {}",
+                    index,
+                    rule.pattern.captures_len() - 1,
+                    config.pattern
+                ));
+            }
+            CaptureRef::Name(name) if !names.contains(name.as_str()) => {
+                problems.push(format!(
+                    "replacement 引用了不存在的命名捕获组 ${{{}}}: {}",
+                    name, config.pattern
+                ));
+            }
+            _ => {}
+        }
+    }
+    problems
+}
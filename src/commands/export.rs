@@ -0,0 +1,22 @@
+use anyhow::{Context, Result};
+use std::{io::Write, path::Path};
+
+use crate::config::{load_theme, LoadOptions, ThemeFormat};
+
+/// 加载任意支持格式的主题, 以规范化形式输出到标准输出或文件
+pub fn run(
+    theme: &Path,
+    format: ThemeFormat,
+    output: Option<&Path>,
+    options: &LoadOptions,
+) -> Result<()> {
+    let theme = load_theme(theme, options)?;
+    let text = format.serialize(&theme.document())?;
+
+    match output {
+        Some(path) => std::fs::write(path, text)
+            .with_context(|| format!("无法写入文件: {}", path.display()))?,
+        None => std::io::stdout().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
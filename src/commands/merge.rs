@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use std::{io::Write, path::PathBuf};
+
+use crate::config::{load_themes, LoadOptions, ReplacementConfig, ThemeDocument, ThemeFormat};
+
+/// merge 子命令参数
+pub struct MergeArgs {
+    pub themes: Vec<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: Option<ThemeFormat>,
+    pub dedupe: bool,
+    pub name: Option<String>,
+    pub version: Option<String>,
+}
+
+/// 按顺序合并多个主题 (展开 include), 输出为单个扁平的主题文件
+pub fn run(args: MergeArgs, options: &LoadOptions) -> Result<()> {
+    let themes = load_themes(&args.themes, options)?;
+
+    // 元数据以第一个主题为准, 命令行参数可以覆盖
+    let mut metadata = themes
+        .first()
+        .map(|theme| theme.metadata.clone())
+        .unwrap_or_default();
+    if args.name.is_some() {
+        metadata.name = args.name;
+    }
+    if args.version.is_some() {
+        metadata.version = args.version;
+    }
+
+    let mut configs: Vec<ReplacementConfig> = Vec::new();
+    for config in themes.into_iter().flat_map(|theme| theme.configs) {
+        if args.dedupe && configs.contains(&config) {
+            continue;
+        }
+        if let Some(index) = configs
+            .iter()
+            .position(|c| c.pattern == config.pattern && c.locale == config.locale)
+            .filter(|_| config.dictionary.is_none())
+        {
+            eprintln!(
+                "警告: 规则 #{} 与规则 #{} 的 pattern 和 locale 相同: {}",
+                configs.len() + 1,
+                index + 1,
+                config.label()
+            );
+        }
+        configs.push(config);
+    }
+
+    let format = args
+        .format
+        .or_else(|| args.output.as_deref().and_then(ThemeFormat::from_path))
+        .unwrap_or(ThemeFormat::Json);
+    let document = ThemeDocument {
+        metadata: &metadata,
+        replacements: &configs,
+    };
+    let text = format.serialize(&document)?;
+
+    match &args.output {
+        Some(path) => std::fs::write(path, text)
+            .with_context(|| format!("无法写入文件: {}", path.display()))?,
+        None => std::io::stdout().write_all(text.as_bytes())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,6 @@
+pub mod bench;
+pub mod check;
+pub mod export;
+pub mod merge;
+pub mod replay;
+pub mod test;
@@ -0,0 +1,195 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::config::{load_themes, LoadOptions, MatchMode};
+use crate::filter::RuleFilters;
+use crate::rule::{CommandContext, SharedRules, Stream};
+use crate::segment::{parse_encoding, DEFAULT_MAX_LINE_LENGTH};
+use crate::{collect_rules, process_stream, ColorChoice, RunOptions, DEFAULT_MULTILINE_HOLD};
+
+/// replay 子命令的选项
+pub struct ReplayOptions {
+    /// 作为哪个命令的输出处理, 如 `cargo build`, 用于匹配 filter_commands 和 args_pattern
+    pub as_command: Option<String>,
+    /// 按录制时的时间间隔回放的速度倍数
+    pub speed: f64,
+    /// 忽略录制的时间, 立即输出
+    pub no_delay: bool,
+    pub locale: String,
+    pub fallback: bool,
+    pub color: ColorChoice,
+}
+
+/// 录下的一段输出
+struct Chunk {
+    /// 从录制开始经过的秒数, 日志没有记录时间时为 None
+    time: Option<f64>,
+    stream: Stream,
+    data: Vec<u8>,
+}
+
+/// 把 --log-raw 的日志或 asciinema 录像当作命令的输出, 经过与运行命令时相同的处理后输出
+pub async fn run(file: &Path, themes: &[PathBuf], load: &LoadOptions, options: ReplayOptions) -> Result<i32> {
+    if options.speed.is_nan() || options.speed <= 0.0 {
+        bail!("--speed 必须大于 0: {}", options.speed);
+    }
+    let data = std::fs::read(file).with_context(|| format!("无法读取 {}", file.display()))?;
+    let chunks = parse(&data);
+
+    let themes = load_themes(themes, load)?;
+    let filters = RuleFilters {
+        only: Vec::new(),
+        skip: Vec::new(),
+        enable_groups: Vec::new(),
+        disable_groups: Vec::new(),
+        locale: options.locale,
+        fallback: options.fallback,
+        now: None,
+        verbose: false,
+    };
+    let input_encoding = themes
+        .iter()
+        .find_map(|theme| theme.metadata.input_encoding.as_deref())
+        .and_then(|label| parse_encoding(label).ok());
+    let run_options = Arc::new(RunOptions {
+        color: options.color,
+        mode: MatchMode::of(&themes),
+        strip_ansi: themes.iter().any(|theme| theme.metadata.strip_ansi == Some(true)),
+        max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        multiline_hold: DEFAULT_MULTILINE_HOLD,
+        input_encoding,
+        stderr_input_encoding: input_encoding,
+        ..Default::default()
+    });
+    let rules = SharedRules::new(collect_rules(themes, &filters)?);
+    let command = options.as_command.unwrap_or_default();
+    let (name, args) = command.split_once(' ').unwrap_or((&command, ""));
+    let context = Arc::new(CommandContext::new(name, args.to_string()));
+
+    // 回放的输出不能被终止, abort 规则只是匹配
+    let (abort, _) = mpsc::unbounded_channel();
+    let (mut stdout_writer, stdout_reader) = tokio::io::duplex(64 * 1024);
+    let (mut stderr_writer, stderr_reader) = tokio::io::duplex(64 * 1024);
+    let stdout_task = {
+        let (rules, context, options, abort) = (rules.clone(), context.clone(), run_options.clone(), abort.clone());
+        tokio::spawn(async move {
+            let writer = tokio::io::stdout();
+            process_stream(stdout_reader, writer, &context, rules, Stream::Stdout, &options, abort).await
+        })
+    };
+    let stderr_task = {
+        let (rules, context, options) = (rules.clone(), context.clone(), run_options.clone());
+        tokio::spawn(async move {
+            let writer = tokio::io::stderr();
+            process_stream(stderr_reader, writer, &context, rules, Stream::Stderr, &options, abort).await
+        })
+    };
+
+    let start = Instant::now();
+    for chunk in chunks {
+        if let Some(time) = chunk.time
+            && !options.no_delay
+        {
+            tokio::time::sleep_until(start + Duration::from_secs_f64(time / options.speed)).await;
+        }
+        let writer = match chunk.stream {
+            Stream::Stdout => &mut stdout_writer,
+            Stream::Stderr => &mut stderr_writer,
+        };
+        writer.write_all(&chunk.data).await?;
+    }
+    drop((stdout_writer, stderr_writer));
+    stdout_task.await??;
+    stderr_task.await??;
+    Ok(0)
+}
+
+/// 第一行是带有 version 的 JSON 对象时按 asciinema v2 录像读取, 否则按 --log-raw 的日志读取:
+/// 以 `[stdout] `、`[stderr] ` 开头的行属于对应的输出流, 其余的行属于标准输出;
+/// `[stdout+] `、`[stderr+] ` 接续被另一个输出流打断的一行, 打断时加上的换行不属于原来的输出
+fn parse(data: &[u8]) -> Vec<Chunk> {
+    let mut lines = data.split(|&b| b == b'\n');
+    let header = lines.next().and_then(|line| serde_json::from_slice::<serde_json::Value>(line).ok());
+    if header.is_some_and(|header| header.get("version").is_some()) {
+        return lines
+            .filter_map(|line| {
+                let (time, kind, text): (f64, String, String) = serde_json::from_slice(line).ok()?;
+                (kind == "o").then(|| Chunk {
+                    time: Some(time),
+                    stream: Stream::Stdout,
+                    data: text.into_bytes(),
+                })
+            })
+            .collect();
+    }
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let (stream, continued, data) = match line {
+            _ if let Some(rest) = line.strip_prefix(b"[stdout] ") => (Stream::Stdout, false, rest),
+            _ if let Some(rest) = line.strip_prefix(b"[stderr] ") => (Stream::Stderr, false, rest),
+            _ if let Some(rest) = line.strip_prefix(b"[stdout+] ") => (Stream::Stdout, true, rest),
+            _ if let Some(rest) = line.strip_prefix(b"[stderr+] ") => (Stream::Stderr, true, rest),
+            _ => (Stream::Stdout, false, line),
+        };
+        if continued
+            && let Some(previous) = chunks.iter_mut().rev().find(|chunk| chunk.stream == stream)
+            && previous.data.last() == Some(&b'\n')
+        {
+            previous.data.pop();
+        }
+        chunks.push(Chunk {
+            time: None,
+            stream,
+            data: data.to_vec(),
+        });
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::LogFile;
+
+    /// 经过 --log-raw 的日志再读回, 两个输出流的内容与原来逐字节相同
+    fn round_trip(name: &str, writes: &[(Stream, &str)]) -> [String; 2] {
+        let path = std::env::temp_dir().join(format!("nyantheme-replay-{}-{}.log", name, std::process::id()));
+        let log = LogFile::create(path.to_str().unwrap()).unwrap();
+        for (stream, data) in writes {
+            log.write(*stream, data.as_bytes());
+        }
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut streams = [Vec::new(), Vec::new()];
+        for chunk in parse(&data) {
+            streams[usize::from(chunk.stream == Stream::Stderr)].extend(chunk.data);
+        }
+        streams.map(|data| String::from_utf8(data).unwrap())
+    }
+
+    #[test]
+    fn partial_line_and_progress() {
+        let writes = [
+            (Stream::Stdout, ">>> "),
+            (Stream::Stdout, "hi\n"),
+            (Stream::Stdout, "downloading 10%\r"),
+            (Stream::Stdout, "downloading 99%\r"),
+            (Stream::Stdout, "done\n"),
+            (Stream::Stdout, "no newline"),
+        ];
+        let [stdout, stderr] = round_trip("partial", &writes);
+        assert_eq!(stdout, ">>> hi\ndownloading 10%\rdownloading 99%\rdone\nno newline");
+        assert_eq!(stderr, "");
+    }
+
+    #[test]
+    fn interrupted_line() {
+        let writes = [(Stream::Stdout, ">>> "), (Stream::Stderr, "warn\n"), (Stream::Stdout, "hi\n")];
+        assert_eq!(round_trip("interrupted", &writes), [">>> hi\n", "warn\n"]);
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+use crate::config::{load_themes, LoadOptions, MatchMode};
+use crate::filter::select_locale;
+use crate::rule::{apply_replacements, sort_by_priority, CommandContext, Counters, ReplaceState};
+
+/// 运行主题规则中附带的测试用例, 全部通过时返回 true
+pub fn run(themes: &[PathBuf], options: &LoadOptions) -> Result<bool> {
+    let themes = load_themes(themes, options)?;
+    let mut rules: Vec<_> = themes.iter().flat_map(|theme| theme.rules.clone()).collect();
+    sort_by_priority(&mut rules);
+    let mode = MatchMode::of(&themes);
+
+    let (mut passed, mut failed) = (0, 0);
+    for (index, config) in themes.iter().flat_map(|theme| &theme.configs).enumerate() {
+        for (case_index, case) in config.tests.iter().enumerate() {
+            let command = case
+                .command
+                .as_deref()
+                .or(config.commands.first().map(String::as_str))
+                .unwrap_or("");
+            let locale = case.locale.as_deref().unwrap_or(config.locale.primary());
+            let context = CommandContext::new(command, case.args.clone().unwrap_or_default());
+            let rules = select_locale(rules.clone(), locale, true);
+            // 固定种子, 使随机替换的测试结果稳定
+            let mut state = ReplaceState::new(Some(0), Counters::default(), false)
+                .with_mode(mode)
+                .with_actions(false);
+            let output = apply_replacements(&case.input, &context, &rules, &mut state);
+            // 插入的行以换行连接, 与期望值整体比较
+            let actual = output.lines().collect::<Vec<_>>().join("\n");
+            if output.line.is_some() && actual == case.expected {
+                passed += 1;
+                continue;
+            }
+            failed += 1;
+            println!(
+                "✘ 规则 #{} 测试 #{} ({}): {}",
+                index + 1,
+                case_index + 1,
+                command,
+                config.label()
+            );
+            println!("  输入: {}", case.input);
+            println!("  - 期望: {}", case.expected);
+            if output.line.is_some() {
+                println!("  + 实际: {}", actual);
+            } else {
+                println!("  + 实际: (该行被丢弃) {}", actual);
+            }
+        }
+    }
+
+    println!("测试结果: {} 通过, {} 失败", passed, failed);
+    Ok(failed == 0)
+}
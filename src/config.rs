@@ -0,0 +1,1609 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fmt,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+};
+
+use crate::position::rule_positions;
+use crate::rule::{ReplacementRule, Stream};
+use crate::segment::parse_encoding;
+
+/// 替换规则配置
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ReplacementConfig {
+    /// 规则标识, 用于 --only/--skip 等按名称引用规则的场合
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replacement: Option<ReplacementText>,
+    /// 原文到译文的对照表, 代替 pattern/replacement 做批量的字面替换, 最长的原文优先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<BTreeMap<String, String>>,
+    /// 从 TSV/CSV 文件读取对照表 (相对于主题文件), 与 dictionary 互斥, 加载时读入 dictionary
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary_file: Option<String>,
+    /// 从文件读取替换文本 (相对于主题文件), 与 replacement 互斥, 加载时读入 replacement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replacement_file: Option<String>,
+    /// 适用的语言环境, 可以是单个字符串、数组或表示任意语言环境的 "*"
+    #[serde(default, skip_serializing_if = "Locales::is_default")]
+    pub locale: Locales,
+    #[serde(
+        default,
+        rename = "filter_commands",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub commands: Vec<String>,
+    /// 匹配以空格连接的命令参数 (不含命令名), 与 filter_commands 需同时满足
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args_pattern: Option<String>,
+    /// 规则作用于子进程的哪个输出流
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub streams: Streams,
+    /// 匹配前对行进行 Unicode 规范化 (nfc/nfd), 只有规范化后匹配成功时输出才采用规范化的形式
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub normalize: Normalization,
+    /// 忽略行中的 ANSI 转义序列进行匹配, 替换后保留周围的转义序列
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ansi_aware: bool,
+    /// 不应用本规则的命令, 优先于 filter_commands
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_commands: Vec<String>,
+    /// 优先级, 数值大的规则先执行, 相同时保持加载顺序
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// 按普通字符串匹配和替换, 不解释正则元字符和 `$` 引用
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub literal: bool,
+    /// 只匹配完整的单词, 在模式两端的单词字符处加 `\b`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub whole_word: bool,
+    /// 模式需要匹配整行 (忽略首尾空白), 匹配时整行替换为 replacement
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub whole_line: bool,
+    /// 配合 whole_line 使用, 保留原行的缩进
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub keep_indent: bool,
+    /// 正则标志, 与模式中的 `(?i)` 等内联标志等价, 内联标志在其作用范围内优先
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case_insensitive: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dotall: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unicode: Option<bool>,
+    /// 允许替换文本中包含转义序列和控制字符, 默认会被清理
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub allow_ansi: bool,
+    /// 展开替换文本中的 `:cat:` 等 emoji 短代码
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub emoji: bool,
+    /// 替换文本沿用匹配文本的大小写形式 (全大写、首字母大写或小写), 默认同时忽略大小写匹配
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub preserve_case: bool,
+    /// 匹配的行中按此概率应用本规则 (0 到 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>,
+    /// 遮盖匹配的文本 (或 redact_group 指定的捕获组), 不需要 replacement
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub redact: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redact_group: Option<String>,
+    /// 固定的遮盖文本, 默认用等长的 `*` 遮盖
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<String>,
+    /// 保留被遮盖文本开头/结尾的字符数
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_prefix: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub keep_suffix: usize,
+    /// 匹配时在该行之前/之后额外输出的行, 可以使用捕获引用, 这些行不再经过其他规则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insert_before: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub insert_after: Option<String>,
+    /// 匹配的行整行丢弃 (连同换行符), 不需要 replacement
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub drop: bool,
+    /// 每处匹配运行的外部命令 (参数中可以使用捕获引用), 以去掉首尾空白的标准输出作为替换文本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replacement_command: Option<Vec<String>>,
+    /// 每处匹配执行的 rhai 脚本, 返回值作为替换文本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// replacement_command 每次运行的超时时间 (毫秒), 默认 1000
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command_timeout_ms: Option<u64>,
+    /// 只给匹配的文本加上颜色样式 (如 `"red bold"`), 不改变文本, 不需要 replacement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub style: Option<String>,
+    /// 匹配时在后台运行的命令, 不改变输出, 匹配的文本通过环境变量 CLITHEME_MATCH、CLITHEME_CAPTURE_N 传入
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_match: Option<Vec<String>>,
+    /// on_match 两次运行之间的最短间隔 (毫秒), 默认 1000
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_match_interval_ms: Option<u64>,
+    /// 匹配时发送的桌面通知
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifyConfig>,
+    /// 匹配时终止子进程 (及其进程组), 输出完剩余内容后以 abort_exit_code (默认 1) 退出
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub abort: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub abort_exit_code: Option<i32>,
+    /// 在最近 window 行 (默认 3) 以 `\n` 连接的文本上匹配, `^`/`$` 匹配每行的开头和结尾,
+    /// 匹配的几行整体替换, 替换结果中的 `\n` 分为多行
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub multiline: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<usize>,
+    /// 每行最多替换的次数, 0 表示不限
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub max_replacements: usize,
+    /// 本规则在某行匹配后不再对该行应用后续规则
+    #[serde(rename = "final", alias = "stop", default, skip_serializing_if = "is_false")]
+    pub stop: bool,
+    /// 生效的时间段 (本地时间 HH:MM), active_after 晚于 active_before 时跨越午夜
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_before: Option<String>,
+    /// 只在这些环境变量等于给定值 (值为 null 或 true 时只要求已设置) 时加载本规则
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "serialize_when_env",
+        deserialize_with = "deserialize_when_env"
+    )]
+    pub when_env: BTreeMap<String, Option<String>>,
+    /// 规则所属的分组, 可通过 --enable-group/--disable-group 整组开关
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    /// 规则自带的测试用例, 只在 test 子命令中使用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<RuleTest>,
+}
+
+/// 匹配时发送的桌面通知, 标题和正文中可以使用捕获引用
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NotifyConfig {
+    /// 默认为 "clitheme"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// 默认为匹配的文本 `$0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// 两次通知之间的最短间隔 (毫秒), 默认 10000, 期间的匹配不再通知
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+}
+
+/// 规则测试用例
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RuleTest {
+    pub input: String,
+    pub expected: String,
+    /// 模拟的命令名, 默认为规则 filter_commands 中的第一个
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// 模拟的命令参数, 以空格连接
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<String>,
+    /// 使用的语言环境, 默认为规则自身的 locale
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+impl ReplacementConfig {
+    /// 用于提示信息的规则简述
+    pub fn label(&self) -> &str {
+        if self.dictionary.is_some() {
+            "(dictionary)"
+        } else {
+            &self.pattern
+        }
+    }
+
+    /// 实际使用的忽略大小写设置, preserve_case 未显式指定时默认忽略大小写
+    pub fn ignore_case(&self) -> Option<bool> {
+        self.case_insensitive.or(self.preserve_case.then_some(true))
+    }
+
+    /// 是否设置了匹配时的动作或插入行, 只有这些的规则不需要 replacement
+    pub fn has_action(&self) -> bool {
+        self.on_match.is_some()
+            || self.notify.is_some()
+            || self.abort
+            || self.insert_before.is_some()
+            || self.insert_after.is_some()
+    }
+
+    /// 规则支持的所有字段名, 由 serde 派生的实现提供, 无需手动维护
+    pub fn field_names() -> &'static [&'static str] {
+        struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> serde::Deserializer<'de> for FieldNames<'_> {
+            type Error = serde::de::value::Error;
+
+            fn deserialize_any<V: serde::de::Visitor<'de>>(
+                self,
+                _visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                Err(serde::de::Error::custom("仅用于获取字段名"))
+            }
+
+            fn deserialize_struct<V: serde::de::Visitor<'de>>(
+                self,
+                _name: &'static str,
+                fields: &'static [&'static str],
+                _visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = fields;
+                Err(serde::de::Error::custom("仅用于获取字段名"))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+                bytes byte_buf option unit unit_struct newtype_struct seq tuple
+                tuple_struct map enum identifier ignored_any
+            }
+        }
+
+        let mut fields: &'static [&'static str] = &[];
+        let _ = ReplacementConfig::deserialize(FieldNames(&mut fields));
+        fields
+    }
+
+    /// 检查规则中的未知字段, 并给出拼写最接近的已知字段作为提示
+    pub fn check_unknown_fields(value: &serde_json::Value) -> Result<()> {
+        let Some(object) = value.as_object() else {
+            return Ok(());
+        };
+        let known = Self::field_names();
+        for key in object.keys() {
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let suggestion = known
+                .iter()
+                .map(|field| (edit_distance(key, field), field))
+                .filter(|(distance, field)| *distance <= (field.len() / 3).max(2))
+                .min_by_key(|(distance, _)| *distance);
+            match suggestion {
+                Some((_, field)) => bail!("未知字段 `{}`, 是否想写 `{}`?", key, field),
+                None => bail!("未知字段 `{}`", key),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 两个字符串之间的编辑距离 (Levenshtein)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                prev.min(row[j]).min(current) + 1
+            };
+            prev = current;
+        }
+    }
+    row[b.len()]
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// 只要求已设置的 when_env 条件写为 true 而不是 null, 因为 TOML 没有 null, 导出时会被丢掉
+fn serialize_when_env<S: serde::Serializer>(
+    when_env: &BTreeMap<String, Option<String>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_map(when_env.iter().map(|(name, expected)| {
+        let value = match expected {
+            Some(expected) => serde_json::Value::String(expected.clone()),
+            None => serde_json::Value::Bool(true),
+        };
+        (name, value)
+    }))
+}
+
+fn deserialize_when_env<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, Option<String>>, D::Error> {
+    BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(expected) => Ok((name, Some(expected))),
+            serde_json::Value::Null | serde_json::Value::Bool(true) => Ok((name, None)),
+            _ => Err(serde::de::Error::custom(format!(
+                "when_env 中 {} 的值应为字符串、null 或 true",
+                name
+            ))),
+        })
+        .collect()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+/// 替换文本, 写成数组时每处匹配随机选择其中一个
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged, expecting = "replacement 应为字符串或字符串数组")]
+pub enum ReplacementText {
+    One(String),
+    Choices(Vec<String>),
+}
+
+impl ReplacementText {
+    pub fn texts(&self) -> &[String] {
+        match self {
+            Self::One(text) => std::slice::from_ref(text),
+            Self::Choices(choices) => choices,
+        }
+    }
+}
+
+/// 规则适用的语言环境
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged, expecting = "locale 应为字符串或字符串数组")]
+pub enum Locales {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for Locales {
+    fn default() -> Self {
+        Self::One("default".to_string())
+    }
+}
+
+impl Locales {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        match self {
+            Self::One(locale) => std::slice::from_ref(locale).iter(),
+            Self::Many(locales) => locales.iter(),
+        }
+        .map(String::as_str)
+    }
+
+    /// 第一个具体的语言环境, 用作测试用例的默认值
+    pub fn primary(&self) -> &str {
+        self.iter().find(|l| *l != "*").unwrap_or("default")
+    }
+}
+
+/// 规则作用的输出流
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Streams {
+    Stdout,
+    Stderr,
+    #[default]
+    Both,
+}
+
+/// 匹配前对文本进行的 Unicode 规范化
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    None,
+    Nfc,
+    Nfd,
+}
+
+impl Normalization {
+    /// 规范化文本, 已经是目标形式时不复制
+    pub fn apply(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Nfc if !unicode_normalization::is_nfc(text) => Cow::Owned(text.nfc().collect()),
+            Self::Nfd if !unicode_normalization::is_nfd(text) => Cow::Owned(text.nfd().collect()),
+            _ => Cow::Borrowed(text),
+        }
+    }
+}
+
+/// 拆分一行 CSV, 字段可以用双引号包裹, `""` 表示字面的引号; 引号不配对时返回 None
+fn split_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return None;
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Some(fields);
+        }
+    }
+}
+
+/// 主题元数据
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ThemeMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// 加载本主题所需的最低 clitheme 版本
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_clitheme_version: Option<String>,
+    /// 去掉命令输出中的 ANSI 转义序列, 同 --strip-ansi
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_ansi: Option<bool>,
+    /// 规则的应用方式
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<MatchMode>,
+    /// 命令第一次输出之前显示的横幅
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<LocalizedText>,
+    /// 命令结束之后显示的总结, 可以使用 {elapsed}、{exit_code}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<LocalizedText>,
+    /// header 和 footer 输出到哪个流, 默认为标准输出
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banner_stream: Option<Stream>,
+    /// 子进程输出的默认编码, 同 --input-encoding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_encoding: Option<String>,
+    /// 交互式命令的提示符, 尚未结束的一行的末尾与之匹配时不等换行立即输出
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub prompt_patterns: Vec<String>,
+}
+
+/// 按语言环境区分的文本, 只写一个字符串时适用于所有语言环境
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LocalizedText {
+    One(String),
+    Localized(BTreeMap<String, String>),
+}
+
+/// 一行文本上多条规则的应用方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// 依次应用所有规则, 后面的规则看到前面规则替换后的文本
+    #[default]
+    Cumulative,
+    /// 只应用第一条匹配原文的规则
+    FirstMatch,
+}
+
+impl MatchMode {
+    /// 同时加载多个主题时, 任一主题要求 first_match 则对所有规则使用该模式
+    pub fn of(themes: &[Theme]) -> Self {
+        themes
+            .iter()
+            .filter_map(|theme| theme.metadata.mode)
+            .max_by_key(|mode| *mode == Self::FirstMatch)
+            .unwrap_or_default()
+    }
+}
+
+/// 所有主题的提示符, 已在加载时检查过
+pub fn prompt_patterns(themes: &[Theme]) -> Arc<[Regex]> {
+    themes
+        .iter()
+        .flat_map(|theme| &theme.metadata.prompt_patterns)
+        .filter_map(|pattern| Regex::new(pattern).ok())
+        .collect()
+}
+
+impl ThemeMetadata {
+    fn from_root(root: &serde_json::Map<String, serde_json::Value>) -> Self {
+        // 版本号在 YAML/TOML 中可能被写成数字, 统一转为字符串
+        let field = |key: &str| match root.get(key)? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        };
+        Self {
+            name: field("name"),
+            version: field("version"),
+            author: field("author"),
+            description: field("description"),
+            min_clitheme_version: field("min_clitheme_version"),
+            strip_ansi: root.get("strip_ansi").and_then(serde_json::Value::as_bool),
+            mode: None,
+            header: None,
+            footer: None,
+            banner_stream: None,
+            input_encoding: field("input_encoding"),
+            prompt_patterns: Vec::new(),
+        }
+    }
+
+    /// 未设置的字段使用基础主题的值
+    fn inherit(self, base: ThemeMetadata) -> Self {
+        Self {
+            name: self.name.or(base.name),
+            version: self.version.or(base.version),
+            author: self.author.or(base.author),
+            description: self.description.or(base.description),
+            min_clitheme_version: self.min_clitheme_version.or(base.min_clitheme_version),
+            strip_ansi: self.strip_ansi.or(base.strip_ansi),
+            mode: self.mode.or(base.mode),
+            header: self.header.or(base.header),
+            footer: self.footer.or(base.footer),
+            banner_stream: self.banner_stream.or(base.banner_stream),
+            input_encoding: self.input_encoding.or(base.input_encoding),
+            prompt_patterns: if self.prompt_patterns.is_empty() { base.prompt_patterns } else { self.prompt_patterns },
+        }
+    }
+
+    /// 检查当前 clitheme 是否满足主题要求的最低版本
+    fn check_min_version(&self) -> Result<()> {
+        let Some(required) = &self.min_clitheme_version else {
+            return Ok(());
+        };
+        let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("crate 版本号有效");
+        let required_version = semver::Version::parse(required)
+            .with_context(|| format!("min_clitheme_version 格式错误, 应为 x.y.z: {}", required))?;
+        if current < required_version {
+            bail!(
+                "主题需要 clitheme {} 或更高版本 (当前为 {}), 请升级 clitheme, 或使用 --ignore-version-check 强制加载",
+                required,
+                current
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ThemeMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name.as_deref().unwrap_or("(未命名主题)"))?;
+        if let Some(version) = &self.version {
+            write!(f, " {}", version)?;
+        }
+        if let Some(author) = &self.author {
+            write!(f, " (作者: {})", author)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, "\n  {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+/// 加载完成的主题: 元数据、展开 include 后的规则配置和编译后的规则
+pub struct Theme {
+    pub metadata: ThemeMetadata,
+    pub configs: Vec<ReplacementConfig>,
+    pub rules: Vec<ReplacementRule>,
+    /// 主题读取过的所有文件和目录, 用于监视变更
+    pub sources: Vec<PathBuf>,
+}
+
+impl Theme {
+    /// 删除 id 对应的规则, 找不到时返回 false
+    fn remove_rule(&mut self, id: &str) -> bool {
+        let found = self.configs.iter().any(|c| c.id.as_deref() == Some(id));
+        self.configs.retain(|c| c.id.as_deref() != Some(id));
+        self.rules.retain(|r| r.id.as_deref() != Some(id));
+        found
+    }
+
+    /// 用新的规则原位替换 id 相同的规则, 找不到时返回 false
+    fn override_rule(
+        &mut self,
+        id: &str,
+        config: ReplacementConfig,
+        rule: Option<ReplacementRule>,
+    ) -> bool {
+        let Some(index) = self.configs.iter().position(|c| c.id.as_deref() == Some(id)) else {
+            return false;
+        };
+        self.configs[index] = config;
+        match (self.rules.iter().position(|r| r.id.as_deref() == Some(id)), rule) {
+            (Some(index), Some(rule)) => self.rules[index] = rule,
+            (Some(index), None) => {
+                self.rules.remove(index);
+            }
+            // 基础主题中的同名规则本身无效
+            (None, Some(rule)) => self.rules.push(rule),
+            (None, None) => {}
+        }
+        true
+    }
+}
+
+/// 规范化的主题文档, 用于导出
+#[derive(Serialize)]
+pub struct ThemeDocument<'a> {
+    #[serde(flatten)]
+    pub metadata: &'a ThemeMetadata,
+    pub replacements: &'a [ReplacementConfig],
+}
+
+impl Theme {
+    pub fn document(&self) -> ThemeDocument<'_> {
+        ThemeDocument {
+            metadata: &self.metadata,
+            replacements: &self.configs,
+        }
+    }
+}
+
+/// 配置加载选项
+#[derive(Debug, Default, Clone)]
+pub struct LoadOptions {
+    /// 只接受严格的JSON, 不允许注释和尾随逗号
+    pub strict_json: bool,
+    /// 加载目录时同时加载子目录
+    pub recursive: bool,
+    /// 忽略规则中的未知字段, 兼容为新版本编写的主题
+    pub lenient: bool,
+    /// 不检查主题要求的 min_clitheme_version
+    pub ignore_version_check: bool,
+}
+
+/// 主题文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ThemeFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ThemeFormat {
+    /// 根据扩展名判断格式, 未知扩展名返回 None
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "json" | "jsonc" | "json5" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yml" | "yaml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn parse(self, data: &str, options: &LoadOptions) -> Result<serde_json::Value> {
+        match self {
+            Self::Json => match serde_json::from_str(data) {
+                Ok(value) => Ok(value),
+                Err(e) if options.strict_json => {
+                    Err(anyhow!("配置文件格式错误: 必须是有效的JSON ({})", e))
+                }
+                // 严格解析失败时再按 JSON5 解析, 允许注释、尾随逗号和无引号键名
+                Err(strict_err) => json5::from_str(data).map_err(|e| {
+                    // 两种解析都失败时, 报告位置更靠后的那个错误, 它通常更接近真正的问题
+                    let json5::Error::Message { location, .. } = &e;
+                    let strict_pos = (strict_err.line(), strict_err.column());
+                    match location {
+                        Some(loc) if (loc.line, loc.column) > strict_pos => {
+                            anyhow!("配置文件格式错误: 必须是有效的JSON\n{}", e)
+                        }
+                        _ => anyhow!("配置文件格式错误: 必须是有效的JSON ({})", strict_err),
+                    }
+                }),
+            },
+            Self::Toml => toml::from_str(data)
+                .map_err(|e| anyhow!("配置文件格式错误: 必须是有效的TOML\n{}", e)),
+            Self::Yaml => serde_yaml::from_str(data)
+                .map_err(|e| anyhow!("配置文件格式错误: 必须是有效的YAML ({})", e)),
+        }
+    }
+}
+
+impl ThemeFormat {
+    /// 将主题序列化为该格式的文本
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Self::Json => serde_json::to_string_pretty(value)? + "\n",
+            Self::Toml => toml::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+        })
+    }
+}
+
+/// 解析配置文件内容, 未知扩展名时依次尝试JSON、TOML、YAML
+fn parse_document(
+    path: &Path,
+    data: &str,
+    options: &LoadOptions,
+) -> Result<(serde_json::Value, ThemeFormat)> {
+    if let Some(format) = ThemeFormat::from_path(path) {
+        return Ok((format.parse(data, options)?, format));
+    }
+    let mut first_err = None;
+    for format in [ThemeFormat::Json, ThemeFormat::Toml, ThemeFormat::Yaml] {
+        match format.parse(data, options) {
+            Ok(value) => return Ok((value, format)),
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_err.expect("至少尝试过一种格式"))
+}
+
+/// 解析后尚未编译的单个主题文件
+pub struct RawTheme {
+    pub path: PathBuf,
+    pub source: String,
+    pub format: ThemeFormat,
+    pub metadata: ThemeMetadata,
+    /// include 引入的文件, 已解析为相对于本文件所在目录的路径
+    pub includes: Vec<PathBuf>,
+    /// extends 继承的基础主题, 本主题的规则可以按 id 覆盖或删除其中的规则
+    pub extends: Option<PathBuf>,
+    /// 可在 pattern 中通过 ${defines.名称} 引用的正则片段
+    pub defines: HashMap<String, String>,
+    pub rules: Vec<serde_json::Value>,
+}
+
+/// defines 展开的最大嵌套深度, 防止片段之间循环引用
+const MAX_DEFINE_DEPTH: usize = 8;
+
+static DEFINE_REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{defines\.([A-Za-z0-9_\-]+)\}").unwrap());
+
+impl RawTheme {
+    /// 将规则值解析为配置: 检查未知字段并展开 pattern 中的 defines 引用
+    pub fn rule_config(
+        &self,
+        value: &serde_json::Value,
+        options: &LoadOptions,
+    ) -> Result<ReplacementConfig> {
+        if !options.lenient {
+            ReplacementConfig::check_unknown_fields(value)?;
+        }
+        let mut config = ReplacementConfig::deserialize(value)?;
+        config.pattern = self.expand_defines(&config.pattern)?;
+        if let Some(file) = config.replacement_file.take() {
+            if config.replacement.is_some() {
+                bail!("replacement 和 replacement_file 不能同时使用");
+            }
+            config.replacement = Some(ReplacementText::One(self.read_replacement_file(&file)?));
+        }
+        if let Some(file) = config.dictionary_file.take() {
+            if config.dictionary.is_some() {
+                bail!("dictionary 和 dictionary_file 不能同时使用");
+            }
+            config.dictionary = Some(self.read_dictionary_file(&file)?);
+        }
+        // 每条规则只能使用一种替换方式
+        let modes: Vec<&str> = [
+            ("replacement", config.replacement.is_some()),
+            ("dictionary", config.dictionary.is_some()),
+            ("drop", config.drop),
+            ("redact", config.redact),
+            ("style", config.style.is_some()),
+            ("replacement_command", config.replacement_command.is_some()),
+            ("script", config.script.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if modes.len() > 1 {
+            bail!("{} 不能同时使用", modes.join("、"));
+        }
+        if config.preserve_case && (config.drop || config.redact || config.style.is_some()) {
+            bail!("preserve_case 不能用于 drop、redact 或 style 规则");
+        }
+        if !config.redact
+            && (config.redact_group.is_some()
+                || config.mask.is_some()
+                || config.keep_prefix > 0
+                || config.keep_suffix > 0)
+        {
+            bail!("redact_group、mask、keep_prefix、keep_suffix 需要同时设置 \"redact\": true");
+        }
+        match (&config.dictionary, config.pattern.is_empty()) {
+            (Some(_), false) => bail!("pattern 和 dictionary 不能同时使用"),
+            (Some(dictionary), true) if dictionary.is_empty() => bail!("dictionary 不能为空"),
+            (Some(dictionary), true) if dictionary.contains_key("") => {
+                bail!("dictionary 中不能有空的原文")
+            }
+            (Some(_), true) => {}
+            (None, true) => bail!("缺少 pattern 或 dictionary"),
+            (None, false) if modes.is_empty() && !config.has_action() => {
+                bail!("缺少 replacement 或 replacement_file")
+            }
+            (None, false) => {}
+        }
+        if let Some(probability) = config.probability
+            && !(0.0..=1.0).contains(&probability)
+        {
+            bail!("probability 应在 0 到 1 之间: {}", probability);
+        }
+        if matches!(&config.replacement, Some(ReplacementText::Choices(choices)) if choices.is_empty()) {
+            bail!("replacement 数组不能为空");
+        }
+        if matches!(&config.replacement_command, Some(argv) if argv.is_empty()) {
+            bail!("replacement_command 不能为空");
+        }
+        if config.command_timeout_ms.is_some() && config.replacement_command.is_none() {
+            bail!("command_timeout_ms 需要同时设置 replacement_command");
+        }
+        if config.abort_exit_code.is_some() && !config.abort {
+            bail!("abort_exit_code 需要同时设置 \"abort\": true");
+        }
+        if config.on_match_interval_ms.is_some() && config.on_match.is_none() {
+            bail!("on_match_interval_ms 需要同时设置 on_match");
+        }
+        match config.window {
+            Some(_) if !config.multiline => bail!("window 需要同时设置 \"multiline\": true"),
+            Some(window) if window < 2 => bail!("window 至少为 2: {}", window),
+            _ => {}
+        }
+        Ok(config)
+    }
+
+    /// 读取对照表文件, 每个非空行为 `原文<TAB>译文`, .csv 文件以逗号分隔 (支持双引号包裹)
+    fn read_dictionary_file(&self, file: &str) -> Result<BTreeMap<String, String>> {
+        let base_dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = base_dir.join(file);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("无法读取 dictionary_file: {}", path.display()))?;
+        let csv = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("csv"));
+
+        let mut dictionary = BTreeMap::new();
+        for (index, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = if csv {
+                split_csv_line(line)
+            } else {
+                Some(line.split('\t').map(str::to_string).collect())
+            };
+            let (from, to) = match fields.as_deref() {
+                Some([from, to]) if !from.is_empty() => (from.clone(), to.clone()),
+                _ => bail!(
+                    "{} 第 {} 行格式错误, 应为 原文{}译文: {}",
+                    path.display(),
+                    index + 1,
+                    if csv { "," } else { "<TAB>" },
+                    line
+                ),
+            };
+            if dictionary.insert(from.clone(), to).is_some() {
+                eprintln!(
+                    "警告: {} 第 {} 行的原文 '{}' 重复, 使用后出现的译文",
+                    path.display(),
+                    index + 1,
+                    from
+                );
+            }
+        }
+        Ok(dictionary)
+    }
+
+    /// 读取替换文本文件, 保留内部换行, 去掉文件末尾的一个换行
+    fn read_replacement_file(&self, file: &str) -> Result<String> {
+        let base_dir = self.path.parent().unwrap_or_else(|| Path::new(""));
+        let path = base_dir.join(file);
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("无法读取 replacement_file: {}", path.display()))?;
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        Ok(text.strip_suffix('\r').unwrap_or(text).to_string())
+    }
+
+    fn expand_defines(&self, pattern: &str) -> Result<String> {
+        let mut expanded = pattern.to_string();
+        for _ in 0..MAX_DEFINE_DEPTH {
+            if !DEFINE_REF.is_match(&expanded) {
+                return Ok(expanded);
+            }
+            let mut missing = None;
+            expanded = DEFINE_REF
+                .replace_all(&expanded, |caps: &regex::Captures| {
+                    match self.defines.get(&caps[1]) {
+                        Some(value) => value.clone(),
+                        None => {
+                            missing.get_or_insert_with(|| caps[1].to_string());
+                            String::new()
+                        }
+                    }
+                })
+                .into_owned();
+            if let Some(name) = missing {
+                bail!("未定义的 defines 引用 ${{defines.{}}}", name);
+            }
+        }
+        if DEFINE_REF.is_match(&expanded) {
+            bail!(
+                "defines 嵌套超过 {} 层, 可能存在循环引用: {}",
+                MAX_DEFINE_DEPTH,
+                pattern
+            );
+        }
+        Ok(expanded)
+    }
+}
+
+/// 读取并解析单个主题文件, 不展开 include 也不编译规则
+pub fn parse_theme_file(path: &Path, options: &LoadOptions) -> Result<RawTheme> {
+    let source = read_theme_source(path)?;
+    let (config, format) = parse_document(path, &source, options)?;
+
+    // 根元素可以是规则数组, 也可以是包含元数据和 'replacements'/'rules' 数组的字典
+    let (metadata, includes, extends, defines, rules) = match config {
+        serde_json::Value::Array(rules) => {
+            (ThemeMetadata::default(), Vec::new(), None, HashMap::new(), rules)
+        }
+        serde_json::Value::Object(mut root) => {
+            let rules = match root.remove("replacements").or_else(|| root.remove("rules")) {
+                Some(serde_json::Value::Array(rules)) => rules,
+                _ => bail!("配置文件格式错误: 根字典必须包含'replacements'或'rules'数组"),
+            };
+            let includes = match root.get("include") {
+                None => Vec::new(),
+                Some(value) => Vec::<String>::deserialize(value)
+                    .or_else(|_| String::deserialize(value).map(|s| vec![s]))
+                    .map_err(|_| anyhow!("配置文件格式错误: 'include' 必须是文件路径或路径数组"))?,
+            };
+            let defines = match root.get("defines") {
+                None => HashMap::new(),
+                Some(value) => HashMap::<String, String>::deserialize(value).map_err(|_| {
+                    anyhow!("配置文件格式错误: 'defines' 必须是名称到正则片段的字典")
+                })?,
+            };
+            let extends = match root.get("extends") {
+                None => None,
+                Some(serde_json::Value::String(extends)) => Some(extends.clone()),
+                Some(_) => bail!("配置文件格式错误: 'extends' 必须是文件路径"),
+            };
+            let mode = match root.get("mode") {
+                None => None,
+                Some(value) => Some(MatchMode::deserialize(value).map_err(|_| {
+                    anyhow!("配置文件格式错误: 'mode' 必须是 \"cumulative\" 或 \"first_match\"")
+                })?),
+            };
+            let banner = |key: &str| match root.get(key) {
+                None => Ok(None),
+                Some(value) => LocalizedText::deserialize(value).map(Some).map_err(|_| {
+                    anyhow!("配置文件格式错误: '{}' 必须是字符串或语言环境到字符串的字典", key)
+                }),
+            };
+            let (header, footer) = (banner("header")?, banner("footer")?);
+            let banner_stream = match root.get("banner_stream") {
+                None => None,
+                Some(value) => Some(Stream::deserialize(value).map_err(|_| {
+                    anyhow!("配置文件格式错误: 'banner_stream' 必须是 \"stdout\" 或 \"stderr\"")
+                })?),
+            };
+            if let Some(serde_json::Value::String(label)) = root.get("input_encoding") {
+                parse_encoding(label).context("配置文件格式错误: 'input_encoding'")?;
+            }
+            let prompt_patterns = match root.get("prompt_patterns") {
+                None => Vec::new(),
+                Some(value) => Vec::<String>::deserialize(value)
+                    .map_err(|_| anyhow!("配置文件格式错误: 'prompt_patterns' 必须是正则表达式数组"))?,
+            };
+            for pattern in &prompt_patterns {
+                Regex::new(pattern).with_context(|| format!("配置文件格式错误: 'prompt_patterns' 中的 '{}' 无效", pattern))?;
+            }
+            let metadata = ThemeMetadata {
+                mode,
+                header,
+                footer,
+                banner_stream,
+                prompt_patterns,
+                ..ThemeMetadata::from_root(&root)
+            };
+            (metadata, includes, extends, defines, rules)
+        }
+        _ => bail!("配置文件格式错误: 根元素必须是规则数组或字典"),
+    };
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Ok(RawTheme {
+        path: path.to_path_buf(),
+        source,
+        format,
+        metadata,
+        includes: includes.iter().map(|include| base_dir.join(include)).collect(),
+        extends: extends.map(|extends| base_dir.join(extends)),
+        defines,
+        rules,
+    })
+}
+
+/// 加载并验证配置文件 (JSON、TOML 或 YAML), 也可以是包含主题片段的目录
+pub fn load_config(path: &Path, options: &LoadOptions) -> Result<Theme> {
+    if path.is_dir() {
+        return load_theme_dir(path, options);
+    }
+    load_theme_file(path, options, &mut Vec::new())
+}
+
+/// 按文件名顺序加载目录中的所有主题文件并拼接规则
+fn load_theme_dir(dir: &Path, options: &LoadOptions) -> Result<Theme> {
+    let mut files = Vec::new();
+    collect_theme_files(dir, options.recursive, &mut files)?;
+    if files.is_empty() {
+        bail!("目录 {} 中没有主题文件", dir.display());
+    }
+
+    let mut configs = Vec::new();
+    let mut rules = Vec::new();
+    let mut sources = vec![dir.to_path_buf()];
+    for file in &files {
+        let theme = load_theme_file(file, options, &mut Vec::new())
+            .with_context(|| format!("无法加载主题片段 {}", file.display()))?;
+        configs.extend(theme.configs);
+        rules.extend(theme.rules);
+        sources.extend(theme.sources);
+    }
+    let metadata = ThemeMetadata {
+        name: dir.file_name().map(|n| n.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    Ok(Theme {
+        metadata,
+        configs,
+        rules,
+        sources,
+    })
+}
+
+fn collect_theme_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("无法读取目录: {}", dir.display()))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("无法读取目录: {}", dir.display()))?;
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            if recursive {
+                collect_theme_files(&entry, recursive, files)?;
+            }
+        } else if ThemeFormat::from_path(&entry).is_some() {
+            files.push(entry);
+        } else {
+            eprintln!("警告: 忽略非主题文件 {}", entry.display());
+        }
+    }
+    Ok(())
+}
+
+/// 表示从标准输入读取主题的路径参数
+pub fn is_stdin_path(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// 读取主题文件内容, 路径为 "-" 时读取全部标准输入
+fn read_theme_source(path: &Path) -> Result<String> {
+    if is_stdin_path(path) {
+        let mut data = String::new();
+        std::io::stdin()
+            .read_to_string(&mut data)
+            .context("无法从标准输入读取主题")?;
+        return Ok(data);
+    }
+    std::fs::read_to_string(path).with_context(|| format!("无法读取配置文件: {}", path.display()))
+}
+
+/// 格式化引入链, 例如 "a.json -> b.json -> a.json"
+fn format_chain(chain: &[PathBuf], last: &Path) -> String {
+    chain
+        .iter()
+        .map(|p| p.display().to_string())
+        .chain(std::iter::once(last.display().to_string()))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// 加载单个主题文件, chain 记录当前的 include 链用于检测循环引入
+fn load_theme_file(path: &Path, options: &LoadOptions, chain: &mut Vec<PathBuf>) -> Result<Theme> {
+    let key = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if chain.contains(&key) {
+        bail!("检测到循环引入: {}", format_chain(chain, &key));
+    }
+
+    let raw = parse_theme_file(path, options)?;
+    if !options.ignore_version_check {
+        raw.metadata.check_min_version()?;
+    }
+
+    // 继承的基础主题最先加载, 被引入文件的规则放在本文件规则之前
+    let mut sources = vec![path.to_path_buf()];
+    chain.push(key);
+    let mut base = match &raw.extends {
+        Some(base_path) => {
+            let base = load_theme_file(base_path, options, chain).with_context(|| {
+                format!("无法加载 {} (由 {} 继承)", base_path.display(), path.display())
+            })?;
+            sources.extend(base.sources.iter().cloned());
+            Some(base)
+        }
+        None => None,
+    };
+    let mut configs = Vec::new();
+    let mut rules = Vec::new();
+    for include_path in &raw.includes {
+        let theme = load_theme_file(include_path, options, chain).with_context(|| {
+            format!("无法加载 {} (由 {} 引入)", include_path.display(), path.display())
+        })?;
+        configs.extend(theme.configs);
+        rules.extend(theme.rules);
+        sources.extend(theme.sources);
+    }
+    chain.pop();
+
+    let positions = rule_positions(raw.format, &raw.source);
+    for (index, value) in raw.rules.iter().enumerate() {
+        if let Some(id) = removal_id(value) {
+            let base = base.as_mut().ok_or_else(|| {
+                anyhow!("规则 #{} 格式错误: \"remove\" 只能用于声明了 extends 的主题", index + 1)
+            })?;
+            if !base.remove_rule(id) {
+                bail!("规则 #{} 格式错误: 继承的主题中没有 id 为 '{}' 的规则", index + 1, id);
+            }
+            continue;
+        }
+        let config = raw.rule_config(value, options).with_context(|| match positions.get(index) {
+            Some((line, column)) => format!("规则 #{} 格式错误 (第 {} 行第 {} 列)", index + 1, line, column),
+            None => format!("规则 #{} 格式错误", index + 1),
+        })?;
+        let rule = match ReplacementRule::from_config(&config) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                eprintln!(
+                    "警告: 跳过 {} 中的无效规则 #{} - {}",
+                    path.display(),
+                    index + 1,
+                    e
+                );
+                None
+            }
+        };
+        // 与基础主题中 id 相同的规则原位替换, 其余规则追加在后面
+        if let (Some(base), Some(id)) = (&mut base, &config.id)
+            && base.override_rule(id, config.clone(), rule.clone())
+        {
+            continue;
+        }
+        rules.extend(rule);
+        configs.push(config);
+    }
+
+    let mut metadata = raw.metadata;
+    if let Some(base) = base {
+        metadata = metadata.inherit(base.metadata);
+        configs.splice(0..0, base.configs);
+        rules.splice(0..0, base.rules);
+    }
+    Ok(Theme {
+        metadata,
+        configs,
+        rules,
+        sources,
+    })
+}
+
+/// `{"id": "banner", "remove": true}` 形式的条目表示删除继承的规则, 返回其 id
+pub fn removal_id(value: &serde_json::Value) -> Option<&str> {
+    let object = value.as_object()?;
+    if object.get("remove")?.as_bool()? {
+        object.get("id")?.as_str()
+    } else {
+        None
+    }
+}
+
+/// 按名称查找主题时尝试的扩展名
+const THEME_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+/// 主题搜索路径: $CLITHEME_PATH, ~/.config/clitheme/themes/, /usr/share/clitheme/themes/
+pub fn theme_search_path() -> Vec<PathBuf> {
+    search_path_from(
+        std::env::var_os("CLITHEME_PATH"),
+        std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")),
+    )
+}
+
+fn search_path_from(clitheme_path: Option<OsString>, home: Option<OsString>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(paths) = clitheme_path {
+        dirs.extend(std::env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()));
+    }
+    if let Some(home) = home {
+        dirs.push(PathBuf::from(home).join(".config/clitheme/themes"));
+    }
+    dirs.push(PathBuf::from("/usr/share/clitheme/themes"));
+    dirs
+}
+
+/// 解析 -apply 参数: 已存在的路径直接使用, 否则在搜索路径中按主题名查找
+pub fn resolve_theme(name: &Path, search_path: &[PathBuf]) -> Result<PathBuf> {
+    if name.exists() || is_stdin_path(name) {
+        return Ok(name.to_path_buf());
+    }
+    for dir in search_path {
+        for ext in THEME_EXTENSIONS {
+            let mut file_name = name.as_os_str().to_os_string();
+            file_name.push(".");
+            file_name.push(ext);
+            let candidate = dir.join(file_name);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+    let searched = search_path
+        .iter()
+        .map(|dir| format!("  {}", dir.display()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    bail!("找不到主题 '{}', 已搜索以下目录:\n{}", name.display(), searched)
+}
+
+/// 按名称或路径加载单个主题
+pub fn load_theme(name: &Path, options: &LoadOptions) -> Result<Theme> {
+    let path = resolve_theme(name, &theme_search_path())?;
+    load_config(&path, options).with_context(|| format!("无法加载主题 {}", path.display()))
+}
+
+/// 按顺序加载多个主题文件, 后加载的规则追加在前面的规则之后; 同一文件只加载一次
+pub fn load_themes(names: &[PathBuf], options: &LoadOptions) -> Result<Vec<Theme>> {
+    let search_path = theme_search_path();
+    let mut seen = HashSet::new();
+    let mut themes = Vec::new();
+    for name in names {
+        let path = &resolve_theme(name, &search_path)?;
+        let key = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        if !seen.insert(key) {
+            continue;
+        }
+        let theme = load_config(path, options)
+            .with_context(|| format!("无法加载主题 {}", path.display()))?;
+        themes.push(theme);
+    }
+    Ok(themes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// 测试用的临时目录, drop 时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("nyantheme-config-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// 加载 JSON 主题, 导出为 format 后重新加载, 返回前后两次的规则配置
+    fn round_trip(name: &str, theme: serde_json::Value, format: ThemeFormat) -> (Vec<ReplacementConfig>, Vec<ReplacementConfig>) {
+        let dir = TempDir::new(name);
+        let options = LoadOptions::default();
+        let original = load_theme(&dir.write("theme.json", &theme.to_string()), &options).unwrap();
+        let text = format.serialize(&original.document()).unwrap();
+        let extension = match format {
+            ThemeFormat::Json => "json",
+            ThemeFormat::Toml => "toml",
+            ThemeFormat::Yaml => "yaml",
+        };
+        let exported = load_theme(&dir.write(&format!("exported.{}", extension), &text), &options).unwrap();
+        (original.configs, exported.configs)
+    }
+
+    #[test]
+    fn when_env_survives_toml_export() {
+        let theme = json!({
+            "replacements": [{
+                "pattern": "error",
+                "replacement": "oops",
+                "when_env": {"CI": null, "TERM": "xterm"}
+            }]
+        });
+        for format in [ThemeFormat::Json, ThemeFormat::Toml, ThemeFormat::Yaml] {
+            let (original, exported) = round_trip("when-env", theme.clone(), format);
+            assert_eq!(exported, original, "{:?}", format);
+            assert_eq!(exported[0].when_env.get("CI"), Some(&None));
+        }
+    }
+
+    #[test]
+    fn when_env_rejects_other_values() {
+        let config = serde_json::from_value::<ReplacementConfig>(json!({
+            "pattern": "error",
+            "replacement": "oops",
+            "when_env": {"CI": false}
+        }));
+        assert!(config.unwrap_err().to_string().contains("when_env 中 CI 的值应为字符串、null 或 true"));
+    }
+
+    /// YAML 主题和等价的 JSON 主题得到完全相同的规则
+    #[test]
+    fn yaml_and_json_themes_are_identical() {
+        let dir = TempDir::new("yaml-json");
+        let json = json!({
+            "replacements": [
+                {
+                    "id": "greeting",
+                    "pattern": "hello (\\w+)",
+                    "replacement": "你好\n再见 $1\n",
+                    "locale": ["zh_CN", "zh_TW"],
+                    "filter_commands": ["echo", "printf"]
+                },
+                {"pattern": "warn", "replacement": ["a", "b"], "locale": "*", "case_insensitive": true}
+            ]
+        });
+        let yaml = r#"
+replacements:
+  - id: greeting
+    pattern: 'hello (\w+)'
+    replacement: |
+      你好
+      再见 $1
+    locale: [zh_CN, zh_TW]
+    filter_commands:
+      - echo
+      - printf
+  - pattern: warn
+    replacement: [a, b]
+    locale: "*"
+    case_insensitive: true
+"#;
+        let options = LoadOptions::default();
+        let from_json = load_theme(&dir.write("theme.json", &json.to_string()), &options).unwrap();
+        let from_yaml = load_theme(&dir.write("theme.yaml", yaml), &options).unwrap();
+        assert_eq!(from_yaml.configs, from_json.configs);
+        let summary = |theme: &Theme| -> Vec<_> {
+            theme
+                .rules
+                .iter()
+                .map(|rule| (rule.id.clone(), rule.pattern.as_str().to_string(), rule.stop, rule.priority))
+                .collect()
+        };
+        assert_eq!(summary(&from_yaml), summary(&from_json));
+    }
+
+    #[test]
+    fn yaml_rule_errors_have_positions() {
+        let dir = TempDir::new("yaml-error");
+        let yaml = "replacements:\n  - pattern: a\n    replacement: b\n  - pattern: c\n    replacment: d\n";
+        let error = load_theme(&dir.write("theme.yaml", yaml), &LoadOptions::default()).err().unwrap();
+        assert!(format!("{:#}", error).contains("规则 #2 格式错误 (第 4 行第 3 列): 未知字段 `replacment`"), "{:#}", error);
+    }
+
+    /// TOML 主题与等价的 JSON 主题相同, 导出为 TOML 再加载后不变
+    #[test]
+    fn toml_round_trip_with_unicode_patterns() {
+        let dir = TempDir::new("toml");
+        let toml = r#"
+name = "猫娘"
+
+[[rules]]
+pattern = '错误: (\d+) 个'
+replacement = "杂鱼♡～ $1 个错误呢"
+filter_commands = ["cargo", "rustc"]
+locale = "zh_CN"
+
+[[rules]]
+pattern = '(?i)^\s*warning… 😿'
+replacement = "喵～"
+filter_commands = ["make"]
+"#;
+        let json = json!({
+            "name": "猫娘",
+            "rules": [
+                {
+                    "pattern": "错误: (\\d+) 个",
+                    "replacement": "杂鱼♡～ $1 个错误呢",
+                    "filter_commands": ["cargo", "rustc"],
+                    "locale": "zh_CN"
+                },
+                {"pattern": "(?i)^\\s*warning… 😿", "replacement": "喵～", "filter_commands": ["make"]}
+            ]
+        });
+        let options = LoadOptions::default();
+        let theme = load_theme(&dir.write("theme.toml", toml), &options).unwrap();
+        let from_json = load_theme(&dir.write("theme.json", &json.to_string()), &options).unwrap();
+        assert_eq!(theme.configs, from_json.configs);
+        assert_eq!(theme.metadata.name.as_deref(), Some("猫娘"));
+        assert_eq!(theme.configs[0].commands, ["cargo", "rustc"]);
+
+        let text = ThemeFormat::Toml.serialize(&theme.document()).unwrap();
+        let exported = load_theme(&dir.write("exported.toml", &text), &options).unwrap();
+        assert_eq!(exported.configs, theme.configs);
+        assert_eq!(exported.metadata.name, theme.metadata.name);
+        assert!(exported.rules[0].pattern.is_match("错误: 3 个"));
+        assert!(exported.rules[1].pattern.is_match("  WARNING… 😿"));
+    }
+
+    #[test]
+    fn search_path_order() {
+        let clitheme_path = std::env::join_paths(["/a", "", "/b"]).unwrap();
+        assert_eq!(
+            search_path_from(Some(clitheme_path), Some("/home/neko".into())),
+            [
+                PathBuf::from("/a"),
+                PathBuf::from("/b"),
+                PathBuf::from("/home/neko/.config/clitheme/themes"),
+                PathBuf::from("/usr/share/clitheme/themes"),
+            ]
+        );
+        assert_eq!(search_path_from(None, None), [PathBuf::from("/usr/share/clitheme/themes")]);
+    }
+
+    #[test]
+    fn resolve_theme_by_name() {
+        let dir = TempDir::new("resolve");
+        let (first, second) = (dir.0.join("first"), dir.0.join("second"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        let search_path = [first.clone(), second.clone()];
+        std::fs::write(second.join("nyancat.toml"), "").unwrap();
+        std::fs::write(second.join("nyancat.yaml"), "").unwrap();
+        std::fs::write(second.join("only.yml"), "").unwrap();
+
+        // 同一目录中按 .json、.toml、.yaml 的顺序, 先找到的目录优先
+        assert_eq!(resolve_theme(Path::new("nyancat"), &search_path).unwrap(), second.join("nyancat.toml"));
+        std::fs::write(first.join("nyancat.json"), "").unwrap();
+        assert_eq!(resolve_theme(Path::new("nyancat"), &search_path).unwrap(), first.join("nyancat.json"));
+        assert_eq!(resolve_theme(Path::new("only"), &search_path).unwrap(), second.join("only.yml"));
+
+        // 已存在的路径和 "-" 直接使用
+        let path = dir.write("local.json", "");
+        assert_eq!(resolve_theme(&path, &search_path).unwrap(), path);
+        assert_eq!(resolve_theme(Path::new("-"), &search_path).unwrap(), Path::new("-"));
+
+        let error = resolve_theme(Path::new("missing"), &search_path).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!("找不到主题 'missing', 已搜索以下目录:\n  {}\n  {}", first.display(), second.display())
+        );
+    }
+
+    /// locale 可以是字符串、数组或任意语言环境 "*", 省略时为 default
+    #[test]
+    fn locale_shapes() {
+        let locale = |value: serde_json::Value| {
+            let mut rule = json!({"pattern": "a", "replacement": "b"});
+            if !value.is_null() {
+                rule["locale"] = value;
+            }
+            serde_json::from_value::<ReplacementConfig>(rule).map(|config| config.locale)
+        };
+        let one = locale(json!("zh_CN")).unwrap();
+        assert_eq!(one, Locales::One("zh_CN".to_string()));
+        let many = locale(json!(["zh_CN", "zh_TW"])).unwrap();
+        assert_eq!(many.iter().collect::<Vec<_>>(), ["zh_CN", "zh_TW"]);
+        let any = locale(json!("*")).unwrap();
+        assert_eq!(any.primary(), "default");
+        assert_eq!(locale(json!(null)).unwrap(), Locales::default());
+        let error = locale(json!(5)).unwrap_err().to_string();
+        assert!(error.contains("locale 应为字符串或字符串数组"), "{}", error);
+
+        // 序列化后保持原来的写法
+        assert_eq!(serde_json::to_value(&many).unwrap(), json!(["zh_CN", "zh_TW"]));
+        assert_eq!(serde_json::to_value(&one).unwrap(), json!("zh_CN"));
+
+        let rules: Vec<ReplacementRule> = [json!("zh_TW"), json!(["zh_CN", "zh_TW"]), json!("*")]
+            .into_iter()
+            .enumerate()
+            .map(|(index, locale)| {
+                let config = json!({"id": index.to_string(), "pattern": "a", "replacement": "b", "locale": locale});
+                ReplacementRule::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+            })
+            .collect();
+        let selected = |locale| -> Vec<String> {
+            crate::filter::select_locale(rules.clone(), locale, true).iter().map(|r| r.label().to_string()).collect()
+        };
+        assert_eq!(selected("zh_CN"), ["1", "2"]);
+        assert_eq!(selected("zh_TW"), ["0", "1", "2"]);
+        assert_eq!(selected("en_US"), ["2"]);
+    }
+
+    #[test]
+    fn probability_out_of_range_is_an_error() {
+        let dir = TempDir::new("probability");
+        for probability in [-0.1, 1.5] {
+            let theme = json!([{"pattern": "a", "replacement": "b", "probability": probability}]);
+            let error = load_theme(&dir.write("theme.json", &theme.to_string()), &LoadOptions::default()).err().unwrap();
+            assert!(format!("{:#}", error).contains("probability 应在 0 到 1 之间"), "{:#}", error);
+        }
+    }
+
+    #[test]
+    fn min_clitheme_version() {
+        let dir = TempDir::new("min-version");
+        let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+        let newer = semver::Version::new(current.major + 1, 0, 0).to_string();
+        let load = |required: &str, options: &LoadOptions| {
+            let theme = json!({"min_clitheme_version": required, "replacements": []});
+            load_theme(&dir.write("theme.json", &theme.to_string()), options).map_err(|e| format!("{:#}", e))
+        };
+        let options = LoadOptions::default();
+
+        assert!(load(&current.to_string(), &options).is_ok());
+        assert!(load("0.0.1", &options).is_ok());
+        let error = load(&newer, &options).err().unwrap();
+        assert!(error.contains(&format!("主题需要 clitheme {} 或更高版本 (当前为 {})", newer, current)), "{}", error);
+        let error = load("1.x", &options).err().unwrap();
+        assert!(error.contains("min_clitheme_version 格式错误, 应为 x.y.z: 1.x"), "{}", error);
+
+        let ignore = LoadOptions { ignore_version_check: true, ..Default::default() };
+        assert!(load(&newer, &ignore).is_ok());
+    }
+}
+
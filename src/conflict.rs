@@ -0,0 +1,129 @@
+use regex_syntax::hir::{Class, Hir, HirKind};
+use std::fmt;
+
+use crate::config::MatchMode;
+use crate::rule::{Replacement, ReplacementRule};
+
+/// 两条规则之间可能的冲突
+pub enum Conflict {
+    /// 两条规则的 pattern 完全相同
+    SamePattern { first: usize, second: usize },
+    /// 前一条规则的替换文本会再被后一条规则改写
+    Rewritten { first: usize, second: usize, text: String },
+    /// 后一条规则能匹配的文本会先被前一条规则改写, 后一条规则可能永远不会生效
+    Shadowed { first: usize, second: usize, text: String },
+}
+
+/// 带有加载位置的规则, 用于在报告中指明是哪一条
+struct Entry<'a> {
+    index: usize,
+    rule: &'a ReplacementRule,
+}
+
+impl fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "规则 @{} ({})", self.index + 1, self.rule.label())
+    }
+}
+
+/// 冲突及所涉及的规则, 用于输出
+pub struct Report<'a> {
+    conflict: Conflict,
+    rules: &'a [&'a ReplacementRule],
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entry = |index: usize| Entry { index, rule: self.rules[index] };
+        match &self.conflict {
+            Conflict::SamePattern { first, second } => {
+                write!(f, "{} 与{} 的 pattern 相同", entry(*first), entry(*second))
+            }
+            Conflict::Rewritten { first, second, text } => {
+                write!(f, "{} 的替换文本 \"{}\" 会再被{} 改写", entry(*first), text, entry(*second))
+            }
+            Conflict::Shadowed { first, second, text } => write!(
+                f,
+                "{} 能匹配的文本 \"{}\" 会先被{} 改写, 该规则可能永远不会生效",
+                entry(*second),
+                text,
+                entry(*first)
+            ),
+        }
+    }
+}
+
+/// 按执行顺序静态检查规则之间的冲突, rules 按加载顺序排列 (报告中的 @N 与 --only 相同)
+pub fn find<'a>(rules: &'a [&'a ReplacementRule], mode: MatchMode) -> Vec<Report<'a>> {
+    // 与 sort_by_priority 相同的稳定排序, 得到实际的执行顺序
+    let mut order: Vec<usize> = (0..rules.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(rules[i].priority));
+
+    let mut conflicts = Vec::new();
+    for (position, &first) in order.iter().enumerate() {
+        let a = rules[first];
+        for &second in &order[position + 1..] {
+            let b = rules[second];
+            // 同一规则的不同语言环境版本只会选用其中一个
+            if a.variant_key == b.variant_key || !a.may_overlap(b) {
+                continue;
+            }
+            if a.pattern.as_str() == b.pattern.as_str() {
+                conflicts.push(Conflict::SamePattern { first, second });
+                continue;
+            }
+            if let Some(text) = sample(b.pattern.as_str()).filter(|text| b.pattern.is_match(text))
+                && a.pattern.is_match(&text)
+            {
+                conflicts.push(Conflict::Shadowed { first, second, text });
+                continue;
+            }
+            // 标记为 stop 的规则或 first_match 模式下, 匹配后不再应用后面的规则
+            if a.stop || mode == MatchMode::FirstMatch {
+                continue;
+            }
+            if let Some(text) = literals(&a.replacement).into_iter().find(|text| b.pattern.is_match(text)) {
+                conflicts.push(Conflict::Rewritten { first, second, text: text.to_string() });
+            }
+        }
+    }
+    conflicts.into_iter().map(|conflict| Report { conflict, rules }).collect()
+}
+
+/// 替换结果中一定会出现的文字
+fn literals(replacement: &Replacement) -> Vec<&str> {
+    match replacement {
+        Replacement::Dictionary(dictionary) => dictionary.values().collect(),
+        _ => replacement.templates().iter().flat_map(|t| t.literals()).collect(),
+    }
+}
+
+/// 构造一段能被模式匹配的最短文本, 模式过于复杂时返回 None
+fn sample(pattern: &str) -> Option<String> {
+    let hir = regex_syntax::parse(pattern).ok()?;
+    let mut text = String::new();
+    sample_into(&hir, &mut text)?;
+    (!text.is_empty()).then_some(text)
+}
+
+fn sample_into(hir: &Hir, text: &mut String) -> Option<()> {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Literal(literal) => text.push_str(std::str::from_utf8(&literal.0).ok()?),
+        HirKind::Class(Class::Unicode(class)) => text.push(class.ranges().first()?.start()),
+        HirKind::Class(Class::Bytes(class)) => text.push(char::from(class.ranges().first()?.start())),
+        HirKind::Repetition(repetition) => {
+            for _ in 0..repetition.min {
+                sample_into(&repetition.sub, text)?;
+            }
+        }
+        HirKind::Capture(capture) => sample_into(&capture.sub, text)?,
+        HirKind::Concat(items) => {
+            for item in items {
+                sample_into(item, text)?;
+            }
+        }
+        HirKind::Alternation(branches) => sample_into(branches.first()?, text)?,
+    }
+    Some(())
+}
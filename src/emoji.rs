@@ -0,0 +1,45 @@
+use std::borrow::Cow;
+
+include!(concat!(env!("OUT_DIR"), "/emoji.rs"));
+
+/// 查找短代码 (不含两侧的冒号) 对应的 emoji
+pub fn lookup(name: &str) -> Option<&'static str> {
+    EMOJI
+        .binary_search_by_key(&name, |&(name, _)| name)
+        .ok()
+        .map(|index| EMOJI[index].1)
+}
+
+/// 把文本中的 `:cat:` 等短代码展开为 emoji, 未知的短代码保持原样
+pub fn expand(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut changed = false;
+    while let Some(start) = rest.find(':') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        match lookup(&after[..name_len]) {
+            Some(emoji) if after[name_len..].starts_with(':') => {
+                result.push_str(emoji);
+                rest = &after[name_len + 1..];
+                changed = true;
+            }
+            // 未知的短代码只输出第一个冒号, 第二个冒号可能是下一个短代码的开头
+            _ => {
+                result.push(':');
+                rest = after;
+            }
+        }
+    }
+    if !changed {
+        return Cow::Borrowed(text);
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
@@ -0,0 +1,141 @@
+use anyhow::{bail, Result};
+use chrono::{Local, NaiveTime};
+use std::collections::HashMap;
+
+use crate::rule::ReplacementRule;
+
+/// 命令行中对规则的筛选条件, 在规则交给输出处理任务之前应用
+#[derive(Debug, Default, Clone)]
+pub struct RuleFilters {
+    /// 只保留这些规则 (id 或 @位置)
+    pub only: Vec<String>,
+    /// 跳过这些规则 (id 或 @位置)
+    pub skip: Vec<String>,
+    /// 强制启用的规则分组
+    pub enable_groups: Vec<String>,
+    /// 强制禁用的规则分组, 优先于 enable_groups
+    pub disable_groups: Vec<String>,
+    /// 运行时的语言环境
+    pub locale: String,
+    /// 是否按 zh_CN -> zh -> default 的顺序回退
+    pub fallback: bool,
+    /// 用于判断规则生效时间段的时刻, 默认为当前本地时间
+    pub now: Option<NaiveTime>,
+    /// 报告因 when_env 条件不满足而跳过的规则
+    pub verbose: bool,
+}
+
+/// 语言环境的回退链, 如 zh_CN 依次回退到 zh 和 default
+pub fn locale_chain(locale: &str, fallback: bool) -> Vec<String> {
+    let mut chain = vec![locale.to_string()];
+    if !fallback {
+        return chain;
+    }
+    let mut current = locale;
+    while let Some(pos) = current.rfind(['_', '-']) {
+        current = &current[..pos];
+        chain.push(current.to_string());
+    }
+    if locale != "default" {
+        chain.push("default".to_string());
+    }
+    chain
+}
+
+/// 只保留适用于该语言环境的规则
+///
+/// 同一条规则 (相同 id, 没有 id 时为相同的 pattern 和命令条件) 有多个语言环境的版本时,
+/// 只保留回退链中最具体的版本; "*" 规则总是适用, 但不如任何具体版本优先
+pub fn select_locale(rules: Vec<ReplacementRule>, locale: &str, fallback: bool) -> Vec<ReplacementRule> {
+    let chain = locale_chain(locale, fallback);
+    let rank = |rule: &ReplacementRule| {
+        rule.locale
+            .iter()
+            .filter_map(|l| match l {
+                "*" => Some(chain.len()),
+                l => chain.iter().position(|c| c == l),
+            })
+            .min()
+    };
+
+    let mut best: HashMap<&str, usize> = HashMap::new();
+    for rule in &rules {
+        if let Some(rank) = rank(rule) {
+            let entry = best.entry(rule.variant_key.as_str()).or_insert(rank);
+            *entry = (*entry).min(rank);
+        }
+    }
+    let keep: Vec<bool> = rules
+        .iter()
+        .map(|rule| rank(rule).is_some_and(|rank| best[rule.variant_key.as_str()] == rank))
+        .collect();
+    rules
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(rule, keep)| keep.then_some(rule))
+        .collect()
+}
+
+/// 规则引用: 按 id 或按加载顺序中的位置 (@5 表示第 5 条, 从 1 开始)
+fn matches_reference(reference: &str, position: usize, rule: &ReplacementRule) -> bool {
+    match reference.strip_prefix('@') {
+        Some(index) => index.parse() == Ok(position),
+        None => rule.id.as_deref() == Some(reference),
+    }
+}
+
+impl RuleFilters {
+    /// 按筛选条件过滤规则, 引用了不存在的规则时返回错误
+    pub fn apply(&self, rules: Vec<ReplacementRule>) -> Result<Vec<ReplacementRule>> {
+        for reference in self.only.iter().chain(&self.skip) {
+            let found = rules
+                .iter()
+                .enumerate()
+                .any(|(i, rule)| matches_reference(reference, i + 1, rule));
+            if !found {
+                bail!("找不到规则 '{}' (共加载 {} 条规则)", reference, rules.len());
+            }
+        }
+
+        for group in self.enable_groups.iter().chain(&self.disable_groups) {
+            if !rules.iter().any(|rule| rule.group.as_ref() == Some(group)) {
+                eprintln!("警告: 没有规则属于分组 '{}'", group);
+            }
+        }
+
+        let now = self.now.unwrap_or_else(|| Local::now().time());
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut rule)| {
+                rule.position = i + 1;
+                (i, rule)
+            })
+            .filter(|(i, rule)| {
+                let hit = |refs: &[String]| refs.iter().any(|r| matches_reference(r, i + 1, rule));
+                (self.only.is_empty() || hit(&self.only)) && !hit(&self.skip)
+            })
+            .filter(|(_, rule)| self.is_enabled(rule) && rule.is_active_at(now))
+            .filter(|(i, rule)| match rule.unmet_env_condition() {
+                Some(reason) => {
+                    if self.verbose {
+                        eprintln!("跳过规则 @{} ({}): {}", i + 1, rule.label(), reason);
+                    }
+                    false
+                }
+                None => true,
+            })
+            .map(|(_, rule)| rule)
+            .collect();
+        Ok(select_locale(rules, &self.locale, self.fallback))
+    }
+
+    /// 规则自身的 enabled 设置可以被所属分组的命令行开关覆盖
+    fn is_enabled(&self, rule: &ReplacementRule) -> bool {
+        match &rule.group {
+            Some(group) if self.disable_groups.contains(group) => false,
+            Some(group) if self.enable_groups.contains(group) => true,
+            _ => rule.enabled,
+        }
+    }
+}
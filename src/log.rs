@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::rule::Stream;
+
+/// --log-raw 或 --log-themed 指定的日志文件
+///
+/// 路径中有 `%s` 时标准输出和标准错误分别写入把 `%s` 替换为 stdout、stderr 的文件,
+/// 否则写入同一个文件, 每行以 `[stdout] ` 或 `[stderr] ` 开头; 一行还没有结束时另一个输出流有输出,
+/// 先换行写入另一个输出流的内容, 这一行其余的部分以 `[stdout+] ` 或 `[stderr+] ` 开头接续
+#[derive(Debug)]
+pub struct LogFile {
+    path: String,
+    target: Target,
+    /// 写入失败后只警告一次并不再写入, 不影响命令运行
+    failed: AtomicBool,
+}
+
+#[derive(Debug)]
+enum Target {
+    Shared(Mutex<Shared>),
+    Separate { stdout: Mutex<File>, stderr: Mutex<File> },
+}
+
+#[derive(Debug)]
+struct Shared {
+    file: File,
+    /// 文件末尾尚未结束的一行属于哪个输出流
+    open: Option<Stream>,
+    /// 两个输出流尚未结束的一行是否被另一个输出流打断, 依次为标准输出和标准错误
+    interrupted: [bool; 2],
+}
+
+impl Shared {
+    /// 只在一行的开头加上输出流标记
+    fn tag(&mut self, stream: Stream, data: &[u8]) -> Vec<u8> {
+        let index = match stream {
+            Stream::Stdout => 0,
+            Stream::Stderr => 1,
+        };
+        let mut tagged = Vec::with_capacity(data.len() + 16);
+        if self.open.is_some_and(|open| open != stream) {
+            tagged.push(b'\n');
+            self.interrupted[1 - index] = true;
+            self.open = None;
+        }
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.open.is_none() {
+                let tag: &[u8] = match (stream, std::mem::take(&mut self.interrupted[index])) {
+                    (Stream::Stdout, false) => b"[stdout] ",
+                    (Stream::Stderr, false) => b"[stderr] ",
+                    (Stream::Stdout, true) => b"[stdout+] ",
+                    (Stream::Stderr, true) => b"[stderr+] ",
+                };
+                tagged.extend_from_slice(tag);
+            }
+            tagged.extend_from_slice(line);
+            self.open = (!line.ends_with(b"\n")).then_some(stream);
+        }
+        tagged
+    }
+}
+
+impl LogFile {
+    pub fn create(path: &str) -> Result<Self> {
+        let create = |path: &str| {
+            File::create(path)
+                .map(Mutex::new)
+                .with_context(|| format!("无法创建日志文件: {}", path))
+        };
+        let target = if path.contains("%s") {
+            Target::Separate {
+                stdout: create(&path.replace("%s", "stdout"))?,
+                stderr: create(&path.replace("%s", "stderr"))?,
+            }
+        } else {
+            let file = File::create(path).with_context(|| format!("无法创建日志文件: {}", path))?;
+            Target::Shared(Mutex::new(Shared {
+                file,
+                open: None,
+                interrupted: [false; 2],
+            }))
+        };
+        Ok(Self {
+            path: path.to_string(),
+            target,
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// 立即写入文件 (不经过缓冲), 使 `tail -f` 能看到每一行
+    pub fn write(&self, stream: Stream, data: &[u8]) {
+        if data.is_empty() || self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        let result = match &self.target {
+            Target::Shared(shared) => {
+                let mut shared = shared.lock().unwrap_or_else(|e| e.into_inner());
+                let tagged = shared.tag(stream, data);
+                shared.file.write_all(&tagged)
+            }
+            Target::Separate { stdout, stderr } => {
+                let file = match stream {
+                    Stream::Stdout => stdout,
+                    Stream::Stderr => stderr,
+                };
+                file.lock().unwrap_or_else(|e| e.into_inner()).write_all(data)
+            }
+        };
+        if let Err(e) = result
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("警告: 无法写入日志文件 {}, 不再写入: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_log(name: &str, writes: &[(Stream, &str)]) -> String {
+        let path = std::env::temp_dir().join(format!("nyantheme-{}-{}.log", name, std::process::id()));
+        let log = LogFile::create(path.to_str().unwrap()).unwrap();
+        for (stream, data) in writes {
+            log.write(*stream, data.as_bytes());
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        content
+    }
+
+    #[test]
+    fn tags_only_at_line_start() {
+        let log = write_log("partial", &[(Stream::Stdout, ">>> "), (Stream::Stdout, "hi\n"), (Stream::Stderr, "err\n")]);
+        assert_eq!(log, "[stdout] >>> hi\n[stderr] err\n");
+    }
+
+    #[test]
+    fn carriage_returns_stay_on_one_line() {
+        let log = write_log("progress", &[(Stream::Stdout, "10%\r"), (Stream::Stdout, "99%\r"), (Stream::Stdout, "done\n")]);
+        assert_eq!(log, "[stdout] 10%\r99%\rdone\n");
+    }
+
+    #[test]
+    fn interrupted_line_continues_with_marker() {
+        let log = write_log("interleaved", &[(Stream::Stdout, ">>> "), (Stream::Stderr, "warn\n"), (Stream::Stdout, "hi\n")]);
+        assert_eq!(log, "[stdout] >>> \n[stderr] warn\n[stdout+] hi\n");
+    }
+}
@@ -1,19 +1,72 @@
-use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+mod action;
+mod banner;
+mod commands;
+mod config;
+mod conflict;
+mod emoji;
+mod filter;
+mod log;
+mod output;
+mod position;
+#[cfg(unix)]
+mod pty;
+mod rainbow;
+mod record;
+mod rule;
+mod script;
+mod segment;
+mod signal;
+mod stats;
+mod style;
+mod template;
+mod terminal;
+mod timestamp;
+mod trace;
+mod watch;
+mod window;
+
+use anyhow::{anyhow, Result};
+use chrono::NaiveTime;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use encoding_rs::Encoding;
 use regex::Regex;
-use serde::Deserialize;
 use std::{
+    borrow::Cow,
     ffi::OsString,
+    io::IsTerminal,
     path::PathBuf,
     process::{Command, Stdio},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
-    process::Command as AsyncCommand,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    process::{Child, Command as AsyncCommand},
+    sync::{mpsc, Notify},
     task,
 };
 
+use crate::banner::{format_elapsed, Banner};
+use crate::config::{is_stdin_path, load_themes, LoadOptions, ThemeFormat};
+use crate::config::{prompt_patterns, MatchMode, Normalization, Streams, Theme};
+use crate::filter::RuleFilters;
+use crate::log::LogFile;
+use crate::output::{FlushPolicy, Output};
+use crate::rainbow::Rainbow;
+use crate::record::Recorder;
+use crate::stats::Stats;
+use crate::timestamp::Timestamps;
+use crate::trace::Tracer;
+use crate::window::Window;
+use crate::segment::{parse_encoding, OutputEncoding, OutputEncoder, Segment, SegmentReader, Terminator};
+use crate::rule::{
+    apply_replacements, parse_time, sort_by_priority, Abort, CommandContext, Counters, LineOutput,
+    ReplaceState, ReplacementRule, SharedRules, Stream,
+};
+
 /// 命令行输出文本替换工具
 #[derive(Parser, Debug)]
 #[command(
@@ -22,227 +75,1492 @@ use tokio::{
     author,
     about,
     long_about = "示例:\n  clitheme -apply config.json -- python3 script.py\n  clitheme -apply config.json python3 script.py",
-    after_help = "示例: clitheme -apply theme.json -- python3 -i"
+    after_help = "示例: clitheme -apply theme.json -- python3 -i",
+    args_conflicts_with_subcommands = true
 )]
 struct Args {
-    /// 包含替换规则的JSON配置文件
-    #[arg(short, long, required = true)]
-    apply: PathBuf,
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
+    /// 包含替换规则的配置文件 (JSON、TOML 或 YAML) 或目录, 可多次指定, 规则按顺序合并;
+    /// "-" 表示从标准输入读取 [默认: $CLITHEME_THEME]
+    #[arg(short, long)]
+    apply: Vec<PathBuf>,
+
+    /// 指定使用的语言环境, default 表示不使用语言环境
+    /// [默认: $CLITHEME_LOCALE, 其次从 LC_ALL/LC_MESSAGES/LANG 推断]
+    #[arg(short, long)]
+    locale: Option<String>,
+
+    /// 不回退到更通用的语言环境 (如 zh_CN 不回退到 zh 和 default)
+    #[arg(long)]
+    no_fallback: bool,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// 显示主题信息等详细输出
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// 命令结束后在标准错误输出每条规则在两个输出流中生效的行数和替换次数
+    #[arg(long)]
+    stats: bool,
+
+    /// 命令结束后列出适用于本次命令却从未生效的规则 (--stats 也会列出)
+    #[arg(long)]
+    report_unused: bool,
+
+    /// 在标准错误逐行输出原文、每条规则是否跳过或生效以及生效后的文本, 以 `[trace]` 开头
+    #[arg(long)]
+    trace: bool,
+
+    /// 没有任何规则生效 (或指定了规则的 id 时该规则没有生效) 时以退出码 3 退出, 命令本身失败时仍使用命令的退出码
+    #[arg(long, value_name = "RULE", num_args = 0..=1, require_equals = true)]
+    require_match: Option<Option<String>>,
+
+    /// --trace 最多跟踪的行数, 避免输出过多
+    #[arg(long, value_name = "N", requires = "trace")]
+    trace_limit: Option<usize>,
+
+    /// 命令结束后把命令、语言环境、每条规则的统计、各输出流的行数、耗时和退出码写为 JSON, `-` 表示标准错误
+    #[arg(long, value_name = "FILE")]
+    stats_json: Option<PathBuf>,
+
+    /// 主题文件变化时自动重新加载规则
+    #[arg(short, long)]
+    watch: bool,
+
+    /// 只启用这些规则, 以逗号分隔的 id 或 @位置 (如 @5)
+    #[arg(long, value_delimiter = ',', conflicts_with = "skip")]
+    only: Vec<String>,
+
+    /// 跳过这些规则, 以逗号分隔的 id 或 @位置 (如 @5)
+    #[arg(long, value_delimiter = ',')]
+    skip: Vec<String>,
+
+    /// 启用指定分组的规则 (包括默认 "enabled": false 的规则)
+    #[arg(long, value_delimiter = ',')]
+    enable_group: Vec<String>,
+
+    /// 禁用指定分组的规则
+    #[arg(long, value_delimiter = ',')]
+    disable_group: Vec<String>,
+
+    /// 以该时刻 (HH:MM) 判断规则的生效时间段, 代替当前时间
+    #[arg(long, value_parser = parse_time)]
+    now: Option<NaiveTime>,
+
+    /// 随机替换使用的种子, 指定后结果可以复现
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// 何时输出规则加上的颜色: auto (输出流是终端时)、always、never; 未指定时遵循 NO_COLOR
+    #[arg(long, value_enum)]
+    color: Option<ColorChoice>,
+
+    /// 去掉命令输出中的 ANSI 转义序列 (颜色、标题、超链接等), 包括规则加上的颜色
+    #[arg(long)]
+    strip_ansi: bool,
+
+    /// 所有规则都忽略输出中的 ANSI 转义序列进行匹配 (同规则的 "ansi_aware": true)
+    #[arg(long)]
+    ansi_aware: bool,
+
+    /// 对命令输出的每一行进行 Unicode 规范化后再匹配规则
+    #[arg(long, value_enum, default_value_t = Normalization::None)]
+    normalize: Normalization,
+
+    /// 在应用规则之后给每行输出加上 lolcat 风格的彩虹渐变 (不输出颜色时无效)
+    #[arg(long)]
+    rainbow: bool,
+
+    /// 彩虹的起始颜色, 默认随机
+    #[arg(long, value_name = "SEED", requires = "rainbow")]
+    rainbow_seed: Option<u64>,
+
+    /// 彩虹颜色变化的频率
+    #[arg(long, value_name = "FREQ", default_value_t = 0.1, requires = "rainbow")]
+    rainbow_freq: f64,
+
+    /// 把命令输出中的 :cat: 等 emoji 短代码展开为 emoji
+    #[arg(long)]
+    emoji: bool,
+
+    /// 标准输出和标准错误共用 {count}/{total_count} 计数, 默认各自计数
+    #[arg(long)]
+    shared_counters: bool,
+
+    /// 命令输出的编码 (如 gbk、shift_jis), 转换为 UTF-8 后再应用规则; 默认使用主题的 input_encoding
+    #[arg(long, value_name = "ENCODING", value_parser = parse_encoding)]
+    input_encoding: Option<&'static Encoding>,
+
+    /// 把输出转换为指定编码 (如 gbk) 再写出, 默认输出 UTF-8
+    #[arg(long, value_name = "ENCODING", value_parser = parse_encoding)]
+    output_encoding: Option<&'static Encoding>,
+
+    /// 输出编码无法表示的字符 (如 emoji) 写为此文本
+    #[arg(long, value_name = "TEXT", default_value = "?", requires = "output_encoding")]
+    output_fallback: String,
+
+    /// 单独指定标准错误的编码, 默认与 --input-encoding 相同
+    #[arg(long, value_name = "ENCODING", value_parser = parse_encoding)]
+    stderr_input_encoding: Option<&'static Encoding>,
+
+    /// 按块读取命令输出并立即转发, 规则分别应用于以换行或回车分隔的每一段, 使进度条正常刷新
+    #[arg(long)]
+    raw: bool,
+
+    /// 按行处理时一行的最大长度 (如 64K、4M), 更长的行不应用规则, 分块直接输出
+    #[arg(long, value_name = "SIZE", default_value = "4M", value_parser = segment::parse_size)]
+    max_line_length: usize,
+
+    /// 何时刷新输出: line 每行刷新, block 缓冲区写满时刷新, interval:<毫秒> 最多每隔这么久刷新一次;
+    /// 命令暂停输出或结束时都会刷新
+    #[arg(long, value_name = "POLICY", default_value = "line", value_parser = FlushPolicy::parse)]
+    flush: FlushPolicy,
+
+    /// 把标准错误合并到标准输出 (类似 `2>&1`), 两个输出流按读到的顺序处理, 规则仍按原来的输出流生效
+    #[arg(long)]
+    merge_output: bool,
+
+    /// 在伪终端中运行命令 (仅 Unix), 使检查 isatty 的程序 (vim、分页器、REPL 等) 正常工作;
+    /// 子进程的标准输出和标准错误都按标准输出处理, 按键原样转发
+    #[arg(long, conflicts_with_all = ["merge_output", "no_theme_stderr", "paragraph_mode"])]
+    pty: bool,
+
+    /// 标准错误不应用规则, 按字节原样输出, 便于错误解析工具和 IDE 的问题匹配器使用
+    #[arg(long, conflicts_with_all = ["merge_output", "output_format"])]
+    no_theme_stderr: bool,
+
+    /// 标准输出不应用规则, 按字节原样输出
+    #[arg(long, conflicts_with_all = ["merge_output", "output_format"])]
+    no_theme_stdout: bool,
+
+    /// 以空行分隔的每一段整体应用规则, `(?s)` 模式可以跨越段中的多行
+    #[arg(long, conflicts_with_all = ["raw", "merge_output"])]
+    paragraph_mode: bool,
+
+    /// paragraph 模式下一段的最大长度 (如 64K), 一直没有空行时超过的部分先作为一段处理
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = segment::parse_size, requires = "paragraph_mode")]
+    max_paragraph_size: usize,
+
+    /// 没有新的行时, 等待 multiline 规则匹配的行最多保留的时间 (毫秒)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_MULTILINE_HOLD.as_millis() as u64)]
+    multiline_hold: u64,
 
-    /// 指定使用的语言环境
-    #[arg(short, long, default_value = "default")]
-    locale: String,
+    /// 读到没有换行的内容 (如交互式命令的提示符) 后, 这么久没有新的输出就先输出 (毫秒), 0 表示一直等到换行
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_IDLE_FLUSH.as_millis() as u64)]
+    idle_flush: u64,
+
+    /// 把命令的原始输出写入文件, 路径中的 `%s` 替换为 stdout/stderr 时两个输出流分别写入,
+    /// 否则写入同一文件并在每行前标记输出流
+    #[arg(long, value_name = "FILE")]
+    log_raw: Option<String>,
+
+    /// 把处理后的输出写入文件, 路径的规则同 --log-raw
+    #[arg(long, value_name = "FILE")]
+    log_themed: Option<String>,
+
+    /// 把处理后的输出 (包括转义序列和 header、footer) 录制为 asciinema v2 格式的文件
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+
+    /// 在每行输出前加上时间戳, 可指定 strftime 格式 (默认 "%H:%M:%S%.3f "),
+    /// `--timestamps=elapsed` 显示从启动开始经过的秒数
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = timestamp::DEFAULT_FORMAT,
+        value_parser = Timestamps::parse
+    )]
+    timestamps: Option<Timestamps>,
+
+    /// 输出格式, jsonl 时每行输出一条包含原文、处理结果和生效规则的 JSON 记录 (两个输出流都写到标准输出)
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "timestamps")]
+    output_format: OutputFormat,
+
+    /// 下游关闭管道 (如 `| head`) 后如何处理子进程: drain 继续读取并丢弃其输出, terminate 终止子进程
+    #[arg(long, value_enum, value_name = "ACTION", default_value_t = BrokenPipe::Drain)]
+    on_broken_pipe: BrokenPipe,
+
+    /// 不显示主题的 header 和 footer, 便于在脚本中使用
+    #[arg(long)]
+    no_banner: bool,
+
+    /// 启动时检查规则之间的冲突 (同 check 子命令), 发现时输出警告
+    #[arg(long)]
+    warn_conflicts: bool,
 
     /// 要执行的命令及其参数
     command: Vec<OsString>,
 }
 
-/// 替换规则配置
-#[derive(Debug, Deserialize)]
-struct ReplacementConfig {
-    pattern: String,
-    replacement: String,
-    #[serde(default = "default_locale")]
-    locale: String,
-    #[serde(default, rename = "filter_commands")]
-    commands: Vec<String>,
-}
+/// 主题加载相关参数, 运行命令和各子命令共用
+#[derive(clap::Args, Debug)]
+struct LoadArgs {
+    /// 只接受严格的JSON主题 (不允许注释、尾随逗号)
+    #[arg(long)]
+    strict_json: bool,
 
-fn default_locale() -> String {
-    "default".to_string()
-}
+    /// 以目录作为主题时同时加载子目录中的主题片段
+    #[arg(long)]
+    recursive: bool,
+
+    /// 忽略规则中的未知字段 (用于为新版本编写的主题)
+    #[arg(long)]
+    lenient: bool,
 
-/// 编译后的替换规则
-#[derive(Clone)] // 实现 Clone trait
-struct ReplacementRule {
-    pattern: Regex,
-    replacement: String,
-    locale: String,
-    commands: Vec<String>,
+    /// 不检查主题要求的最低 clitheme 版本
+    #[arg(long)]
+    ignore_version_check: bool,
 }
 
-impl ReplacementRule {
-    fn from_config(config: &ReplacementConfig) -> Result<Self> {
-        let pattern = Regex::new(&config.pattern)
-            .with_context(|| format!("无效的正则表达式: {}", config.pattern))?;
-        Ok(Self {
-            pattern,
-            replacement: config.replacement.clone(),
-            locale: config.locale.clone(),
-            commands: config.commands.iter().map(|s| s.to_lowercase()).collect(),
-        })
+impl LoadArgs {
+    fn options(&self) -> LoadOptions {
+        LoadOptions {
+            strict_json: self.strict_json,
+            recursive: self.recursive,
+            lenient: self.lenient,
+            ignore_version_check: self.ignore_version_check,
+        }
     }
 }
 
-/// 加载并验证JSON配置文件
-fn load_config(path: &PathBuf) -> Result<Vec<ReplacementRule>> {
-    let data = std::fs::read_to_string(path)
-        .with_context(|| format!("无法读取配置文件: {}", path.display()))?;
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// 加载主题并以规范化形式导出
+    Export {
+        /// 主题文件路径或名称
+        theme: PathBuf,
 
-    let config: serde_json::Value = serde_json::from_str(&data)
-        .with_context(|| "配置文件格式错误: 必须是有效的JSON".to_string())?;
+        /// 输出格式
+        #[arg(short, long, value_enum, default_value_t = ThemeFormat::Json)]
+        format: ThemeFormat,
 
-    // 检查根元素是否是字典
-    let replacements = config
-        .get("replacements")
-        .and_then(|v| v.as_array())
-        .ok_or_else(|| anyhow!("配置文件格式错误: 根元素必须是字典且包含'replacements'数组"))?;
+        /// 输出文件, 默认输出到标准输出
+        #[arg(short, long)]
+        output: Option<PathBuf>,
 
-    let configs: Vec<ReplacementConfig> = serde_json::from_value(
-        serde_json::Value::Array(replacements.clone())
-    )?;
+        #[command(flatten)]
+        load: LoadArgs,
+    },
 
-    let mut rules = Vec::new();
-    for config in configs {
-        match ReplacementRule::from_config(&config) {
-            Ok(rule) => rules.push(rule),
-            Err(e) => eprintln!("警告: 跳过无效规则 - {}", e),
-        }
-    }
-    Ok(rules)
-}
+    /// 按顺序合并多个主题为一个文件
+    Merge {
+        /// 要合并的主题文件路径或名称
+        #[arg(required = true)]
+        themes: Vec<PathBuf>,
+
+        /// 输出文件, 默认输出到标准输出
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// 输出格式, 默认根据输出文件扩展名判断, 否则为 JSON
+        #[arg(short, long, value_enum)]
+        format: Option<ThemeFormat>,
+
+        /// 丢弃完全相同的重复规则
+        #[arg(long)]
+        dedupe: bool,
+
+        /// 覆盖合并后主题的名称
+        #[arg(long)]
+        name: Option<String>,
+
+        /// 覆盖合并后主题的版本
+        #[arg(long)]
+        version: Option<String>,
 
-/// 应用所有匹配的替换规则到文本
-fn apply_replacements(
-    text: &str,
-    command_name: &str,
-    rules: &[ReplacementRule],
-    locale: &str,
-) -> String {
-    let command_name = command_name.to_lowercase();
-    let mut result = text.to_string(); // 保留 mut 是必要的
+        #[command(flatten)]
+        load: LoadArgs,
+    },
 
-    for rule in rules {
-        if !rule.commands.is_empty() && !rule.commands.contains(&command_name) {
-            continue;
+    /// 运行规则中附带的测试用例, 有失败时以非零状态退出
+    Test {
+        /// 要测试的主题文件路径或名称, 多个主题按顺序合并
+        #[arg(required = true)]
+        themes: Vec<PathBuf>,
+
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+
+    /// 检查主题中的每一条规则, 有问题时以非零状态退出
+    Check {
+        /// 要检查的主题文件路径或名称
+        #[arg(required = true)]
+        themes: Vec<PathBuf>,
+
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+
+    /// 把 --log-raw 记录的日志 (或 asciinema 录像) 当作命令的输出, 经过主题处理后输出
+    Replay {
+        /// --log-raw 写出的日志文件或 asciinema v2 录像 (.cast)
+        file: PathBuf,
+
+        /// 包含替换规则的主题, 可多次指定 [默认: $CLITHEME_THEME]
+        #[arg(short, long)]
+        apply: Vec<PathBuf>,
+
+        /// 作为该命令的输出处理 (如 "cargo build"), 使 filter_commands 和 args_pattern 生效
+        #[arg(long, value_name = "COMMAND")]
+        as_command: Option<String>,
+
+        /// 按录像记录的时间回放时的速度倍数
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// 忽略录像记录的时间, 立即输出
+        #[arg(long)]
+        no_delay: bool,
+
+        /// 指定使用的语言环境 [默认: $CLITHEME_LOCALE, 其次从 LC_ALL/LC_MESSAGES/LANG 推断]
+        #[arg(short, long)]
+        locale: Option<String>,
+
+        /// 不回退到更通用的语言环境
+        #[arg(long)]
+        no_fallback: bool,
+
+        /// 何时输出规则加上的颜色
+        #[arg(long, value_enum)]
+        color: Option<ColorChoice>,
+
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+
+    /// 把文件当作命令的输出反复处理, 测量主题的吞吐量和每条规则的耗时占比
+    Bench {
+        /// 要测量的主题文件路径或名称, 多个主题按顺序合并
+        #[arg(required = true)]
+        themes: Vec<PathBuf>,
+
+        /// 作为命令输出处理的文件
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// 每项测量运行的次数, 取最快的一次
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+
+        /// 以 JSON 输出结果
+        #[arg(long)]
+        json: bool,
+
+        /// 作为该命令的输出处理 (如 "cargo build"), 使 filter_commands 和 args_pattern 生效
+        #[arg(long, value_name = "COMMAND")]
+        as_command: Option<String>,
+
+        /// 指定使用的语言环境 [默认: $CLITHEME_LOCALE, 其次从 LC_ALL/LC_MESSAGES/LANG 推断]
+        #[arg(short, long)]
+        locale: Option<String>,
+
+        /// 不回退到更通用的语言环境
+        #[arg(long)]
+        no_fallback: bool,
+
+        #[command(flatten)]
+        load: LoadArgs,
+    },
+}
+
+/// 执行子命令, 返回退出码
+async fn run_subcommand(subcommand: Commands) -> Result<i32> {
+    match subcommand {
+        Commands::Export {
+            theme,
+            format,
+            output,
+            load,
+        } => commands::export::run(&theme, format, output.as_deref(), &load.options()).map(|_| 0),
+        Commands::Merge {
+            themes,
+            output,
+            format,
+            dedupe,
+            name,
+            version,
+            load,
+        } => commands::merge::run(
+            commands::merge::MergeArgs {
+                themes,
+                output,
+                format,
+                dedupe,
+                name,
+                version,
+            },
+            &load.options(),
+        )
+        .map(|_| 0),
+        Commands::Test { themes, load } => {
+            commands::test::run(&themes, &load.options()).map(|ok| if ok { 0 } else { 1 })
+        }
+        Commands::Check { themes, load } => {
+            commands::check::run(&themes, &load.options()).map(|ok| if ok { 0 } else { 1 })
+        }
+        Commands::Replay {
+            file,
+            apply,
+            as_command,
+            speed,
+            no_delay,
+            locale,
+            no_fallback,
+            color,
+            load,
+        } => {
+            let themes = if apply.is_empty() { default_theme_paths() } else { apply };
+            let options = commands::replay::ReplayOptions {
+                as_command,
+                speed,
+                no_delay,
+                locale: resolve_locale(locale).0,
+                fallback: !no_fallback,
+                color: ColorChoice::resolve(color),
+            };
+            commands::replay::run(&file, &themes, &load.options(), options).await
         }
-        if rule.locale != locale {
-            continue;
+        Commands::Bench {
+            themes,
+            input,
+            iterations,
+            json,
+            as_command,
+            locale,
+            no_fallback,
+            load,
+        } => {
+            let options = commands::bench::BenchOptions {
+                as_command,
+                iterations: iterations as usize,
+                json,
+                locale: resolve_locale(locale).0,
+                fallback: !no_fallback,
+            };
+            commands::bench::run(&input, &themes, &load.options(), options).await
         }
-        result = rule.pattern.replace_all(&result, &rule.replacement).to_string();
     }
-    result
 }
 
 /// 处理流数据并应用替换规则
 async fn process_stream<R, W>(
     reader: R,
-    mut writer: W,
-    command_name: &str,
-    rules: Arc<Vec<ReplacementRule>>,
-    locale: &str,
-) -> Result<()>
+    writer: W,
+    command: &CommandContext,
+    rules: SharedRules,
+    stream: Stream,
+    options: &RunOptions,
+    abort: mpsc::UnboundedSender<Abort>,
+) -> Result<usize>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    let mut writer = Output::new(writer, options.flush);
+    if !options.themes(stream) {
+        return copy_stream(reader, writer, stream, options).await;
+    }
+    let mut themer = LineThemer::new(command, rules, stream, options);
+    let mut reader = segment_reader(reader, stream, options);
+    if options.raw {
+        return process_raw(reader, writer, themer, abort).await;
+    }
+    if let Some(max_size) = options.paragraph_mode {
+        return process_paragraphs(reader, writer, themer, max_size, abort).await;
+    }
+    loop {
+        // 窗口中有等待 multiline 规则匹配的行时, 一段时间内没有新的行就先输出这些行
+        let next = if themer.holding() {
+            match tokio::time::timeout(options.multiline_hold, writer.during(reader.next_segment())).await {
+                Ok(next) => next??,
+                Err(_) => {
+                    let mut buffer = Vec::new();
+                    let aborted = themer.flush_window(&mut buffer);
+                    emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+                    continue;
+                }
+            }
+        } else {
+            writer.during(reader.next_segment()).await??
+        };
+        let Some(segment) = next else {
+            break;
+        };
+        themer.input(&segment);
+        // 按行读取, 跨越读取块的转义序列已经拼接完整; 进度条等用 `\r` 重绘的每一段单独处理
+        let mut buffer = Vec::new();
+        let aborted = themer.theme_line(segment, &mut buffer);
+        emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+    }
+    let mut buffer = Vec::new();
+    let aborted = themer.flush_window(&mut buffer);
+    emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+    finish_stream(&mut writer, &mut themer).await?;
+    Ok(themer.dropped())
+}
+
+/// --no-theme-stdout/--no-theme-stderr: 不处理的输出流按字节原样复制, 同时写入日志和录像
+async fn copy_stream<R, W>(mut reader: R, mut writer: Output<W>, stream: Stream, options: &RunOptions) -> Result<usize>
 where
-    R: tokio::io::AsyncBufReadExt + Unpin,
+    R: tokio::io::AsyncRead + Unpin,
     W: tokio::io::AsyncWriteExt + Unpin,
 {
-    let mut lines = reader.lines();
-    while let Some(line) = lines.next_line().await? {
-        let processed = apply_replacements(&line, command_name, &rules, locale);
-        writer.write_all(processed.as_bytes()).await?;
-        writer.write_all(b"\n").await?;
-        writer.flush().await?;
+    let mut buffer = [0; 8192];
+    let (mut lines, mut unterminated) = (0, false);
+    loop {
+        let read = writer.during(reader.read(&mut buffer)).await??;
+        if read == 0 {
+            writer.flush().await?;
+            if let Some(stats) = &options.stats {
+                stats.merge(stream, lines + usize::from(unterminated), Default::default());
+            }
+            return Ok(0);
+        }
+        let data = &buffer[..read];
+        lines += data.iter().filter(|&&b| b == b'\n').count();
+        unterminated = data.last() != Some(&b'\n');
+        for log in [&options.log_raw, &options.log_themed].into_iter().flatten() {
+            log.write(stream, data);
+        }
+        if let Some(recorder) = &options.record {
+            recorder.output(data);
+        }
+        writer.write(data).await?;
+        options.check_closed(&writer);
+    }
+}
+
+/// 按输出流的编码读取子进程输出
+fn segment_reader<R: tokio::io::AsyncRead + Unpin>(reader: R, stream: Stream, options: &RunOptions) -> SegmentReader<R> {
+    let encoding = match stream {
+        Stream::Stdout => options.input_encoding,
+        Stream::Stderr => options.stderr_input_encoding,
+    };
+    SegmentReader::new(reader, encoding)
+        .with_max_line_length(options.max_line_length)
+        .with_idle_flush(options.idle_flush)
+        .with_prompt_patterns(options.prompt_patterns.clone())
+}
+
+/// --merge-output: 两个输出流读到的内容按到达顺序由同一个任务处理, 都写到 writer
+///
+/// 每个输出流仍然使用各自的规则和状态, 同一输出流中的顺序保持不变
+async fn process_merged<R1, R2, W>(
+    stdout: R1,
+    stderr: R2,
+    writer: W,
+    command: &CommandContext,
+    rules: SharedRules,
+    options: &RunOptions,
+    abort: mpsc::UnboundedSender<Abort>,
+) -> Result<usize>
+where
+    R1: tokio::io::AsyncRead + Unpin,
+    R2: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    // 有界的通道使处理跟不上时读取暂停, 子进程的输出留在管道中
+    let (sender, mut receiver) = mpsc::channel(64);
+    let mut writer = Output::new(writer, options.flush);
+    let read_stdout = forward(segment_reader(stdout, Stream::Stdout, options), Stream::Stdout, options.raw, sender.clone());
+    let read_stderr = forward(segment_reader(stderr, Stream::Stderr, options), Stream::Stderr, options.raw, sender);
+    // 处理出错时 receiver 随之释放, 转发的任务不会阻塞在已满的通道上
+    let consume = async move {
+        let mut themers = [
+            LineThemer::new(command, rules.clone(), Stream::Stdout, options),
+            LineThemer::new(command, rules, Stream::Stderr, options),
+        ];
+        loop {
+            let next = if themers.iter().any(LineThemer::holding) {
+                match tokio::time::timeout(options.multiline_hold, writer.during(receiver.recv())).await {
+                    Ok(next) => next?,
+                    Err(_) => {
+                        for themer in &mut themers {
+                            let mut buffer = Vec::new();
+                            let aborted = themer.flush_window(&mut buffer);
+                            emit(&mut writer, themer, &buffer, aborted, &abort).await?;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                writer.during(receiver.recv()).await?
+            };
+            let Some((stream, segments)) = next else {
+                break;
+            };
+            let themer = match stream {
+                Stream::Stdout => &mut themers[0],
+                Stream::Stderr => &mut themers[1],
+            };
+            let mut buffer = Vec::new();
+            let mut aborted = None;
+            for segment in segments {
+                themer.input(&segment);
+                let matched = if options.raw {
+                    themer.theme_segment(&segment, &mut buffer).abort
+                } else {
+                    themer.theme_line(segment, &mut buffer)
+                };
+                aborted = aborted.or(matched);
+            }
+            emit(&mut writer, themer, &buffer, aborted, &abort).await?;
+        }
+        let mut dropped = 0;
+        for themer in &mut themers {
+            let mut buffer = Vec::new();
+            let aborted = themer.flush_window(&mut buffer);
+            emit(&mut writer, themer, &buffer, aborted, &abort).await?;
+            finish_stream(&mut writer, themer).await?;
+            dropped += themer.dropped();
+        }
+        Ok::<_, anyhow::Error>(dropped)
+    };
+    let (read_stdout, read_stderr, dropped) = tokio::join!(read_stdout, read_stderr, consume);
+    read_stdout?;
+    read_stderr?;
+    dropped
+}
+
+/// 把一个输出流读到的内容转发给 process_merged, 按行处理时每次一行, raw 模式下每次一块
+async fn forward<R: tokio::io::AsyncRead + Unpin>(
+    mut reader: SegmentReader<R>,
+    stream: Stream,
+    raw: bool,
+    sender: mpsc::Sender<(Stream, Vec<Segment>)>,
+) -> Result<()> {
+    loop {
+        let segments = if raw {
+            reader.read_chunk().await?
+        } else {
+            reader.next_segment().await?.map(|segment| vec![segment])
+        };
+        let Some(segments) = segments else {
+            return Ok(());
+        };
+        if sender.send((stream, segments)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// 写出处理好的内容, 有 abort 规则匹配时通知 execute_command 终止子进程
+///
+/// 匹配的行已经输出, 终止子进程后继续输出管道中剩余的内容
+async fn emit<W: tokio::io::AsyncWriteExt + Unpin>(
+    writer: &mut Output<W>,
+    themer: &LineThemer<'_>,
+    buffer: &[u8],
+    aborted: Option<Abort>,
+    abort: &mpsc::UnboundedSender<Abort>,
+) -> Result<()> {
+    themer.log_themed(buffer);
+    writer.write(buffer).await?;
+    themer.options.check_closed(writer);
+    if let Some(matched) = aborted {
+        let _ = abort.send(matched);
     }
     Ok(())
 }
 
-/// 执行命令并处理输出
-async fn execute_command(
-    command: &[OsString],
-    rules: &[ReplacementRule],
-    locale: &str,
-) -> Result<i32> {
-    if command.is_empty() {
-        return Err(anyhow!("必须指定要执行的命令"));
+/// paragraph 模式: 以空行分隔的每一段整体应用规则, `(?s)` 模式可以跨越段中的多行
+///
+/// 读到空行或输出结束时处理一段; 一直没有空行时, 超过 max_size 字节的部分先作为一段处理
+async fn process_paragraphs<R, W>(
+    mut reader: SegmentReader<R>,
+    mut writer: Output<W>,
+    mut themer: LineThemer<'_>,
+    max_size: usize,
+    abort: mpsc::UnboundedSender<Abort>,
+) -> Result<usize>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    let mut paragraph = Vec::new();
+    let mut size = 0;
+    while let Some(segment) = writer.during(reader.next_segment()).await?? {
+        themer.input(&segment);
+        let blank = segment.terminator != Terminator::None && segment.text.iter().all(u8::is_ascii_whitespace);
+        let mut buffer = Vec::new();
+        let aborted = if blank || segment.oversized || segment.partial {
+            let aborted = themer.theme_paragraph(&paragraph, &mut buffer);
+            // 空行原样输出, 保持原来的分段; 提示符等尚未结束的一行不等空行就输出
+            if blank {
+                themer.write_original(&segment, &mut buffer);
+            } else {
+                themer.theme_segment(&segment, &mut buffer);
+            }
+            aborted
+        } else {
+            size += segment.text.len() + 1;
+            paragraph.push(segment);
+            if size < max_size {
+                continue;
+            }
+            themer.theme_paragraph(&paragraph, &mut buffer)
+        };
+        paragraph.clear();
+        size = 0;
+        emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+    }
+    let mut buffer = Vec::new();
+    let aborted = themer.theme_paragraph(&paragraph, &mut buffer);
+    emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+    finish_stream(&mut writer, &mut themer).await?;
+    Ok(themer.dropped())
+}
+
+/// raw 模式: 按块读取, 读到的内容立即输出, 规则分别应用于以换行或回车分隔的每一段
+///
+/// 进度条等用 `\r` 重绘的输出与不使用 clitheme 时一样实时刷新; 尚未结束的一段也会立即输出,
+/// 因此一次匹配不会跨越两次读取
+async fn process_raw<R, W>(
+    mut reader: SegmentReader<R>,
+    mut writer: Output<W>,
+    mut themer: LineThemer<'_>,
+    abort: mpsc::UnboundedSender<Abort>,
+) -> Result<usize>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWriteExt + Unpin,
+{
+    while let Some(segments) = writer.during(reader.read_chunk()).await?? {
+        let mut buffer = Vec::new();
+        let mut aborted = None;
+        for segment in segments {
+            themer.input(&segment);
+            let output = themer.theme_segment(&segment, &mut buffer);
+            aborted = aborted.or(output.abort);
+        }
+        emit(&mut writer, &themer, &buffer, aborted, &abort).await?;
+    }
+    finish_stream(&mut writer, &mut themer).await?;
+    Ok(themer.dropped())
+}
+
+/// 输出流结束时写出编码器剩余的内容, 并按任何刷新策略都刷新缓冲区
+async fn finish_stream<W: tokio::io::AsyncWriteExt + Unpin>(writer: &mut Output<W>, themer: &mut LineThemer<'_>) -> Result<()> {
+    let mut buffer = Vec::new();
+    themer.finish(&mut buffer);
+    themer.log_themed(&buffer);
+    if let Some(stats) = &themer.options.stats {
+        let lines = themer.lines + usize::from(themer.unterminated);
+        stats.merge(themer.stream, lines, themer.state.take_stats());
+    }
+    writer.write(&buffer).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// --output-format jsonl 中的一条记录, 被 drop 规则丢弃的行 themed 为 null
+#[derive(serde::Serialize)]
+struct Record<'a> {
+    stream: Stream,
+    raw: &'a str,
+    themed: Option<&'a str>,
+    rules: &'a [String],
+    /// Unix 时间 (秒)
+    ts: f64,
+}
+
+/// 没有新的行时, 等待 multiline 规则匹配的行默认最多保留的时间
+const DEFAULT_MULTILINE_HOLD: Duration = Duration::from_millis(200);
+
+/// --require-match 的条件不满足时的退出码
+const REQUIRE_MATCH_FAILED: i32 = 3;
+
+/// 没有读到换行时, 默认等待多久后先输出已经读到的部分内容
+const DEFAULT_IDLE_FLUSH: Duration = Duration::from_millis(50);
+
+/// 两个输出流共用, 过长的行只警告一次
+static LONG_LINE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// 单个输出流中对每一行应用规则和输出前处理的状态
+struct LineThemer<'a> {
+    command: &'a CommandContext,
+    rules: SharedRules,
+    stream: Stream,
+    options: &'a RunOptions,
+    state: ReplaceState,
+    rainbow: Option<Rainbow>,
+    encoder: Option<OutputEncoder>,
+    window: Window,
+    /// 下一次写出位于行首, 而不是接续尚未结束的一行
+    at_line_start: bool,
+    /// 被 drop 规则丢弃的行数
+    dropped: usize,
+    /// 读到的行数, 最后读到的一段没有分隔符时 unterminated 为 true, 结束时也算作一行
+    lines: usize,
+    unterminated: bool,
+}
+
+impl<'a> LineThemer<'a> {
+    fn new(command: &'a CommandContext, rules: SharedRules, stream: Stream, options: &'a RunOptions) -> Self {
+        // 两个输出流使用不同的随机序列, 指定种子时各自可以复现
+        let seed = options.seed.map(|seed| match stream {
+            Stream::Stdout => seed,
+            Stream::Stderr => seed.wrapping_add(1),
+        });
+        let counters = options.counters.clone().unwrap_or_default();
+        let color = options.color.enabled(stream);
+        let state = ReplaceState::new(seed, counters, color)
+            .with_ansi_aware(options.ansi_aware)
+            .with_mode(options.mode)
+            .with_trace(options.trace.is_some())
+            .with_actions(!options.no_actions);
+        Self {
+            command,
+            rules,
+            stream,
+            options,
+            state,
+            rainbow: options.rainbow.clone().filter(|_| color),
+            encoder: options.output_encoding.as_ref().map(OutputEncoding::encoder),
+            window: Window::default(),
+            at_line_start: true,
+            dropped: 0,
+            lines: 0,
+            unterminated: false,
+        }
+    }
+
+    /// 处理按行读取的一行: 有 multiline 规则时先经过滑动窗口, 离开窗口的行再应用单行规则
+    ///
+    /// 一段时间内没有换行时先读到的部分内容不进入窗口: 先输出窗口中的行, 再单独应用单行规则;
+    /// 同一行其余的内容之后作为下一段读到, 接续在后面输出, 不再写出时间戳
+    fn theme_line(&mut self, segment: Segment, dst: &mut Vec<u8>) -> Option<Abort> {
+        if segment.partial {
+            let abort = self.flush_window(dst);
+            return abort.or(self.theme_segment(&segment, dst).abort);
+        }
+        let rules = self.rules.load(self.stream);
+        let mut released = Vec::new();
+        let mut abort = if rules.multiline.is_empty() || segment.oversized {
+            // 过长的行不进入窗口, 先输出窗口中的行以保持顺序
+            let abort = self.window.flush(&rules.multiline, self.command, &mut self.state, &mut released);
+            released.push(segment);
+            abort
+        } else {
+            self.window.push(segment, &rules.multiline, self.command, &mut self.state, &mut released)
+        };
+        for segment in &released {
+            abort = abort.or(self.theme_segment(segment, dst).abort);
+        }
+        abort
+    }
+
+    /// 窗口中是否有等待匹配的行
+    fn holding(&self) -> bool {
+        !self.window.is_empty()
+    }
+
+    /// 不再等待后面的行, 输出窗口中所有的行
+    fn flush_window(&mut self, dst: &mut Vec<u8>) -> Option<Abort> {
+        let rules = self.rules.load(self.stream);
+        let mut released = Vec::new();
+        let mut abort = self.window.flush(&rules.multiline, self.command, &mut self.state, &mut released);
+        for segment in &released {
+            abort = abort.or(self.theme_segment(segment, dst).abort);
+        }
+        abort
+    }
+
+    /// 读到的一段原始输出: 统计行数, 并写入 --log-raw 指定的日志
+    fn input(&mut self, segment: &Segment) {
+        self.unterminated = segment.terminator == Terminator::None;
+        if !self.unterminated {
+            self.lines += 1;
+        }
+        if let Some(log) = &self.options.log_raw {
+            log.write(self.stream, &[&segment.text[..], segment.terminator.as_bytes()].concat());
+        }
+    }
+
+    /// 把处理后的输出写入 --log-themed 指定的日志和 --record 指定的录像
+    fn log_themed(&self, output: &[u8]) {
+        if let Some(log) = &self.options.log_themed {
+            log.write(self.stream, output);
+        }
+        if let Some(recorder) = &self.options.record {
+            recorder.output(output);
+        }
+    }
+
+    fn dropped(&self) -> usize {
+        self.dropped + self.window.dropped
+    }
+
+    /// paragraph 模式: 一段 (以空行分隔的几行) 以 `\n` 连接后应用规则, 没有规则改变这一段时原样写出
+    fn theme_paragraph(&mut self, lines: &[Segment], dst: &mut Vec<u8>) -> Option<Abort> {
+        let last = lines.last()?;
+        let texts: Vec<_> = lines.iter().map(|line| String::from_utf8_lossy(&line.text)).collect();
+        let block = texts.join("\n");
+        let output = self.theme(&block);
+        if self.options.jsonl {
+            self.write_record(&block, &output, dst);
+        } else if output.is_unchanged(&block) {
+            for line in lines {
+                self.write_original(line, dst);
+            }
+        } else {
+            if output.line.is_none() {
+                self.dropped += lines.len() - 1;
+            }
+            // 整段沿用第一行的换行方式
+            let terminator = match (lines[0].terminator, last.terminator) {
+                (Terminator::CrLf, Terminator::Lf) => Terminator::CrLf,
+                (_, terminator) => terminator,
+            };
+            self.write_output(&output, terminator, dst);
+        }
+        output.abort
+    }
+
+    /// 按原样写出一段输出, 有效的 UTF-8 按输出编码转换, 其余字节原样写出
+    fn write_original(&mut self, segment: &Segment, dst: &mut Vec<u8>) {
+        if self.options.jsonl {
+            let text = String::from_utf8_lossy(&segment.text);
+            let output = LineOutput { line: Some(text.to_string()), ..Default::default() };
+            return self.write_record(&text, &output, dst);
+        }
+        self.begin_line(dst);
+        match std::str::from_utf8(&segment.text) {
+            Ok(text) => self.write(text, dst),
+            Err(_) => dst.extend_from_slice(&segment.text),
+        }
+        self.end_line(segment.terminator.as_str(), dst);
+    }
+
+    /// 处理一段输出并写入 dst
+    ///
+    /// 不是有效 UTF-8 的内容 (如 GBK 或个别无效字节) 转换为替换字符后再匹配规则,
+    /// 没有规则改变这一行时原样写出原来的字节
+    fn theme_segment(&mut self, segment: &Segment, dst: &mut Vec<u8>) -> LineOutput {
+        if segment.oversized {
+            self.pass_through(segment, dst);
+            return LineOutput::default();
+        }
+        let text = String::from_utf8_lossy(&segment.text);
+        let output = self.theme(&text);
+        if self.options.jsonl {
+            self.write_record(&text, &output, dst);
+        } else if matches!(text, Cow::Owned(_)) && output.is_unchanged(&text) {
+            self.begin_line(dst);
+            dst.extend_from_slice(&segment.text);
+            self.end_line(segment.terminator.as_str(), dst);
+        } else {
+            self.write_output(&output, segment.terminator, dst);
+        }
+        output
+    }
+
+    /// 过长的行不应用规则, 按原样 (或只转换编码) 写出
+    fn pass_through(&mut self, segment: &Segment, dst: &mut Vec<u8>) {
+        if !LONG_LINE_WARNED.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "警告: 输出中有超过 {} 字节的行, 这些行不应用规则直接输出 (可用 --max-line-length 调整)",
+                self.options.max_line_length
+            );
+        }
+        if self.options.jsonl {
+            let text = String::from_utf8_lossy(&segment.text);
+            let output = LineOutput { line: Some(text.to_string()), ..Default::default() };
+            return self.write_record(&text, &output, dst);
+        }
+        self.begin_line(dst);
+        if self.encoder.is_some() {
+            self.write(&String::from_utf8_lossy(&segment.text), dst);
+        } else {
+            dst.extend_from_slice(&segment.text);
+        }
+        self.end_line(segment.terminator.as_str(), dst);
+    }
+
+    /// 写出一行的处理结果, 最后一行使用原来的分隔符 (没有分隔符时不补上换行),
+    /// 插入的行与原来的行使用相同的换行方式 (`\r\n` 或 `\n`)
+    fn write_output(&mut self, output: &LineOutput, terminator: Terminator, dst: &mut Vec<u8>) {
+        let line_break = if terminator == Terminator::CrLf { "\r\n" } else { "\n" };
+        let lines: Vec<_> = output.lines().flat_map(|line| line.split('\n')).collect();
+        for (index, line) in lines.iter().enumerate() {
+            self.begin_line(dst);
+            self.write(line, dst);
+            self.end_line(if index + 1 == lines.len() { terminator.as_str() } else { line_break }, dst);
+        }
+    }
+
+    /// --output-format jsonl: 每行 (或每段) 输出一条 JSON 记录, 代替处理后的文本
+    fn write_record(&mut self, raw: &str, output: &LineOutput, dst: &mut Vec<u8>) {
+        let themed = output.line.as_ref().map(|_| output.lines().collect::<Vec<_>>().join("\n"));
+        let record = Record {
+            stream: self.stream,
+            raw,
+            themed: themed.as_deref(),
+            rules: &output.rules,
+            ts: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+        };
+        let mut line = serde_json::to_string(&record).expect("记录总能序列化为 JSON");
+        line.push('\n');
+        self.write(&line, dst);
+    }
+
+    /// 在行首写出时间戳; raw 模式下接续尚未结束的一行时不再写出, 避免时间戳重复
+    fn begin_line(&mut self, dst: &mut Vec<u8>) {
+        if self.at_line_start
+            && let Some(timestamps) = &self.options.timestamps
+        {
+            self.write(&timestamps.prefix(), dst);
+        }
+    }
+
+    /// 写出分隔符, 没有分隔符时下一段接续这一行
+    fn end_line(&mut self, terminator: &str, dst: &mut Vec<u8>) {
+        self.write(terminator, dst);
+        self.at_line_start = !terminator.is_empty();
+    }
+
+    /// 按输出编码写入文本
+    fn write(&mut self, text: &str, dst: &mut Vec<u8>) {
+        match &mut self.encoder {
+            Some(encoder) => encoder.encode(text, dst),
+            None => dst.extend_from_slice(text.as_bytes()),
+        }
+    }
+
+    /// 输出流结束时需要写出的内容
+    fn finish(&mut self, dst: &mut Vec<u8>) {
+        if let Some(encoder) = &mut self.encoder {
+            encoder.finish(dst);
+        }
     }
 
-    let command_name = PathBuf::from(&command[0])
+    /// 应用规则, 并对得到的每一行去掉转义序列、规范化、展开 emoji 和加上彩虹
+    fn theme(&mut self, text: &str) -> LineOutput {
+        let options = self.options;
+        let line = if options.strip_ansi { style::strip_ansi(text) } else { Cow::Borrowed(text) };
+        let line = options.normalize.apply(&line);
+        // 只跟踪单行规则, 丢弃 multiline 规则在窗口中尝试匹配时记录的处理过程
+        self.state.take_trace();
+        let mut output = apply_replacements(&line, self.command, &self.rules.load(self.stream).lines, &mut self.state);
+        if let Some(tracer) = &options.trace
+            && tracer.admit()
+        {
+            let excluded = match self.stream {
+                Stream::Stdout => Streams::Stderr,
+                Stream::Stderr => Streams::Stdout,
+            };
+            let all = self.rules.all();
+            let other_stream = all.iter().filter(|rule| rule.streams == excluded).map(|rule| rule.label());
+            tracer.line(self.stream, text, &self.state.take_trace(), other_stream);
+        }
+        if output.line.is_none() {
+            self.dropped += 1;
+        }
+        let lines = output.before.iter_mut().chain(&mut output.line).chain(&mut output.after);
+        for processed in lines {
+            let stripped = if options.strip_ansi { style::strip_ansi(processed) } else { Cow::Borrowed(processed.as_str()) };
+            let normalized = options.normalize.apply(&stripped);
+            let expanded = if options.emoji { emoji::expand(&normalized) } else { Cow::Borrowed(normalized.as_ref()) };
+            // paragraph 模式下一段包含多行, 彩虹按行计算
+            let painted = match &mut self.rainbow {
+                Some(rainbow) => Cow::Owned(expanded.split('\n').map(|line| rainbow.paint(line)).collect::<Vec<_>>().join("\n")),
+                None => expanded,
+            };
+            if let Cow::Owned(painted) = painted {
+                *processed = painted;
+            }
+        }
+        output
+    }
+}
+
+/// 按主题顺序合并所有规则并应用命令行筛选条件
+fn collect_rules(themes: Vec<Theme>, filters: &RuleFilters) -> Result<Vec<ReplacementRule>> {
+    let mut rules = filters.apply(themes.into_iter().flat_map(|theme| theme.rules).collect())?;
+    sort_by_priority(&mut rules);
+    Ok(rules)
+}
+
+/// 获取命令的文件名部分, 用于匹配 filter_commands
+fn command_name(program: &OsString) -> String {
+    PathBuf::from(program)
         .file_name()
         .and_then(|n| n.to_str())
         .unwrap_or("unknown")
-        .to_string();
+        .to_string()
+}
+
+/// 未通过 --apply 指定主题时使用 CLITHEME_THEME 环境变量, 都没有时报错退出
+fn default_theme_paths() -> Vec<PathBuf> {
+    match std::env::var_os("CLITHEME_THEME").filter(|v| !v.is_empty()) {
+        Some(theme) => vec![PathBuf::from(theme)],
+        None => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "必须通过 --apply 或 CLITHEME_THEME 环境变量指定主题",
+            )
+            .exit(),
+    }
+}
+
+/// 命令行参数优先, 其次是 CLITHEME_LOCALE 和系统的语言环境, 返回语言环境及其来源
+fn resolve_locale(locale: Option<String>) -> (String, &'static str) {
+    match locale {
+        Some(locale) => (locale, "--locale"),
+        None => match std::env::var("CLITHEME_LOCALE").ok().filter(|v| !v.is_empty()) {
+            Some(locale) => (locale, "CLITHEME_LOCALE"),
+            None => detect_locale().unwrap_or_else(|| ("default".to_string(), "")),
+        },
+    }
+}
 
+/// 与 gettext 相同, 依次从 LC_ALL、LC_MESSAGES、LANG 推断语言环境, 返回语言环境及其来源
+fn detect_locale() -> Option<(String, &'static str)> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"].into_iter().find_map(|name| {
+        let value = std::env::var(name).ok().filter(|v| !v.is_empty())?;
+        // zh_CN.UTF-8@pinyin -> zh_CN
+        let locale = value.split(['.', '@']).next().unwrap_or_default();
+        match locale {
+            "" | "C" | "POSIX" => Some(("default".to_string(), name)),
+            locale => Some((locale.to_string(), name)),
+        }
+    })
+}
+
+/// 将命令参数以空格连接, 用于匹配 args_pattern
+fn join_args(args: &[OsString]) -> String {
+    args.iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 需要转发标准输入的交互式命令
+fn is_interactive_command(command_name: &str) -> bool {
     let interactive_commands = ["python", "python3", "ipython", "bash", "sh", "cmd", "zsh"];
-    let is_interactive = interactive_commands
+    interactive_commands
         .iter()
-        .any(|&cmd| cmd == command_name.to_lowercase());
+        .any(|&cmd| cmd == command_name.to_lowercase())
+}
 
-    let mut cmd = AsyncCommand::new(&command[0]);
-    cmd.args(&command[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true);
+/// 命令输出的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// 处理后的文本
+    Text,
+    /// 每行一条 JSON 记录
+    Jsonl,
+}
 
-    let mut child = if is_interactive {
+/// 下游关闭管道后对子进程的处理
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum BrokenPipe {
+    /// 继续读取并丢弃子进程的输出, 等待它自行结束
+    #[default]
+    Drain,
+    /// 终止子进程
+    Terminate,
+}
+
+/// 颜色输出策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    /// 对应的输出流是终端时输出颜色
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// 未通过 --color 指定时, 设置了非空的 NO_COLOR 则不输出颜色
+    fn resolve(choice: Option<Self>) -> Self {
+        choice.unwrap_or_else(|| match std::env::var_os("NO_COLOR") {
+            Some(value) if !value.is_empty() => Self::Never,
+            _ => Self::Auto,
+        })
+    }
+
+    /// 标准输出和标准错误分别判断, 其中一个常常被重定向
+    fn enabled(self, stream: Stream) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => match stream {
+                Stream::Stdout => std::io::stdout().is_terminal(),
+                Stream::Stderr => std::io::stderr().is_terminal(),
+            },
+        }
+    }
+}
+
+/// 运行命令时影响输出处理的选项
+#[derive(Debug, Default)]
+struct RunOptions {
+    /// 随机替换使用的种子
+    seed: Option<u64>,
+    /// 所有输出流共用的计数器, 未设置时每个流各自计数
+    counters: Option<Counters>,
+    /// 颜色标签和 style 规则是否输出颜色
+    color: ColorChoice,
+    /// 规则的应用方式, 由主题的 "mode" 决定
+    mode: MatchMode,
+    /// 在应用规则前和输出前去掉转义序列
+    strip_ansi: bool,
+    /// 所有规则都忽略转义序列匹配
+    ansi_aware: bool,
+    /// 强制对所有输出行进行的 Unicode 规范化
+    normalize: Normalization,
+    /// 展开输出中的 emoji 短代码
+    emoji: bool,
+    /// 最后给输出加上彩虹渐变
+    rainbow: Option<Rainbow>,
+    /// 按块读取并立即输出, 以换行或回车分隔应用规则
+    raw: bool,
+    /// 按行处理时一行的最大字节数
+    max_line_length: usize,
+    /// 何时把输出刷新到终端
+    flush: FlushPolicy,
+    /// 下游关闭管道后对子进程的处理
+    on_broken_pipe: BrokenPipe,
+    /// terminate 时由处理输出的任务通知 execute_command 终止子进程
+    downstream_closed: Notify,
+    /// 两个输出流按读到的顺序处理后都写到标准输出
+    merge_output: bool,
+    /// 不处理的输出流, 按字节原样复制
+    no_theme_stdout: bool,
+    no_theme_stderr: bool,
+    /// 以空行分隔的每一段整体应用规则, 值为一段的最大字节数
+    paragraph_mode: Option<usize>,
+    /// 等待 multiline 规则匹配的行最多保留的时间
+    multiline_hold: Duration,
+    /// 没有读到换行时, 等待多久后先输出已经读到的部分内容
+    idle_flush: Option<Duration>,
+    /// 尚未结束的一行与之匹配时立即输出
+    prompt_patterns: Arc<[Regex]>,
+    /// 标准输出和标准错误的编码, 未指定时按 UTF-8 处理
+    input_encoding: Option<&'static Encoding>,
+    stderr_input_encoding: Option<&'static Encoding>,
+    /// 输出的编码, 未指定时输出 UTF-8
+    output_encoding: Option<OutputEncoding>,
+    /// 加在每行输出开头的时间戳
+    timestamps: Option<Timestamps>,
+    /// 以 JSON 记录代替处理后的文本输出
+    jsonl: bool,
+    /// 原始输出和处理后输出的日志文件
+    log_raw: Option<LogFile>,
+    log_themed: Option<LogFile>,
+    /// 处理后输出的 asciinema 录像
+    record: Option<Recorder>,
+    /// 结束时报告处理统计
+    verbose: bool,
+    /// --stats 和 --stats-json: 每条规则生效的行数和替换次数, 以及各输出流的行数
+    stats: Option<Stats>,
+    /// --trace: 逐行输出规则的处理过程
+    trace: Option<Tracer>,
+    /// --require-match: 要求任一规则 (或指定的规则) 生效
+    require_match: Option<Option<String>>,
+    /// 不执行 on_match、notify 等动作, 用于 bench
+    no_actions: bool,
+    /// 在伪终端中运行子进程
+    pty: bool,
+    /// 命令运行前后显示的主题横幅
+    headers: Vec<Banner>,
+    footers: Vec<Banner>,
+}
+
+impl RunOptions {
+    /// 下游关闭后, 按 --on-broken-pipe terminate 通知 execute_command 终止子进程
+    fn check_closed<W: tokio::io::AsyncWrite + Unpin>(&self, writer: &Output<W>) {
+        if writer.is_closed() && self.on_broken_pipe == BrokenPipe::Terminate {
+            self.downstream_closed.notify_one();
+        }
+    }
+
+    /// 是否对该输出流应用规则, 否则原样复制
+    fn themes(&self, stream: Stream) -> bool {
+        match stream {
+            Stream::Stdout => !self.no_theme_stdout,
+            Stream::Stderr => !self.no_theme_stderr,
+        }
+    }
+}
+
+/// 执行命令并处理输出
+async fn execute_command(
+    command: &[OsString],
+    rules: SharedRules,
+    options: RunOptions,
+) -> Result<i32> {
+    if command.is_empty() {
+        return Err(anyhow!("必须指定要执行的命令"));
+    }
+
+    let command_name = command_name(&command[0]);
+    let is_interactive = is_interactive_command(&command_name);
+
+    let mut cmd = if is_interactive {
         // 交互式命令需要shell
         let full_cmd = command
             .iter()
             .map(|s| s.to_string_lossy().to_string())
             .collect::<Vec<_>>()
             .join(" ");
-        AsyncCommand::new("sh")
-            .arg("-c")
-            .arg(full_cmd)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .kill_on_drop(true)
-            .spawn()?
+        let mut cmd = AsyncCommand::new("sh");
+        cmd.arg("-c").arg(full_cmd);
+        cmd
     } else {
-        cmd.spawn()?
+        let mut cmd = AsyncCommand::new(&command[0]);
+        cmd.args(&command[1..]);
+        cmd
     };
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // 子进程使用单独的进程组, 以便连同它启动的进程一起终止和转发信号; 只有在终端的前台运行且
+    // 没有 abort 规则时例外, 子进程留在前台进程组中才能读写终端;
+    // 伪终端中的子进程通过 setsid 成为新会话和进程组的首进程
+    #[cfg(unix)]
+    let process_group = rules.has_abort() || options.pty || !signal::terminal_foreground();
+    #[cfg(not(unix))]
+    let process_group = rules.has_abort();
+    #[cfg(unix)]
+    if process_group && !options.pty {
+        cmd.process_group(0);
+    }
+    #[cfg(unix)]
+    let pty = options.pty.then(pty::Pty::open).transpose()?;
+    #[cfg(unix)]
+    if let Some(pty) = &pty {
+        pty.attach(&mut cmd)?;
+        pty.watch_resize()?;
+    }
+    let started = Instant::now();
+    for header in &options.headers {
+        let color = options.color.enabled(header.stream());
+        let printed = header.print(&[("command", command_name.clone())], color, options.output_encoding.as_ref());
+        if let Some(recorder) = &options.record {
+            recorder.output(&printed);
+        }
+    }
+    #[cfg(unix)]
+    let raw_mode = options.pty.then(pty::RawMode::enable).flatten();
+    let mut signals = signal::Forwarded::new()?;
+    let mut child = cmd.spawn()?;
+    drop(cmd);
+    #[cfg(unix)]
+    let (terminal_reader, terminal_writer) = pty.map(pty::Pty::into_master).transpose()?.unzip();
+    #[cfg(not(unix))]
+    let (terminal_reader, terminal_writer): (Option<tokio::io::Empty>, Option<tokio::io::Sink>) = (None, None);
 
-    let stdout = child.stdout.take().expect("无法获取子进程stdout");
-    let stderr = child.stderr.take().expect("无法获取子进程stderr");
     let stdin = child.stdin.take();
 
     // 共享规则引用
-    let rules_arc = Arc::new(rules.to_vec());
-    let locale_arc = locale.to_string();
-    let command_name_arc = command_name;
-
-    // 处理标准输出
-    let stdout_handle = {
-        let rules = Arc::clone(&rules_arc);
-        let locale = locale_arc.clone();
-        let command_name = command_name_arc.clone();
-        task::spawn(async move {
-            let reader = AsyncBufReader::new(stdout);
-            let writer = tokio::io::stdout();
-            process_stream(
-                reader,
-                writer,
-                &command_name,
-                rules,
-                &locale,
-            )
-            .await
-        })
-    };
+    let rules_arc = rules;
+    let context_arc = Arc::new(CommandContext::new(&command_name, join_args(&command[1..])));
+    let options_arc = Arc::new(options);
+    let (abort_tx, mut abort_rx) = mpsc::unbounded_channel();
 
-    // 处理标准错误
-    let stderr_handle = {
-        let rules = Arc::clone(&rules_arc);
-        let locale = locale_arc.clone();
-        let command_name = command_name_arc.clone();
-        task::spawn(async move {
-            let reader = AsyncBufReader::new(stderr);
-            let writer = tokio::io::stderr();
-            process_stream(
-                reader,
-                writer,
-                &command_name,
-                rules,
-                &locale,
-            )
-            .await
-        })
+    let handles = if let Some(reader) = terminal_reader {
+        // 伪终端只有一个输出流, 子进程的标准输出和标准错误都按标准输出处理
+        let rules = rules_arc.clone();
+        let context = context_arc.clone();
+        let options = options_arc.clone();
+        let abort = abort_tx.clone();
+        vec![task::spawn(async move {
+            process_stream(reader, tokio::io::stdout(), &context, rules, Stream::Stdout, &options, abort).await
+        })]
+    } else {
+        let stdout = child.stdout.take().expect("无法获取子进程stdout");
+        let stderr = child.stderr.take().expect("无法获取子进程stderr");
+        if options_arc.merge_output {
+            let rules = rules_arc.clone();
+            let context = context_arc.clone();
+            let options = options_arc.clone();
+            let abort = abort_tx.clone();
+            vec![task::spawn(async move {
+                let stdout = AsyncBufReader::new(stdout);
+                let stderr = AsyncBufReader::new(stderr);
+                process_merged(stdout, stderr, tokio::io::stdout(), &context, rules, &options, abort).await
+            })]
+        } else {
+            // 处理标准输出
+            let stdout_handle = {
+                let rules = rules_arc.clone();
+                let context = context_arc.clone();
+                let options = options_arc.clone();
+                let abort = abort_tx.clone();
+                task::spawn(async move {
+                    let reader = AsyncBufReader::new(stdout);
+                    let writer = tokio::io::stdout();
+                    process_stream(
+                        reader,
+                        writer,
+                        &context,
+                        rules,
+                        Stream::Stdout,
+                        &options,
+                        abort,
+                    )
+                    .await
+                })
+            };
+
+            // 处理标准错误
+            let stderr_handle = {
+                let rules = rules_arc.clone();
+                let context = context_arc.clone();
+                let options = options_arc.clone();
+                let abort = abort_tx.clone();
+                task::spawn(async move {
+                    let reader = AsyncBufReader::new(stderr);
+                    // jsonl 格式下两个输出流的记录都写到标准输出, 用 stream 字段区分
+                    let writer: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = if options.jsonl {
+                        Box::new(tokio::io::stdout())
+                    } else {
+                        Box::new(tokio::io::stderr())
+                    };
+                    process_stream(
+                        reader,
+                        writer,
+                        &context,
+                        rules,
+                        Stream::Stderr,
+                        &options,
+                        abort,
+                    )
+                    .await
+                })
+            };
+            vec![stdout_handle, stderr_handle]
+        }
     };
 
     // 处理交互式输入
-    let stdin_handle = if is_interactive && stdin.is_some() {
-        let mut child_stdin = stdin.unwrap();
+    let stdin_handle = if let Some(mut terminal) = terminal_writer {
+        // 按键原样转发, 回显和 Ctrl-C 等控制字符由伪终端处理
+        Some(task::spawn(async move {
+            let mut stdin = tokio::io::stdin();
+            let mut buf = [0; 1024];
+            loop {
+                let n = stdin.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                terminal.write_all(&buf[..n]).await?;
+                terminal.flush().await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        }))
+    } else if let (true, Some(mut child_stdin)) = (is_interactive, stdin) {
         let stdin = tokio::io::stdin();
         Some(task::spawn(async move {
             let mut reader = AsyncBufReader::new(stdin).lines();
@@ -257,23 +1575,103 @@ async fn execute_command(
         None
     };
 
-    // 等待子进程结束
-    let status = child.wait().await?;
+    // 等待子进程结束, abort 规则匹配时提前终止; 两个输出流结束后通道关闭, 只等待子进程;
+    // 收到 Ctrl-C 等信号时转发给子进程, 继续处理输出直到子进程自行退出
+    drop(abort_tx);
+    let (status, aborted) = loop {
+        tokio::select! {
+            status = child.wait() => break (status?, None),
+            Some(matched) = abort_rx.recv() => {
+                eprintln!("规则 {} 匹配, 终止命令", matched.rule);
+                terminate(&mut child, process_group);
+                break (child.wait().await?, Some(matched.exit_code));
+            }
+            _ = options_arc.downstream_closed.notified() => {
+                terminate(&mut child, process_group);
+                break (child.wait().await?, None);
+            }
+            signal = signals.recv() => signal::forward(&child, process_group, signal),
+        }
+    };
 
     // 等待所有任务完成
-    let _ = stdout_handle.await;
-    let _ = stderr_handle.await;
+    let mut dropped = 0;
+    for handle in handles {
+        dropped += handle.await.ok().and_then(Result::ok).unwrap_or(0);
+    }
+    #[cfg(unix)]
+    drop(raw_mode);
+    if options_arc.verbose && dropped > 0 {
+        eprintln!("已丢弃 {} 行输出", dropped);
+    }
+    if let Some(stats) = &options_arc.stats {
+        let themed = [options_arc.themes(Stream::Stdout), options_arc.themes(Stream::Stderr)];
+        stats.report(&rules_arc.all(), &context_arc, themed, command, status, started.elapsed());
+    }
     if let Some(handle) = stdin_handle {
+        // 伪终端模式下子进程退出后不再等待按键
+        if options_arc.pty {
+            handle.abort();
+        }
         let _ = handle.await;
     }
 
-    Ok(status.code().unwrap_or(1))
+    let mut code = aborted.unwrap_or_else(|| signal::exit_code(status));
+    if let (Some(required), Some(stats)) = (&options_arc.require_match, &options_arc.stats)
+        && !stats.matched(&rules_arc.all(), required.as_deref())
+    {
+        match required {
+            Some(id) => eprintln!("--require-match: 规则 {} 没有生效", id),
+            None => eprintln!("--require-match: 没有任何规则生效"),
+        }
+        // 命令本身失败时优先报告命令的退出码
+        if code == 0 {
+            code = REQUIRE_MATCH_FAILED;
+        }
+    }
+    let values = [
+        ("command", command_name),
+        ("elapsed", format_elapsed(started.elapsed())),
+        ("exit_code", code.to_string()),
+    ];
+    for footer in &options_arc.footers {
+        let color = options_arc.color.enabled(footer.stream());
+        let printed = footer.print(&values, color, options_arc.output_encoding.as_ref());
+        if let Some(recorder) = &options_arc.record {
+            recorder.output(&printed);
+        }
+    }
+    Ok(code)
+}
+
+/// 强制终止子进程, 子进程有单独的进程组时终止整个进程组
+fn terminate(child: &mut Child, process_group: bool) {
+    #[cfg(unix)]
+    if process_group && let Some(pid) = child.id() {
+        // SAFETY: killpg 只向进程组发送信号, 不涉及内存访问
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+        return;
+    }
+    // Windows 上只终止子进程本身
+    #[cfg(not(unix))]
+    let _ = process_group;
+    let _ = child.start_kill();
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    if let Some(subcommand) = args.subcommand {
+        match run_subcommand(subcommand).await {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                eprintln!("错误: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // 处理命令参数
     let command = if let Some(pos) = args.command.iter().position(|arg| arg == "--") {
         args.command.split_at(pos + 1).1.to_vec()
@@ -285,25 +1683,234 @@ async fn main() -> Result<()> {
         return Err(anyhow!("必须指定要执行的命令"));
     }
 
+    // 命令行参数优先, 其次是环境变量
+    let theme_paths = if !args.apply.is_empty() { args.apply } else { default_theme_paths() };
+    let (locale, locale_source) = resolve_locale(args.locale);
+    if args.verbose {
+        match locale_source {
+            "" => eprintln!("语言环境: {} (未设置 LC_ALL/LC_MESSAGES/LANG)", locale),
+            source => eprintln!("语言环境: {} (来自 {})", locale, source),
+        }
+    }
+
+    #[cfg(not(unix))]
+    if args.pty {
+        eprintln!("错误: --pty 仅支持 Unix 系统");
+        std::process::exit(1);
+    }
+
+    // 主题从标准输入读取后, 标准输入已经读尽, 无法再转发给交互式命令
+    let theme_from_stdin = theme_paths.iter().any(|p| is_stdin_path(p));
+    if theme_from_stdin && (args.pty || is_interactive_command(&command_name(&command[0]))) {
+        return Err(anyhow!(
+            "使用 -apply - 从标准输入读取主题时, 标准输入不会转发给子进程, 无法运行交互式命令 {}",
+            command[0].to_string_lossy()
+        ));
+    }
+
     // 加载配置
-    let rules = match load_config(&args.apply) {
+    let options = args.load.options();
+    let themes = match load_themes(&theme_paths, &options) {
+        Ok(themes) => themes,
+        Err(e) => {
+            eprintln!("配置错误: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.verbose {
+        for theme in &themes {
+            eprintln!("主题: {}", theme.metadata);
+            eprintln!("已加载 {} 条规则", theme.rules.len());
+        }
+    }
+    if args.warn_conflicts {
+        let rules: Vec<_> = themes.iter().flat_map(|theme| &theme.rules).collect();
+        for conflict in conflict::find(&rules, MatchMode::of(&themes)) {
+            eprintln!("警告: {}", conflict);
+        }
+    }
+    let sources: Vec<PathBuf> = themes.iter().flat_map(|t| t.sources.clone()).collect();
+    let filters = RuleFilters {
+        only: args.only,
+        skip: args.skip,
+        enable_groups: args.enable_group,
+        disable_groups: args.disable_group,
+        locale,
+        fallback: !args.no_fallback,
+        now: args.now,
+        verbose: args.verbose,
+    };
+    let strip_ansi = args.strip_ansi || themes.iter().any(|theme| theme.metadata.strip_ansi == Some(true));
+    let mode = MatchMode::of(&themes);
+    let prompt_patterns = prompt_patterns(&themes);
+    // 主题中的编码已在加载时检查过
+    let input_encoding = args.input_encoding.or_else(|| {
+        let label = themes.iter().find_map(|theme| theme.metadata.input_encoding.as_deref())?;
+        parse_encoding(label).ok()
+    });
+    let output_encoding = match args.output_encoding.filter(|&encoding| encoding != encoding_rs::UTF_8) {
+        Some(encoding) => match OutputEncoding::new(encoding, &args.output_fallback) {
+            Ok(encoding) => Some(encoding),
+            Err(e) => Args::command().error(ErrorKind::ValueValidation, format!("{:#}", e)).exit(),
+        },
+        None => None,
+    };
+    let create_log = |path: &Option<String>| match path.as_deref().map(LogFile::create).transpose() {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("错误: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let log_raw = create_log(&args.log_raw);
+    let log_themed = create_log(&args.log_themed);
+    let title = join_args(&command);
+    let record = match args.record.as_deref().map(|path| Recorder::create(path, &title)).transpose() {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("错误: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let jsonl = args.output_format == OutputFormat::Jsonl;
+    if jsonl && is_interactive_command(&command_name(&command[0])) {
+        Args::command()
+            .error(ErrorKind::ArgumentConflict, "交互式命令不支持 --output-format jsonl")
+            .exit();
+    }
+    // 横幅会混入 JSON 记录中, jsonl 格式不显示
+    let (headers, footers) = if args.no_banner || jsonl {
+        Default::default()
+    } else {
+        Banner::collect(&themes, &filters.locale, filters.fallback)
+    };
+    let loaded = themes.iter().map(|theme| theme.rules.len()).sum();
+    let stats = (args.stats || args.report_unused || args.stats_json.is_some() || args.require_match.is_some()).then(|| {
+        Stats::new(args.stats, args.stats || args.report_unused, args.stats_json.clone(), &filters.locale, loaded)
+    });
+    let rules = match collect_rules(themes, &filters) {
         Ok(rules) => rules,
         Err(e) => {
-            eprintln!("配置错误: {}", e);
+            eprintln!("配置错误: {:#}", e);
             std::process::exit(1);
         }
     };
+    if let Some(Some(id)) = &args.require_match
+        && !rules.iter().any(|rule| rule.label() == id)
+    {
+        eprintln!("配置错误: --require-match 指定的规则 '{}' 不存在或已被筛选掉", id);
+        std::process::exit(1);
+    }
+    if args.trace && loaded > rules.len() {
+        eprintln!("[trace] {} 条规则被语言环境、分组等条件筛选掉, 不参与处理", loaded - rules.len());
+    }
 
-    // 如果没有替换规则，直接执行命令
-    if rules.is_empty() {
-        let status = Command::new(&command[0])
-            .args(&command[1..])
-            .status()?;
-        std::process::exit(status.code().unwrap_or(1));
+    // 如果没有替换规则也不需要处理输出，直接执行命令
+    let transforms_output = strip_ansi
+        || args.emoji
+        || args.normalize != Normalization::None
+        || args.rainbow
+        || input_encoding.is_some()
+        || args.stderr_input_encoding.is_some()
+        || output_encoding.is_some()
+        || log_raw.is_some()
+        || args.timestamps.is_some()
+        || jsonl
+        || args.merge_output
+        || log_themed.is_some()
+        || record.is_some()
+        || args.stats
+        || args.stats_json.is_some()
+        || args.report_unused
+        || args.trace
+        || args.require_match.is_some()
+        || !headers.is_empty()
+        || !footers.is_empty();
+    if rules.is_empty() && !args.watch && !transforms_output {
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..]);
+        if theme_from_stdin {
+            cmd.stdin(Stdio::null());
+        }
+        let status = cmd.status()?;
+        std::process::exit(signal::exit_code(status));
     }
 
+    let rules = SharedRules::new(rules);
+
+    // 监视主题文件, 重新加载失败时保留旧规则
+    let _watcher = if args.watch {
+        let shared = rules.clone();
+        let reload = move || {
+            let reloaded = load_themes(&theme_paths, &options).and_then(|themes| {
+                let sources: Vec<PathBuf> = themes.iter().flat_map(|t| t.sources.clone()).collect();
+                Ok((collect_rules(themes, &filters)?, sources))
+            });
+            match reloaded {
+                Ok((rules, sources)) => {
+                    shared.store(rules);
+                    eprintln!("已重新加载主题");
+                    Some(sources)
+                }
+                Err(e) => {
+                    eprintln!("警告: 重新加载主题失败, 继续使用旧规则: {:#}", e);
+                    None
+                }
+            }
+        };
+        match watch::watch_sources(&sources, reload) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("警告: 无法监视主题文件: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     // 执行命令
-    match execute_command(&command, &rules, &args.locale).await {
+    terminal::watch_resize();
+    let options = RunOptions {
+        seed: args.seed,
+        counters: args.shared_counters.then(Counters::default),
+        color: ColorChoice::resolve(args.color),
+        mode,
+        strip_ansi,
+        ansi_aware: args.ansi_aware,
+        normalize: args.normalize,
+        emoji: args.emoji,
+        rainbow: args.rainbow.then(|| Rainbow::new(args.rainbow_seed, args.rainbow_freq)),
+        raw: args.raw,
+        max_line_length: args.max_line_length,
+        flush: args.flush,
+        on_broken_pipe: args.on_broken_pipe,
+        downstream_closed: Notify::new(),
+        merge_output: args.merge_output,
+        no_theme_stdout: args.no_theme_stdout,
+        no_theme_stderr: args.no_theme_stderr,
+        paragraph_mode: args.paragraph_mode.then_some(args.max_paragraph_size),
+        multiline_hold: Duration::from_millis(args.multiline_hold),
+        idle_flush: (args.idle_flush > 0).then(|| Duration::from_millis(args.idle_flush)),
+        prompt_patterns,
+        input_encoding,
+        stderr_input_encoding: args.stderr_input_encoding.or(input_encoding),
+        output_encoding,
+        timestamps: args.timestamps,
+        jsonl,
+        log_raw,
+        log_themed,
+        record,
+        verbose: args.verbose,
+        stats,
+        trace: args.trace.then(|| Tracer::new(args.trace_limit)),
+        require_match: args.require_match,
+        no_actions: false,
+        pty: args.pty,
+        headers,
+        footers,
+    };
+    match execute_command(&command, rules, options).await {
         Ok(code) => std::process::exit(code),
         Err(e) => {
             eprintln!("执行错误: {}", e);
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::time::Instant;
+
+/// 输出缓冲区的大小, block 策略下写满时才刷新
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// 何时把处理后的输出刷新到终端
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// 每行处理后立即刷新
+    #[default]
+    Line,
+    /// 缓冲区写满或输出结束时刷新
+    Block,
+    /// 距上次刷新超过间隔时刷新
+    Interval(Duration),
+}
+
+impl FlushPolicy {
+    /// 解析 `line`、`block` 或 `interval:<毫秒>`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "line" => Ok(Self::Line),
+            "block" => Ok(Self::Block),
+            _ => {
+                let ms = value
+                    .strip_prefix("interval:")
+                    .and_then(|ms| ms.parse().ok())
+                    .filter(|&ms| ms > 0)
+                    .ok_or_else(|| anyhow!("应为 line、block 或 interval:<毫秒>: '{}'", value))?;
+                Ok(Self::Interval(Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+/// 按刷新策略缓冲的输出流
+///
+/// 下游 (如管道另一端的 `head`) 关闭后不再写出, 也不报错, 调用方可以继续读取子进程的输出
+pub struct Output<W: AsyncWrite + Unpin> {
+    writer: BufWriter<W>,
+    policy: FlushPolicy,
+    /// interval 策略下有尚未刷新的内容时, 最迟的刷新时间
+    deadline: Option<Instant>,
+    /// 写出时遇到了 BrokenPipe
+    closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> Output<W> {
+    pub fn new(writer: W, policy: FlushPolicy) -> Self {
+        Self {
+            writer: BufWriter::with_capacity(BUFFER_SIZE, writer),
+            policy,
+            deadline: None,
+            closed: false,
+        }
+    }
+
+    /// 下游是否已经关闭
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() || self.closed {
+            return Ok(());
+        }
+        let written = self.writer.write_all(data).await;
+        self.check(written)?;
+        match self.policy {
+            FlushPolicy::Line => self.flush().await,
+            FlushPolicy::Block => Ok(()),
+            FlushPolicy::Interval(interval) => match self.deadline {
+                Some(deadline) if Instant::now() >= deadline => self.flush().await,
+                Some(_) => Ok(()),
+                None => {
+                    self.deadline = Some(Instant::now() + interval);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.deadline = None;
+        if self.closed {
+            return Ok(());
+        }
+        let flushed = self.writer.flush().await;
+        self.check(flushed)
+    }
+
+    /// BrokenPipe 只记录下来, 其他错误照常返回
+    fn check(&mut self, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.closed = true;
+                self.deadline = None;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// 等待 future 完成 (如读取下一行), 等待期间到了 interval 的刷新时间就先刷新,
+    /// 使命令暂停输出时已经处理的内容不会一直留在缓冲区中
+    pub async fn during<F: Future>(&mut self, future: F) -> io::Result<F::Output> {
+        tokio::pin!(future);
+        loop {
+            let Some(deadline) = self.deadline else {
+                return Ok(future.await);
+            };
+            tokio::select! {
+                output = &mut future => return Ok(output),
+                _ = tokio::time::sleep_until(deadline) => self.flush().await?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// 记录写入次数的输出, 每次写入相当于一次 write 系统调用
+    #[derive(Default)]
+    struct Counting {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.data.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// 按策略写出 lines 行, 返回输出、写入次数和耗时
+    async fn run(policy: FlushPolicy, lines: usize) -> (Vec<u8>, usize, Duration) {
+        let start = std::time::Instant::now();
+        let mut output = Output::new(Counting::default(), policy);
+        for i in 0..lines {
+            output.write(format!("line {}\n", i).as_bytes()).await.unwrap();
+        }
+        output.flush().await.unwrap();
+        let elapsed = start.elapsed();
+        let Counting { data, writes } = output.writer.into_inner();
+        (data, writes, elapsed)
+    }
+
+    /// 大量短行时 block 和 interval 把写入合并到缓冲区写满时, 输出与 line 完全相同;
+    /// 用 `cargo test flush_policy_throughput -- --nocapture` 查看各策略的吞吐量
+    #[tokio::test]
+    async fn flush_policy_throughput() {
+        const LINES: usize = 300_000;
+        let (expected, line_writes, elapsed) = run(FlushPolicy::Line, LINES).await;
+        assert_eq!(line_writes, LINES);
+        println!("line:     {:>7} 次写入, {:>10.0} 行/秒", line_writes, LINES as f64 / elapsed.as_secs_f64());
+        for (name, policy) in [("block", FlushPolicy::Block), ("interval", FlushPolicy::Interval(Duration::from_secs(3600)))] {
+            let (data, writes, elapsed) = run(policy, LINES).await;
+            println!("{:<9} {:>7} 次写入, {:>10.0} 行/秒", format!("{}:", name), writes, LINES as f64 / elapsed.as_secs_f64());
+            assert_eq!(data, expected);
+            assert!(writes <= expected.len() / BUFFER_SIZE + 1, "{:?}: {} 次写入", policy, writes);
+        }
+    }
+}
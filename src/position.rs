@@ -0,0 +1,222 @@
+use crate::config::ThemeFormat;
+
+/// 定位规则数组每个元素在源码中的起始行列 (从 1 开始), 用于在错误信息中指出出错的规则;
+/// 无法定位时 (如 TOML) 返回空数组
+pub fn rule_positions(format: ThemeFormat, source: &str) -> Vec<(usize, usize)> {
+    match format {
+        ThemeFormat::Json => json_rule_positions(source),
+        ThemeFormat::Yaml => yaml_rule_positions(source),
+        ThemeFormat::Toml => Vec::new(),
+    }
+}
+
+/// 在 JSON (含 JSON5 注释) 源码中定位规则数组每个元素的起始行列 (从 1 开始)
+fn json_rule_positions(source: &str) -> Vec<(usize, usize)> {
+    let mut positions = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    let (mut line, mut column) = (1, 0);
+    let mut depth = 0usize;
+    // 规则数组所在的深度, 以及是否正在等待下一个元素
+    let mut target_depth = None;
+    let mut expecting = false;
+    let mut last_key = String::new();
+
+    while let Some((_, c)) = chars.next() {
+        if c == '\n' {
+            line += 1;
+            column = 0;
+            continue;
+        }
+        column += 1;
+        if c.is_whitespace() {
+            continue;
+        }
+        // 跳过注释
+        if c == '/' {
+            match chars.peek().map(|&(_, c)| c) {
+                Some('/') => {
+                    while chars.peek().is_some_and(|&(_, c)| c != '\n') {
+                        chars.next();
+                    }
+                    continue;
+                }
+                Some('*') => {
+                    chars.next();
+                    column += 1;
+                    let mut prev = ' ';
+                    for (_, c) in chars.by_ref() {
+                        if c == '\n' {
+                            line += 1;
+                            column = 0;
+                        } else {
+                            column += 1;
+                        }
+                        if prev == '*' && c == '/' {
+                            break;
+                        }
+                        prev = c;
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        if expecting && Some(depth) == target_depth && c != ']' {
+            positions.push((line, column));
+            expecting = false;
+        }
+
+        match c {
+            '"' | '\'' => {
+                let mut text = String::new();
+                let mut escaped = false;
+                for (_, s) in chars.by_ref() {
+                    column += 1;
+                    if escaped {
+                        escaped = false;
+                    } else if s == '\\' {
+                        escaped = true;
+                    } else if s == c {
+                        break;
+                    } else {
+                        text.push(s);
+                    }
+                }
+                if depth == 1 {
+                    last_key = text;
+                }
+            }
+            '[' | '{' => {
+                let root_array = depth == 0 && c == '[';
+                let rules_key = depth == 1 && c == '[' && matches!(last_key.as_str(), "replacements" | "rules");
+                depth += 1;
+                if target_depth.is_none() && (root_array || rules_key) {
+                    target_depth = Some(depth);
+                    expecting = true;
+                }
+            }
+            ']' | '}' => {
+                if Some(depth) == target_depth {
+                    break;
+                }
+                depth = depth.saturating_sub(1);
+            }
+            ',' if Some(depth) == target_depth => expecting = true,
+            c if depth == 1 && (c.is_alphanumeric() || c == '_') => {
+                // JSON5 无引号键名
+                let mut key = c.to_string();
+                while let Some(&(_, n)) = chars.peek() {
+                    if !(n.is_alphanumeric() || n == '_') {
+                        break;
+                    }
+                    key.push(n);
+                    chars.next();
+                    column += 1;
+                }
+                last_key = key;
+            }
+            _ => {}
+        }
+    }
+    positions
+}
+
+/// 在 YAML 源码中定位规则序列每一项的 "-" 所在行列; 只识别块序列 (根序列, 或根字典中
+/// replacements/rules 键下的序列), 流式写法 [...] 无法定位
+fn yaml_rule_positions(source: &str) -> Vec<(usize, usize)> {
+    let mut lines = yaml_lines(source).peekable();
+    match lines.peek() {
+        Some(&(_, 0, content)) if is_yaml_item(content) => yaml_items(lines),
+        _ => ["replacements", "rules"]
+            .iter()
+            .find_map(|key| {
+                let mut lines = yaml_lines(source).skip_while(|&(_, indent, content)| {
+                    !(indent == 0 && yaml_key_value(content, key).is_some())
+                });
+                let (_, _, content) = lines.next()?;
+                // 值写在同一行时是流式序列
+                yaml_key_value(content, key)?.is_empty().then(|| yaml_items(lines.peekable()))
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// 跳过空行、注释和文档标记, 返回 (行号, 缩进, 去掉缩进后的内容)
+fn yaml_lines(source: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    source.lines().enumerate().filter_map(|(number, line)| {
+        let content = line.trim_start_matches(' ');
+        let indent = line.len() - content.len();
+        let marker = indent == 0 && (content.starts_with("---") || content.starts_with("...") || content.starts_with('%'));
+        (!content.is_empty() && !content.starts_with('#') && !marker).then_some((number + 1, indent, content))
+    })
+}
+
+fn is_yaml_item(content: &str) -> bool {
+    content == "-" || content.starts_with("- ")
+}
+
+/// 内容是 key 这个键时返回冒号后的值 (去掉注释), 否则返回 None
+fn yaml_key_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    let rest = [format!("\"{}\"", key), format!("'{}'", key), key.to_string()]
+        .iter()
+        .find_map(|quoted| content.strip_prefix(quoted.as_str()))?;
+    let value = rest.trim_start().strip_prefix(':')?;
+    if !value.is_empty() && !value.starts_with(' ') {
+        return None;
+    }
+    let value = value.trim();
+    Some(if value.starts_with('#') { "" } else { value })
+}
+
+/// 第一行所在缩进的序列中每一项的位置; 缩进更小或同一缩进的非序列项表示序列结束,
+/// 块标量等多行内容总是缩进更深, 不会被当作新的一项
+fn yaml_items<'a>(mut lines: std::iter::Peekable<impl Iterator<Item = (usize, usize, &'a str)>>) -> Vec<(usize, usize)> {
+    let Some(&(_, item_indent, content)) = lines.peek() else {
+        return Vec::new();
+    };
+    if !is_yaml_item(content) {
+        return Vec::new();
+    }
+    let mut positions = Vec::new();
+    for (number, indent, content) in lines {
+        if indent < item_indent || (indent == item_indent && !is_yaml_item(content)) {
+            break;
+        }
+        if indent == item_indent {
+            positions.push((number, indent + 1));
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_rules_under_key() {
+        let source = "# 主题\nname: demo\nreplacements:\n  - pattern: a\n    replacement: |\n      - 不是规则\n\n      text\n  -\n    pattern: b\nother:\n  - 1\n";
+        assert_eq!(rule_positions(ThemeFormat::Yaml, source), vec![(4, 3), (9, 3)]);
+    }
+
+    #[test]
+    fn yaml_root_sequence_and_unindented_items() {
+        assert_eq!(rule_positions(ThemeFormat::Yaml, "---\n- pattern: a\n- pattern: b\n"), vec![(2, 1), (3, 1)]);
+        let source = "rules: # 规则\n- pattern: a\n- pattern: b\nname: demo\n";
+        assert_eq!(rule_positions(ThemeFormat::Yaml, source), vec![(2, 1), (3, 1)]);
+    }
+
+    /// 加载时 replacements 优先于 rules, 与书写顺序无关
+    #[test]
+    fn yaml_replacements_before_rules() {
+        let source = "rules:\n  - pattern: a\n\"replacements\":\n  - pattern: b\n  - pattern: c\n";
+        assert_eq!(rule_positions(ThemeFormat::Yaml, source), vec![(4, 3), (5, 3)]);
+    }
+
+    #[test]
+    fn yaml_flow_sequence_has_no_positions() {
+        assert!(rule_positions(ThemeFormat::Yaml, "replacements: [{pattern: a}]\n").is_empty());
+        assert!(rule_positions(ThemeFormat::Yaml, "replacements_extra:\n  - pattern: a\n").is_empty());
+    }
+}
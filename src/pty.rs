@@ -0,0 +1,210 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Mutex, Once};
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::process::Command;
+
+/// --pty 使用的伪终端, 子进程运行在从设备上
+pub struct Pty {
+    master: OwnedFd,
+    slave: OwnedFd,
+}
+
+impl Pty {
+    /// 初始大小与所在的终端相同, 而不是默认的 80x24
+    pub fn open() -> io::Result<Self> {
+        let (mut master, mut slave) = (-1, -1);
+        let mut size = window_size();
+        // SAFETY: openpty 只写入两个文件描述符, 读取窗口大小, 名称和 termios 都不使用;
+        // 有的平台上窗口大小参数是 *mut, 所以传可变指针
+        let result = unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), &raw mut size) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty 成功时两个描述符都是新打开的, 由这里独占
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // 子进程只应继承作为标准输入输出的从设备
+        set_cloexec(master.as_raw_fd())?;
+        set_cloexec(slave.as_raw_fd())?;
+        Ok(Self { master, slave })
+    }
+
+    /// 子进程的标准输入、输出和错误都连接到从设备, 并以从设备作为控制终端
+    pub fn attach(&self, cmd: &mut Command) -> io::Result<()> {
+        cmd.stdin(self.slave.try_clone()?)
+            .stdout(self.slave.try_clone()?)
+            .stderr(self.slave.try_clone()?);
+        // SAFETY: 闭包在 fork 之后的子进程中运行, 只调用异步信号安全的 setsid 和 ioctl
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// 在后台监听 SIGWINCH, 所在的终端大小变化后同步到伪终端, 内核随后通知子进程
+    pub fn watch_resize(&self) -> io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let master = self.master.try_clone()?;
+        let mut resized = signal(SignalKind::window_change())?;
+        tokio::spawn(async move {
+            while resized.recv().await.is_some() {
+                crate::terminal::refresh();
+                let _ = set_size(master.as_raw_fd(), &window_size());
+            }
+        });
+        Ok(())
+    }
+
+    /// 子进程启动后关闭这一端的从设备, 返回读取输出和写入按键的主设备;
+    /// 否则子进程退出后读取主设备不会结束
+    pub fn into_master(self) -> io::Result<(Reader, File)> {
+        let writer = File::from_std(self.master.try_clone()?.into());
+        Ok((Reader(File::from_std(self.master.into())), writer))
+    }
+}
+
+/// 所在终端 (控制终端) 的大小: 标准输出可能被重定向到管道, 因此依次查询标准输入和 /dev/tty,
+/// 都不是终端时为默认的 80x24
+fn window_size() -> libc::winsize {
+    query_size(libc::STDIN_FILENO)
+        .or_else(|| std::fs::File::open("/dev/tty").ok().and_then(|tty| query_size(tty.as_raw_fd())))
+        .unwrap_or(libc::winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        })
+}
+
+/// 用 TIOCGWINSZ 查询终端的大小, 不是终端或大小为 0 时返回 None
+fn query_size(fd: RawFd) -> Option<libc::winsize> {
+    // SAFETY: winsize 是普通的 C 结构体, 由 TIOCGWINSZ 填充
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ 只写入 winsize 结构体
+    let result = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut size) };
+    (result == 0 && size.ws_row > 0 && size.ws_col > 0).then_some(size)
+}
+
+/// 用 TIOCSWINSZ 设置伪终端的大小, 内核随后向子进程发送 SIGWINCH
+fn set_size(master: RawFd, size: &libc::winsize) -> io::Result<()> {
+    // SAFETY: TIOCSWINSZ 只读取 winsize 结构体
+    if unsafe { libc::ioctl(master, libc::TIOCSWINSZ as _, size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: 只修改描述符的标志
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 读取伪终端主设备, 从设备全部关闭后 Linux 返回的 EIO 视为结束
+pub struct Reader(File);
+
+impl AsyncRead for Reader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) if e.raw_os_error() == Some(libc::EIO) => Poll::Ready(Ok(())),
+            poll => poll,
+        }
+    }
+}
+
+/// 切换为原始模式前的终端设置, panic 时由 panic hook 恢复
+static ORIGINAL: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// 子进程运行期间把所在的终端切换为原始模式, 按键原样交给伪终端处理; drop 或 panic 时恢复
+pub struct RawMode(());
+
+impl RawMode {
+    /// 标准输入不是终端时不做任何改变, 返回 None
+    pub fn enable() -> Option<Self> {
+        let fd = libc::STDIN_FILENO;
+        // SAFETY: termios 是普通的 C 结构体, 由 tcgetattr 填充
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: 只读取和设置标准输入的终端属性
+        unsafe {
+            if libc::isatty(fd) != 1 || libc::tcgetattr(fd, &mut original) != 0 {
+                return None;
+            }
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(fd, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+        }
+        *ORIGINAL.lock().unwrap_or_else(|e| e.into_inner()) = Some(original);
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore();
+                previous(info);
+            }));
+        });
+        Some(Self(()))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+fn restore() {
+    if let Some(original) = ORIGINAL.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        // SAFETY: 恢复之前由 tcgetattr 读取的设置
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(rows: u16, cols: u16) -> libc::winsize {
+        libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    /// 大小变化时从所在的终端读取新的大小并设置到伪终端, 子进程一侧能读到新的大小
+    #[test]
+    fn resize_is_applied_to_the_child_side() {
+        let terminal = Pty::open().unwrap();
+        set_size(terminal.master.as_raw_fd(), &size(50, 132)).unwrap();
+        let pty = Pty::open().unwrap();
+        let current = query_size(terminal.slave.as_raw_fd()).unwrap();
+        set_size(pty.master.as_raw_fd(), &current).unwrap();
+        let child = query_size(pty.slave.as_raw_fd()).unwrap();
+        assert_eq!((child.ws_row, child.ws_col), (50, 132));
+    }
+
+    #[test]
+    fn pipes_have_no_size() {
+        let mut fds = [0; 2];
+        // SAFETY: pipe 只写入两个文件描述符
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: pipe 成功时两个描述符都是新打开的
+        let _fds = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        assert!(query_size(fds[0]).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use rand::Rng;
+use std::f64::consts::PI;
+use std::fmt::Write;
+use unicode_width::UnicodeWidthChar;
+
+use crate::style::ANSI_ESCAPE;
+
+/// 每行内颜色随列变化的快慢, 与 lolcat 的 spread 相同
+const SPREAD: f64 = 3.0;
+
+/// lolcat 风格的彩虹着色, 相位在行之间延续, 使波纹连贯
+#[derive(Debug, Clone)]
+pub struct Rainbow {
+    frequency: f64,
+    /// 当前行起始处的相位, 每行加 1
+    phase: f64,
+}
+
+impl Rainbow {
+    /// 指定种子时起始颜色固定, 否则随机
+    pub fn new(seed: Option<u64>, frequency: f64) -> Self {
+        let phase = match seed {
+            Some(seed) => (seed % 256) as f64,
+            None => rand::rng().random_range(0..256) as f64,
+        };
+        Self { frequency, phase }
+    }
+
+    /// 给一行文本着色, 已有的转义序列原样保留; 宽字符占两列, 使各行的颜色按列对齐
+    pub fn paint(&mut self, line: &str) -> String {
+        let mut result = String::with_capacity(line.len() * 8);
+        let mut column = 0usize;
+        let mut painted = false;
+        let mut last = 0;
+        let escapes = ANSI_ESCAPE.find_iter(line).map(|m| (m.start(), m.end()));
+        for (start, end) in escapes.chain([(line.len(), line.len())]) {
+            for c in line[last..start].chars() {
+                if !c.is_whitespace() {
+                    let (r, g, b) = self.color(column);
+                    let _ = write!(result, "\x1b[38;2;{};{};{}m", r, g, b);
+                    painted = true;
+                }
+                result.push(c);
+                column += c.width().unwrap_or(0);
+            }
+            result.push_str(&line[start..end]);
+            last = end;
+        }
+        if painted {
+            result.push_str("\x1b[39m");
+        }
+        self.phase += 1.0;
+        result
+    }
+
+    fn color(&self, column: usize) -> (u8, u8, u8) {
+        let position = self.frequency * (self.phase + column as f64 / SPREAD);
+        let channel = |offset: f64| ((position + offset).sin() * 127.0 + 128.0) as u8;
+        (channel(0.0), channel(2.0 * PI / 3.0), channel(4.0 * PI / 3.0))
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::terminal;
+
+/// --record 写出的 asciinema v2 录像
+///
+/// 每个输出事件立即写入文件, 命令被信号终止时已写入的内容仍是完整的录像
+#[derive(Debug)]
+pub struct Recorder {
+    path: String,
+    file: Mutex<File>,
+    start: Instant,
+    /// 写入失败后只警告一次并不再写入, 不影响命令运行
+    failed: AtomicBool,
+}
+
+impl Recorder {
+    /// 创建录像文件并写出文件头, 终端大小取自当前终端, 标题为运行的命令
+    pub fn create(path: &str, title: &str) -> Result<Self> {
+        let mut file = File::create(path).with_context(|| format!("无法创建录像文件: {}", path))?;
+        let (width, height) = terminal::size();
+        let header = serde_json::json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            "title": title,
+            "env": {
+                "SHELL": std::env::var("SHELL").ok(),
+                "TERM": std::env::var("TERM").ok(),
+            },
+        });
+        writeln!(file, "{}", header).with_context(|| format!("无法写入录像文件: {}", path))?;
+        Ok(Self {
+            path: path.to_string(),
+            file: Mutex::new(file),
+            start: Instant::now(),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// 记录一次输出, 包括其中的转义序列; 标准输出和标准错误都记录为终端输出
+    pub fn output(&self, data: &[u8]) {
+        if data.is_empty() || self.failed.load(Ordering::Relaxed) {
+            return;
+        }
+        // 与终端的 onlcr 相同把 `\n` 转换为 `\r\n`, 否则播放时每行不会回到行首
+        let mut text = String::with_capacity(data.len());
+        let mut previous = '\0';
+        for c in String::from_utf8_lossy(data).chars() {
+            if c == '\n' && previous != '\r' {
+                text.push('\r');
+            }
+            text.push(c);
+            previous = c;
+        }
+        let event = serde_json::json!([self.start.elapsed().as_secs_f64(), "o", text]);
+        let line = event.to_string() + "\n";
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes())
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            eprintln!("警告: 无法写入录像文件 {}, 不再写入: {}", self.path, e);
+        }
+    }
+}
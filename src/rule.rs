@@ -0,0 +1,1588 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveTime;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::{Captures, Regex, RegexBuilder};
+use regex_syntax::hir::{Class, Hir, HirKind};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::action::{MatchHook, Notifier};
+use crate::config::{Locales, MatchMode, Normalization, ReplacementConfig, ReplacementText, Streams};
+use crate::emoji;
+use crate::script::Script;
+use crate::style;
+use crate::template::{match_case, CaptureRef, Counts, Template};
+
+/// multiline 规则未指定 window 时匹配的行数
+const DEFAULT_WINDOW: usize = 3;
+
+/// 编译后的替换规则
+#[derive(Clone)] // 实现 Clone trait
+pub struct ReplacementRule {
+    pub id: Option<String>,
+    pub pattern: Regex,
+    pub replacement: Replacement,
+    pub locale: Locales,
+    /// 用于识别同一规则不同语言环境版本的标识
+    pub variant_key: String,
+    pub commands: Vec<CommandPattern>,
+    pub exclude_commands: Vec<CommandPattern>,
+    pub args_pattern: Option<Regex>,
+    pub streams: Streams,
+    pub normalize: Normalization,
+    pub ansi_aware: bool,
+    pub allow_ansi: bool,
+    pub priority: i32,
+    pub max_replacements: usize,
+    pub insert_before: Option<Template>,
+    pub insert_after: Option<Template>,
+    pub on_match: Option<Arc<MatchHook>>,
+    pub notify: Option<Arc<Notifier>>,
+    /// abort 规则匹配后的退出状态
+    pub abort: Option<i32>,
+    /// multiline 规则匹配的最大行数
+    pub window: Option<usize>,
+    pub probability: Option<f64>,
+    pub keep_indent: bool,
+    pub preserve_case: bool,
+    pub stop: bool,
+    pub active_after: Option<NaiveTime>,
+    pub active_before: Option<NaiveTime>,
+    pub when_env: BTreeMap<String, Option<String>>,
+    pub group: Option<String>,
+    pub enabled: bool,
+    /// 筛选前在加载顺序中的位置 (从 1 开始, 与 `--only @N` 相同), 用于区分 pattern 相同的规则
+    pub position: usize,
+}
+
+impl ReplacementRule {
+    pub fn from_config(config: &ReplacementConfig) -> Result<Self> {
+        if config.keep_indent && !config.whole_line {
+            bail!("keep_indent 需要同时设置 whole_line");
+        }
+        let pattern = compile_pattern(config)
+            .with_context(|| format!("无效的正则表达式: {}", config.pattern))?;
+        let replacement = match &config.dictionary {
+            _ if config.drop => Replacement::Drop,
+            _ if let Some(spec) = &config.style => Replacement::Highlight(style::parse(spec)?),
+            _ if let Some(source) = &config.script => Replacement::Script(Arc::new(Script::compile(source)?)),
+            _ if let Some(argv) = &config.replacement_command => {
+                Replacement::Command(Arc::new(ReplacementCommand::new(config, argv)?))
+            }
+            _ if config.redact => Replacement::Redact(Redaction::new(config, &pattern)?),
+            Some(dictionary) => Replacement::Dictionary(Arc::new(Dictionary::new(
+                dictionary,
+                config.ignore_case().unwrap_or(false),
+                !config.allow_ansi,
+            ))),
+            None if config.replacement.is_none() && config.has_action() => Replacement::Keep,
+            None => {
+                let replacement = config
+                    .replacement
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("缺少 replacement"))?;
+                let mut texts = replacement
+                    .texts()
+                    .iter()
+                    .map(|text| prepare_template(config, text))
+                    .collect::<Result<Vec<_>>>()?;
+                match replacement {
+                    ReplacementText::One(_) => Replacement::Template(texts.remove(0)),
+                    ReplacementText::Choices(_) => Replacement::Choices(texts),
+                }
+            }
+        };
+        Ok(Self {
+            id: config.id.clone(),
+            pattern,
+            replacement,
+            locale: config.locale.clone(),
+            variant_key: match &config.id {
+                Some(id) => format!("id:{}", id),
+                None => format!(
+                    "pattern:{}\0{}\0{}\0{}",
+                    match &config.dictionary {
+                        Some(dictionary) => dictionary.keys().cloned().collect::<Vec<_>>().join("\0"),
+                        None => config.pattern.clone(),
+                    },
+                    config.commands.join(","),
+                    config.exclude_commands.join(","),
+                    config.args_pattern.as_deref().unwrap_or("")
+                ),
+            },
+            commands: CommandPattern::parse_all(&config.commands, "filter_commands")?,
+            exclude_commands: CommandPattern::parse_all(&config.exclude_commands, "exclude_commands")?,
+            args_pattern: config
+                .args_pattern
+                .as_deref()
+                .map(|source| {
+                    Regex::new(source).with_context(|| format!("无效的 args_pattern: {}", source))
+                })
+                .transpose()?,
+            streams: config.streams,
+            normalize: config.normalize,
+            ansi_aware: config.ansi_aware,
+            allow_ansi: config.allow_ansi,
+            priority: config.priority,
+            max_replacements: config.max_replacements,
+            insert_before: config.insert_before.as_deref().map(|t| prepare_template(config, t)).transpose()?,
+            insert_after: config.insert_after.as_deref().map(|t| prepare_template(config, t)).transpose()?,
+            on_match: config
+                .on_match
+                .as_deref()
+                .map(|argv| MatchHook::new(argv, config.on_match_interval_ms).map(Arc::new))
+                .transpose()?,
+            notify: config
+                .notify
+                .as_ref()
+                .map(|notify| -> Result<_> {
+                    let title = prepare_template(config, notify.title.as_deref().unwrap_or("clitheme"))?;
+                    let body = prepare_template(config, notify.body.as_deref().unwrap_or("$0"))?;
+                    Ok(Arc::new(Notifier::new(title, body, notify.interval_ms)))
+                })
+                .transpose()?,
+            abort: config.abort.then_some(config.abort_exit_code.unwrap_or(1)),
+            window: config.multiline.then_some(config.window.unwrap_or(DEFAULT_WINDOW)),
+            probability: config.probability,
+            keep_indent: config.keep_indent,
+            preserve_case: config.preserve_case,
+            stop: config.stop,
+            active_after: config.active_after.as_deref().map(parse_time).transpose()?,
+            active_before: config.active_before.as_deref().map(parse_time).transpose()?,
+            when_env: config.when_env.clone(),
+            group: config.group.clone(),
+            enabled: config.enabled,
+            position: 0,
+        })
+    }
+
+    /// 规则在该时刻是否处于生效时间段内
+    pub fn is_active_at(&self, now: NaiveTime) -> bool {
+        match (self.active_after, self.active_before) {
+            (None, None) => true,
+            (Some(after), None) => now >= after,
+            (None, Some(before)) => now < before,
+            (Some(after), Some(before)) if after <= before => after <= now && now < before,
+            // 跨越午夜, 如 20:00 到 07:00
+            (Some(after), Some(before)) => now >= after || now < before,
+        }
+    }
+
+    /// 检查 when_env 条件, 不满足时返回原因
+    pub fn unmet_env_condition(&self) -> Option<String> {
+        self.when_env.iter().find_map(|(name, expected)| {
+            match (std::env::var(name).ok(), expected) {
+                (None, _) => Some(format!("环境变量 {} 未设置", name)),
+                (Some(value), Some(expected)) if value != *expected => {
+                    Some(format!("环境变量 {} 为 \"{}\" 而不是 \"{}\"", name, value, expected))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// 用于提示信息的规则简述
+    pub fn label(&self) -> &str {
+        match (&self.id, &self.replacement) {
+            (Some(id), _) => id,
+            (None, Replacement::Dictionary(_)) => "(dictionary)",
+            (None, _) => self.pattern.as_str(),
+        }
+    }
+
+    /// 统计报告中的规则名称: id, 没有 id 时为 `@N (pattern)`
+    pub fn display_label(&self) -> String {
+        match &self.id {
+            Some(id) => id.clone(),
+            None => format!("@{} ({})", self.position, self.label()),
+        }
+    }
+
+    /// 清理外部命令或脚本生成的替换文本中的控制字符, allow_ansi 规则保持原样
+    fn sanitize(&self, text: String) -> String {
+        if self.allow_ansi {
+            return text;
+        }
+        match style::sanitize(&text) {
+            Cow::Owned(clean) => clean,
+            Cow::Borrowed(_) => text,
+        }
+    }
+
+    /// preserve_case 规则按匹配文本的大小写形式调整替换文本
+    fn match_case(&self, matched: &str, replacement: String) -> String {
+        if self.preserve_case {
+            match_case(matched, &replacement)
+        } else {
+            replacement
+        }
+    }
+
+    /// 规则是否适用于正在运行的命令
+    pub fn applies_to(&self, command: &CommandContext) -> bool {
+        let listed = |commands: &[CommandPattern]| commands.iter().any(|c| c.matches(&command.name));
+        (self.commands.is_empty() || listed(&self.commands))
+            && !listed(&self.exclude_commands)
+            && self.args_pattern.as_ref().is_none_or(|p| p.is_match(&command.args))
+    }
+
+    /// 两条规则是否可能作用于同一命令的同一输出流
+    ///
+    /// 只比较能确定的精确命令名, 两边都是通配符或正则时保守地认为可能重叠
+    pub fn may_overlap(&self, other: &Self) -> bool {
+        let streams = self.streams == Streams::Both || other.streams == Streams::Both || self.streams == other.streams;
+        streams && (self.shares_command_with(other) || other.shares_command_with(self))
+    }
+
+    fn shares_command_with(&self, other: &Self) -> bool {
+        let accepts = |rule: &Self, name: &str| {
+            (rule.commands.is_empty() || rule.commands.iter().any(|c| c.matches(name)))
+                && !rule.exclude_commands.iter().any(|c| c.matches(name))
+        };
+        match (self.commands.is_empty(), other.commands.is_empty()) {
+            (true, true) => true,
+            (true, false) => false,
+            _ => self.commands.iter().any(|command| match command {
+                CommandPattern::Exact(name) => accepts(other, name),
+                CommandPattern::Pattern(_) => {
+                    other.commands.is_empty() || other.commands.iter().any(|c| matches!(c, CommandPattern::Pattern(_)))
+                }
+            }),
+        }
+    }
+}
+
+/// 正在运行的命令, 启动时计算一次供每行的规则筛选使用
+#[derive(Debug, Clone)]
+pub struct CommandContext {
+    /// 小写的命令文件名
+    pub name: String,
+    /// 以空格连接的参数
+    pub args: String,
+}
+
+impl CommandContext {
+    pub fn new(name: &str, args: String) -> Self {
+        Self {
+            name: name.to_lowercase(),
+            args,
+        }
+    }
+}
+
+/// 解析 HH:MM 格式的时间
+pub fn parse_time(text: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(text, "%H:%M").with_context(|| format!("无效的时间 '{}', 应为 HH:MM", text))
+}
+
+/// filter_commands/exclude_commands 中的一项: 精确的命令名、通配符 (`python*`) 或 `re:` 开头的正则
+#[derive(Clone)]
+pub enum CommandPattern {
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl CommandPattern {
+    fn parse(entry: &str) -> Result<Self> {
+        if let Some(source) = entry.strip_prefix("re:") {
+            let regex = RegexBuilder::new(source).case_insensitive(true).build()?;
+            return Ok(Self::Pattern(regex));
+        }
+        if entry.contains(['*', '?', '[']) {
+            let regex = RegexBuilder::new(&glob_to_regex(entry)?).case_insensitive(true).build()?;
+            return Ok(Self::Pattern(regex));
+        }
+        Ok(Self::Exact(entry.to_lowercase()))
+    }
+
+    fn parse_all(entries: &[String], field: &str) -> Result<Vec<Self>> {
+        entries
+            .iter()
+            .map(|entry| {
+                Self::parse(entry).map_err(|e| anyhow!("{} 中的 '{}' 无效: {}", field, entry, e))
+            })
+            .collect()
+    }
+
+    /// 与小写的命令文件名比较
+    fn matches(&self, command_name: &str) -> bool {
+        match self {
+            Self::Exact(name) => name == command_name,
+            Self::Pattern(regex) => regex.is_match(command_name),
+        }
+    }
+}
+
+/// 将命令名通配符转换为完整匹配的正则, 支持 `*`、`?` 和 `[...]` 字符集
+fn glob_to_regex(glob: &str) -> Result<String> {
+    let mut source = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => source.push_str(".*"),
+            '?' => source.push('.'),
+            '[' => {
+                let mut class = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') if !class.is_empty() => break,
+                        Some(c) => class.push(c),
+                        None => bail!("字符集缺少 ']'"),
+                    }
+                }
+                let class = match class.strip_prefix('!') {
+                    Some(negated) => format!("^{}", negated),
+                    None => class,
+                };
+                source.push('[');
+                source.push_str(&class.replace('\\', "\\\\").replace('[', "\\["));
+                source.push(']');
+            }
+            c => source.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    source.push('$');
+    Ok(source)
+}
+
+/// 规则的替换方式
+#[derive(Clone)]
+pub enum Replacement {
+    /// 支持 `$1`/`${name:upper}` 捕获引用的替换文本
+    Template(Template),
+    /// 每处匹配随机选择一个替换文本
+    Choices(Vec<Template>),
+    /// 按匹配到的原文查表
+    Dictionary(Arc<Dictionary>),
+    /// 丢弃匹配的整行
+    Drop,
+    /// 遮盖匹配的文本
+    Redact(Redaction),
+    /// 只给匹配的文本加上样式 (ANSI 转义序列), 不改变文本
+    Highlight(String),
+    /// 以外部命令的输出作为替换文本
+    Command(Arc<ReplacementCommand>),
+    /// 以脚本的返回值作为替换文本
+    Script(Arc<Script>),
+    /// 不改变文本, 只执行 on_match 等匹配时的动作
+    Keep,
+}
+
+impl Replacement {
+    /// 所有可能用到的替换文本, 用于检查捕获引用
+    pub fn templates(&self) -> &[Template] {
+        match self {
+            Self::Template(template) => std::slice::from_ref(template),
+            Self::Choices(choices) => choices,
+            Self::Command(command) => &command.argv,
+            Self::Dictionary(_)
+            | Self::Drop
+            | Self::Redact(_)
+            | Self::Highlight(_)
+            | Self::Script(_)
+            | Self::Keep => &[],
+        }
+    }
+}
+
+/// 单个输出流处理过程中的可变状态
+pub struct ReplaceState {
+    rng: StdRng,
+    counters: Counters,
+    /// 是否输出颜色标签
+    color: bool,
+    /// 所有规则都忽略转义序列匹配
+    ansi_aware: bool,
+    /// 每行只应用第一条匹配的规则
+    first_match: bool,
+    /// 是否执行 on_match 等匹配时的动作
+    actions: bool,
+    /// 当前规则在这一行中的替换次数
+    substitutions: usize,
+    /// 每条规则生效的行数和替换次数 (按规则的加载位置), 用于 --stats
+    stats: HashMap<usize, RuleCount>,
+    /// --trace: 处理当前行时每条规则的处理过程, 未开启时为 None
+    trace: Option<Vec<TraceEvent>>,
+}
+
+/// --trace 中一条规则对一行的处理
+#[derive(Debug)]
+pub struct TraceEvent {
+    /// 规则的 id, 没有 id 时为 pattern
+    pub rule: String,
+    pub step: TraceStep,
+}
+
+#[derive(Debug)]
+pub enum TraceStep {
+    /// 不适用于当前命令 (filter_commands、exclude_commands 或 args_pattern)
+    Command,
+    /// 匹配了, 但没有被 probability 选中
+    Probability,
+    /// 没有匹配
+    NoMatch,
+    /// 生效, 值为应用后的文本
+    Applied(String),
+    /// 被 drop 规则丢弃
+    Dropped,
+}
+
+/// 一条规则在一个输出流中的统计
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct RuleCount {
+    /// 规则生效的行数
+    pub lines: usize,
+    /// 替换 (或 style、keep 规则匹配) 的次数
+    pub substitutions: usize,
+}
+
+impl ReplaceState {
+    /// 指定种子时随机选择可以复现, 多个输出流传入同一个 `counters` 时共用计数
+    pub fn new(seed: Option<u64>, counters: Counters, color: bool) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        Self {
+            rng,
+            counters,
+            color,
+            ansi_aware: false,
+            first_match: false,
+            actions: true,
+            substitutions: 0,
+            stats: HashMap::new(),
+            trace: None,
+        }
+    }
+
+    /// 关闭匹配时的动作, 用于 test 子命令
+    pub fn with_actions(mut self, enabled: bool) -> Self {
+        self.actions = enabled;
+        self
+    }
+
+    /// 设置规则的应用方式
+    pub fn with_mode(mut self, mode: MatchMode) -> Self {
+        self.first_match = mode == MatchMode::FirstMatch;
+        self
+    }
+
+    /// 让所有规则都按 ansi_aware 方式匹配
+    pub fn with_ansi_aware(mut self, enabled: bool) -> Self {
+        self.ansi_aware = enabled;
+        self
+    }
+
+    /// 记录每条规则对每一行的处理过程, 用于 --trace
+    pub fn with_trace(mut self, enabled: bool) -> Self {
+        self.trace = enabled.then(Vec::new);
+        self
+    }
+
+    /// 取出上一行的处理过程
+    pub fn take_trace(&mut self) -> Vec<TraceEvent> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// 开启 --trace 时记录规则的处理, step 只在需要时才计算
+    fn trace(&mut self, rule: &ReplacementRule, step: impl FnOnce() -> TraceStep) {
+        if let Some(events) = &mut self.trace {
+            events.push(TraceEvent { rule: rule.label().to_string(), step: step() });
+        }
+    }
+
+    /// 取出到目前为止的规则统计
+    pub fn take_stats(&mut self) -> HashMap<usize, RuleCount> {
+        std::mem::take(&mut self.stats)
+    }
+
+    /// 记录规则的一次替换, 返回 `{count}`/`{total_count}` 的计数
+    fn count(&mut self, rule: &ReplacementRule) -> Counts {
+        self.substitutions += 1;
+        self.counters.next(rule)
+    }
+
+    /// 规则在这一行生效
+    fn record(&mut self, rule: &ReplacementRule) {
+        let substitutions = std::mem::take(&mut self.substitutions);
+        let count = self.stats.entry(rule.position).or_default();
+        count.lines += 1;
+        count.substitutions += substitutions;
+    }
+}
+
+/// `{count}`/`{total_count}` 使用的匹配计数, 在子进程运行期间不会重置
+#[derive(Debug, Clone, Default)]
+pub struct Counters(Arc<Mutex<CounterValues>>);
+
+#[derive(Debug, Default)]
+struct CounterValues {
+    /// 按 variant_key 统计, 规则重新加载后继续计数
+    per_rule: HashMap<String, usize>,
+    total: usize,
+}
+
+impl Counters {
+    /// 记录规则的一次匹配, 返回记录后的计数
+    fn next(&self, rule: &ReplacementRule) -> Counts {
+        let mut values = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        values.total += 1;
+        let count = values.per_rule.entry(rule.variant_key.clone()).or_default();
+        *count += 1;
+        Counts {
+            count: *count,
+            total: values.total,
+        }
+    }
+
+    /// 规则下一次匹配时的计数, 不记录匹配
+    fn peek(&self, rule: &ReplacementRule) -> Counts {
+        let values = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        Counts {
+            count: values.per_rule.get(&rule.variant_key).copied().unwrap_or(0) + 1,
+            total: values.total + 1,
+        }
+    }
+}
+
+/// dictionary 规则的对照表
+pub struct Dictionary {
+    entries: HashMap<String, String>,
+    case_insensitive: bool,
+}
+
+impl Dictionary {
+    fn new(entries: &BTreeMap<String, String>, case_insensitive: bool, sanitize: bool) -> Self {
+        let entries = entries
+            .iter()
+            .map(|(from, to)| {
+                let from = if case_insensitive { from.to_lowercase() } else { from.clone() };
+                let to = if sanitize { style::sanitize(to).into_owned() } else { to.clone() };
+                (from, to)
+            })
+            .collect();
+        Self {
+            entries,
+            case_insensitive,
+        }
+    }
+
+    /// 所有替换后的文本
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        self.entries.values().map(String::as_str)
+    }
+
+    fn lookup<'a>(&'a self, text: &'a str) -> &'a str {
+        let found = if self.case_insensitive {
+            self.entries.get(&text.to_lowercase())
+        } else {
+            self.entries.get(text)
+        };
+        found.map(String::as_str).unwrap_or(text)
+    }
+}
+
+/// replacement_command 规则每处匹配运行的外部命令
+pub struct ReplacementCommand {
+    argv: Vec<Template>,
+    timeout: Duration,
+    /// 每条规则只警告一次, 避免每处匹配都输出警告
+    warned: AtomicBool,
+    /// 超时过一次后不再运行
+    disabled: AtomicBool,
+}
+
+impl ReplacementCommand {
+    const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+    fn new(config: &ReplacementConfig, argv: &[String]) -> Result<Self> {
+        Ok(Self {
+            argv: argv
+                .iter()
+                .map(|arg| prepare_template(config, arg))
+                .collect::<Result<_>>()?,
+            timeout: config
+                .command_timeout_ms
+                .map_or(Self::DEFAULT_TIMEOUT, Duration::from_millis),
+            warned: AtomicBool::new(false),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    /// 运行命令得到替换文本, 失败时警告并保留原文
+    fn replace(&self, caps: &Captures, counts: Counts, label: &str) -> String {
+        if self.disabled.load(Ordering::Relaxed) {
+            return caps[0].to_string();
+        }
+        let argv: Vec<String> = self.argv.iter().map(|arg| arg.render(caps, counts, false)).collect();
+        match self.run(&argv) {
+            Ok(output) => output,
+            Err(e) => {
+                if !self.warned.swap(true, Ordering::Relaxed) {
+                    eprintln!("警告: 规则 {} 的 replacement_command 执行失败, 保留原文: {:#}", label, e);
+                }
+                caps[0].to_string()
+            }
+        }
+    }
+
+    /// 超时后终止命令所在的整个进程组, 留在后台、仍占用输出管道的进程也会被终止;
+    /// 之后这条规则不再运行命令, 避免每处匹配都等待到超时
+    fn run(&self, argv: &[String]) -> Result<String> {
+        let mut command = tokio::process::Command::new(&argv[0]);
+        command
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        command.process_group(0);
+        let timeout = self.timeout;
+        let output = block_on(async move {
+            let child = command.spawn()?;
+            let pid = child.id();
+            match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Ok(output) => output.map(Some),
+                Err(_) => {
+                    kill_group(pid);
+                    Ok(None)
+                }
+            }
+        })
+        .and_then(|output| output.with_context(|| format!("无法运行 {}", argv[0])))?;
+        let Some(output) = output else {
+            self.disabled.store(true, Ordering::Relaxed);
+            bail!("{} 超过 {} 毫秒未结束, 之后不再运行", argv[0], timeout.as_millis());
+        };
+        if !output.status.success() {
+            bail!("{} 退出状态为 {}", argv[0], output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// 在同步的替换中运行 future: 多线程运行时中让出所在的工作线程, 不阻塞其他输出流的处理;
+/// 否则 (单线程运行时或不在运行时中) 在单独的线程中运行
+fn block_on<F>(future: F) -> Result<F::Output>
+where
+    F: std::future::Future + Send,
+    F::Output: Send,
+{
+    use tokio::runtime::{Builder, Handle, RuntimeFlavor};
+
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+        }
+        _ => std::thread::scope(|scope| {
+            scope
+                .spawn(|| Ok(Builder::new_current_thread().enable_all().build()?.block_on(future)))
+                .join()
+                .map_err(|_| anyhow!("运行命令的线程 panic"))?
+        }),
+    }
+}
+
+#[cfg(unix)]
+fn kill_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        // SAFETY: killpg 只发送信号
+        unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(_pid: Option<u32>) {}
+
+/// redact 规则的遮盖方式
+#[derive(Clone)]
+pub struct Redaction {
+    /// 要遮盖的捕获组, 默认为整个匹配
+    group: Option<CaptureRef>,
+    mask: Option<String>,
+    keep_prefix: usize,
+    keep_suffix: usize,
+}
+
+impl Redaction {
+    fn new(config: &ReplacementConfig, pattern: &Regex) -> Result<Self> {
+        let group = match config.redact_group.as_deref() {
+            None => None,
+            Some(group) => Some(match group.parse() {
+                Ok(index) if index < pattern.captures_len() => CaptureRef::Index(index),
+                Ok(index) => bail!("redact_group 引用了不存在的捕获组 {}", index),
+                Err(_) if pattern.capture_names().flatten().any(|n| n == group) => {
+                    CaptureRef::Name(group.to_string())
+                }
+                Err(_) => bail!("redact_group 引用了不存在的命名捕获组 {}", group),
+            }),
+        };
+        Ok(Self {
+            group,
+            mask: match &config.mask {
+                Some(mask) if !config.allow_ansi => Some(style::sanitize(mask).into_owned()),
+                mask => mask.clone(),
+            },
+            keep_prefix: config.keep_prefix,
+            keep_suffix: config.keep_suffix,
+        })
+    }
+
+    /// 返回遮盖后的整个匹配文本
+    fn apply(&self, caps: &Captures) -> String {
+        let whole = caps.get(0).expect("捕获组 0 总是存在");
+        let target = match &self.group {
+            None => Some(whole),
+            Some(CaptureRef::Index(index)) => caps.get(*index),
+            Some(CaptureRef::Name(name)) => caps.name(name),
+        };
+        let Some(target) = target else {
+            return whole.as_str().to_string();
+        };
+        let text = whole.as_str();
+        let (start, end) = (target.start() - whole.start(), target.end() - whole.start());
+        format!("{}{}{}", &text[..start], self.mask(target.as_str()), &text[end..])
+    }
+
+    fn mask(&self, secret: &str) -> String {
+        let chars: Vec<char> = secret.chars().collect();
+        // 保留的字符过多时整体遮盖, 避免泄露全部内容
+        let (prefix, suffix) = if self.keep_prefix + self.keep_suffix < chars.len() {
+            (self.keep_prefix, self.keep_suffix)
+        } else {
+            (0, 0)
+        };
+        let hidden = chars.len() - prefix - suffix;
+        let mut masked: String = chars[..prefix].iter().collect();
+        match &self.mask {
+            Some(mask) => masked.push_str(mask),
+            None => masked.extend(std::iter::repeat_n('*', hidden)),
+        }
+        masked.extend(&chars[chars.len() - suffix..]);
+        masked
+    }
+}
+
+/// 按规则的 literal 设置和正则标志编译 pattern
+///
+/// dictionary 规则编译为按长度降序排列的字面量分支, regex 的最左优先语义因此等价于最长匹配,
+/// 大量字面量分支在 regex 内部会使用多模式匹配算法
+fn compile_pattern(config: &ReplacementConfig) -> Result<Regex, regex::Error> {
+    let mut source = if let Some(dictionary) = &config.dictionary {
+        let mut keys: Vec<&String> = dictionary.keys().collect();
+        keys.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        keys.iter()
+            .map(|key| {
+                let (start, end) = literal_word_edges(key);
+                let boundary = |edge: bool| if config.whole_word && edge { r"\b" } else { "" };
+                format!("{}{}{}", boundary(start), regex::escape(key), boundary(end))
+            })
+            .collect::<Vec<_>>()
+            .join("|")
+    } else if config.literal {
+        regex::escape(&config.pattern)
+    } else {
+        config.pattern.clone()
+    };
+    if config.whole_word && config.dictionary.is_none() {
+        let (start, end) = word_edges(config);
+        source = format!(
+            "{}(?:{}){}",
+            if start { r"\b" } else { "" },
+            source,
+            if end { r"\b" } else { "" }
+        );
+    }
+    if config.whole_line {
+        source = format!(r"^\s*(?:{})\s*$", source);
+    }
+    let mut builder = RegexBuilder::new(&source);
+    if let Some(enabled) = config.ignore_case() {
+        builder.case_insensitive(enabled);
+    }
+    if let Some(enabled) = config.dotall {
+        builder.dot_matches_new_line(enabled);
+    }
+    if let Some(enabled) = config.unicode {
+        builder.unicode(enabled);
+    }
+    builder.multi_line(config.multiline);
+    builder.build()
+}
+
+/// 判断模式的开头和结尾是否可能是单词字符, 只有这些位置才需要加单词边界
+///
+/// 以 `-`、`(` 等非单词字符或 `^` 等断言开头的模式不加边界, 否则会要求前面紧挨着单词字符
+fn word_edges(config: &ReplacementConfig) -> (bool, bool) {
+    if config.literal {
+        return literal_word_edges(&config.pattern);
+    }
+    let hir = regex_syntax::ParserBuilder::new()
+        .case_insensitive(config.ignore_case().unwrap_or(false))
+        .dot_matches_new_line(config.dotall.unwrap_or(false))
+        .unicode(config.unicode.unwrap_or(true))
+        .build()
+        .parse(&config.pattern);
+    match hir {
+        Ok(hir) => (hir_word_edge(&hir, true), hir_word_edge(&hir, false)),
+        // 模式本身无效, 交给后续编译报错
+        Err(_) => (true, true),
+    }
+}
+
+fn literal_word_edges(text: &str) -> (bool, bool) {
+    let start = text.chars().next().is_some_and(is_word_char);
+    let end = text.chars().next_back().is_some_and(is_word_char);
+    (start, end)
+}
+
+fn hir_word_edge(hir: &Hir, start: bool) -> bool {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => false,
+        HirKind::Literal(literal) => {
+            let text = String::from_utf8_lossy(&literal.0);
+            let c = if start { text.chars().next() } else { text.chars().next_back() };
+            c.is_some_and(is_word_char)
+        }
+        HirKind::Class(Class::Unicode(class)) => class
+            .ranges()
+            .iter()
+            .any(|r| is_word_char(r.start()) || is_word_char(r.end())),
+        HirKind::Class(Class::Bytes(class)) => class
+            .ranges()
+            .iter()
+            .any(|r| is_word_char(r.start() as char) || is_word_char(r.end() as char)),
+        HirKind::Repetition(repetition) => hir_word_edge(&repetition.sub, start),
+        HirKind::Capture(capture) => hir_word_edge(&capture.sub, start),
+        HirKind::Concat(items) => {
+            let edge = if start { items.first() } else { items.last() };
+            edge.is_some_and(|item| hir_word_edge(item, start))
+        }
+        HirKind::Alternation(branches) => branches.iter().all(|b| hir_word_edge(b, start)),
+    }
+}
+
+/// 用于单词边界判断的单词字符
+///
+/// 中文、日文不以空格分词, 汉字和假名两侧的 `\b` 几乎不会成立, 因此不视为单词字符
+fn is_word_char(c: char) -> bool {
+    let cjk = matches!(c,
+        '\u{3040}'..='\u{30FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{2FA1F}');
+    (c.is_alphanumeric() || c == '_') && !cjk
+}
+
+/// 按规则设置解析替换文本: literal 规则原样输出, 否则先展开环境变量占位符;
+/// emoji 规则先展开 `:cat:` 等短代码, 除非设置了 allow_ansi, 文本中的控制字符会被清理
+fn prepare_template(config: &ReplacementConfig, text: &str) -> Result<Template> {
+    let text = if config.emoji { emoji::expand(text) } else { Cow::Borrowed(text) };
+    let text = text.as_ref();
+    let template = if config.literal {
+        Template::literal(text)
+    } else {
+        Template::parse(&expand_env_placeholders(text)?)?
+    };
+    Ok(if config.allow_ansi { template } else { template.sanitized() })
+}
+
+/// 在加载时展开替换文本中的 `${env:NAME}` 和 `${env:NAME:-默认值}`
+///
+/// 环境变量的值按字面插入 (其中的 `$` 会被转义), `$${` 表示字面的 `${`,
+/// 与 regex 的 `$$` 转义保持一致, 其余 `$1`/`${name}` 捕获引用原样保留
+fn expand_env_placeholders(replacement: &str) -> Result<String> {
+    let mut result = String::with_capacity(replacement.len());
+    let mut rest = replacement;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if rest.starts_with("$$") {
+            result.push_str("$$");
+            rest = &rest[2..];
+            continue;
+        }
+        let Some(body) = rest.strip_prefix("${env:") else {
+            result.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = body
+            .find('}')
+            .ok_or_else(|| anyhow!("环境变量占位符缺少 '}}': {}", rest))?;
+        let (name, fallback) = match body[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&body[..end], None),
+        };
+        match (std::env::var(name), fallback) {
+            (Ok(value), _) => result.push_str(&value.replace('$', "$$")),
+            (Err(_), Some(fallback)) => result.push_str(fallback),
+            (Err(_), None) => bail!("环境变量 {} 未设置且没有默认值", name),
+        }
+        rest = &body[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// 规则匹配的文本
+///
+/// ansi_aware 规则在去掉转义序列的纯文本上匹配, 替换后再把转义序列放回原来的位置;
+/// 被替换的文本内部的转义序列移到替换文本之后, 这样之后文本的样式保持不变
+struct Subject<'a> {
+    text: &'a str,
+    plain: Cow<'a, str>,
+    escapes: Vec<(usize, &'a str)>,
+}
+
+impl<'a> Subject<'a> {
+    fn new(text: &'a str, ansi_aware: bool) -> Self {
+        if ansi_aware && text.contains('\x1b') {
+            let (plain, escapes) = style::split_ansi(text);
+            Self {
+                text,
+                plain: Cow::Owned(plain),
+                escapes,
+            }
+        } else {
+            Self {
+                text,
+                plain: Cow::Borrowed(text),
+                escapes: Vec::new(),
+            }
+        }
+    }
+
+    /// 用于匹配的文本
+    fn as_str(&self) -> &str {
+        &self.plain
+    }
+
+    /// 替换前 `limit` 处匹配 (0 表示全部), 没有匹配时返回 `None`
+    fn replacen(
+        &self,
+        pattern: &Regex,
+        limit: usize,
+        mut replace: impl FnMut(&Captures) -> String,
+    ) -> Option<String> {
+        if self.escapes.is_empty() {
+            return match pattern.replacen(self.text, limit, replace) {
+                Cow::Borrowed(_) => None,
+                Cow::Owned(replaced) => Some(replaced),
+            };
+        }
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let mut result = String::with_capacity(self.text.len());
+        let mut escapes = self.escapes.iter().peekable();
+        let mut last = 0;
+        let mut matched = false;
+        for caps in pattern.captures_iter(&self.plain).take(limit) {
+            let whole = caps.get(0).expect("捕获组 0 总是存在");
+            // 匹配之前 (包括匹配开头处) 的转义序列保留在原位置
+            while let Some((pos, escape)) = escapes.next_if(|(pos, _)| *pos <= whole.start()) {
+                result.push_str(&self.plain[last..*pos]);
+                result.push_str(escape);
+                last = *pos;
+            }
+            result.push_str(&self.plain[last..whole.start()]);
+            result.push_str(&replace(&caps));
+            while let Some((_, escape)) = escapes.next_if(|(pos, _)| *pos < whole.end()) {
+                result.push_str(escape);
+            }
+            last = whole.end();
+            matched = true;
+        }
+        if !matched {
+            return None;
+        }
+        for (pos, escape) in escapes {
+            result.push_str(&self.plain[last..*pos]);
+            result.push_str(escape);
+            last = *pos;
+        }
+        result.push_str(&self.plain[last..]);
+        Some(result)
+    }
+}
+
+/// 按优先级从高到低稳定排序, 使多个主题合并后的执行顺序可预测
+pub fn sort_by_priority(rules: &mut [ReplacementRule]) {
+    rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+}
+
+/// 子进程的输出流
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// 一个输出流的规则, 按单行规则和 multiline 规则分开
+pub struct RuleSet {
+    pub lines: Vec<ReplacementRule>,
+    pub multiline: Vec<ReplacementRule>,
+}
+
+impl RuleSet {
+    fn new(rules: impl Iterator<Item = ReplacementRule>) -> Self {
+        let (multiline, lines) = rules.partition(|rule| rule.window.is_some());
+        Self { lines, multiline }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &ReplacementRule> {
+        self.lines.iter().chain(&self.multiline)
+    }
+}
+
+/// 按输出流划分好的规则, 每次加载时划分一次, 处理每一行时无需再按流筛选
+struct StreamRules {
+    stdout: Arc<RuleSet>,
+    stderr: Arc<RuleSet>,
+    /// 按执行顺序排列的所有规则
+    all: Arc<[ReplacementRule]>,
+}
+
+impl StreamRules {
+    fn new(rules: Vec<ReplacementRule>) -> Self {
+        let stdout = RuleSet::new(rules.iter().filter(|rule| rule.streams != Streams::Stderr).cloned());
+        let stderr = RuleSet::new(rules.iter().filter(|rule| rule.streams != Streams::Stdout).cloned());
+        Self {
+            stdout: Arc::new(stdout),
+            stderr: Arc::new(stderr),
+            all: rules.into(),
+        }
+    }
+}
+
+/// 可在运行中整体替换的规则集, 由 stdout 和 stderr 处理任务共享
+#[derive(Clone)]
+pub struct SharedRules(Arc<RwLock<StreamRules>>);
+
+impl SharedRules {
+    pub fn new(rules: Vec<ReplacementRule>) -> Self {
+        Self(Arc::new(RwLock::new(StreamRules::new(rules))))
+    }
+
+    /// 获取某个输出流当前规则的快照, 处理一行期间规则不会变化
+    pub fn load(&self, stream: Stream) -> Arc<RuleSet> {
+        let rules = self.0.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(match stream {
+            Stream::Stdout => &rules.stdout,
+            Stream::Stderr => &rules.stderr,
+        })
+    }
+
+    /// 当前的所有规则, 用于统计
+    pub fn all(&self) -> Arc<[ReplacementRule]> {
+        Arc::clone(&self.0.read().unwrap_or_else(|e| e.into_inner()).all)
+    }
+
+    /// 当前规则中是否有 abort 规则
+    pub fn has_abort(&self) -> bool {
+        let rules = self.0.read().unwrap_or_else(|e| e.into_inner());
+        rules.stdout.iter().chain(rules.stderr.iter()).any(|rule| rule.abort.is_some())
+    }
+
+    /// 原子地替换为新的规则集
+    pub fn store(&self, rules: Vec<ReplacementRule>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = StreamRules::new(rules);
+    }
+}
+
+/// 处理一行输入得到的输出
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LineOutput {
+    /// insert_before 插入的行, 按规则顺序排列
+    pub before: Vec<String>,
+    /// 处理后的行, 被 drop 规则丢弃时为 None
+    pub line: Option<String>,
+    /// insert_after 插入的行, 按规则顺序排列
+    pub after: Vec<String>,
+    /// 匹配的 abort 规则, 要求终止子进程
+    pub abort: Option<Abort>,
+    /// 生效的规则 (规则的 id, 没有 id 时为 pattern), 按应用顺序排列
+    pub rules: Vec<String>,
+}
+
+/// abort 规则的匹配
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Abort {
+    pub rule: String,
+    pub exit_code: i32,
+}
+
+impl LineOutput {
+    /// 规则没有改变这一行, 也没有插入其他行
+    pub fn is_unchanged(&self, text: &str) -> bool {
+        self.before.is_empty() && self.after.is_empty() && self.line.as_deref() == Some(text)
+    }
+
+    /// 按输出顺序排列的所有行
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.before
+            .iter()
+            .chain(&self.line)
+            .chain(&self.after)
+            .map(String::as_str)
+    }
+}
+
+/// 应用所有匹配的替换规则到文本, 规则应已按语言环境筛选
+pub fn apply_replacements(
+    text: &str,
+    command: &CommandContext,
+    rules: &[ReplacementRule],
+    state: &mut ReplaceState,
+) -> LineOutput {
+    let mut output = LineOutput::default();
+    let mut result = text.to_string(); // 保留 mut 是必要的
+
+    for rule in rules {
+        if !rule.applies_to(command) {
+            state.trace(rule, || TraceStep::Command);
+            continue;
+        }
+        state.substitutions = 0;
+        // 规范化后能匹配时才采用规范化的文本, 避免改动没有规则生效的行
+        let normalized = match rule.normalize.apply(&result) {
+            Cow::Owned(normalized) if rule.pattern.is_match(&normalized) => Some(normalized),
+            _ => None,
+        };
+        // 概率按匹配的行计算, 未匹配的行不消耗随机数
+        if let Some(probability) = rule.probability {
+            if !rule.pattern.is_match(normalized.as_deref().unwrap_or(&result)) {
+                state.trace(rule, || TraceStep::NoMatch);
+                continue;
+            }
+            if !state.rng.random_bool(probability) {
+                state.trace(rule, || TraceStep::Probability);
+                continue;
+            }
+        }
+        if let Some(normalized) = normalized {
+            result = normalized;
+        }
+        let subject = Subject::new(&result, rule.ansi_aware || state.ansi_aware);
+        if (rule.insert_before.is_some() || rule.insert_after.is_some())
+            && let Some(caps) = rule.pattern.captures(subject.as_str())
+        {
+            // 插入行中的计数为触发插入的这次匹配的计数
+            let counts = state.counters.peek(rule);
+            let render = |template: &Template| template.render(&caps, counts, state.color);
+            output.before.extend(rule.insert_before.as_ref().map(render));
+            output.after.extend(rule.insert_after.as_ref().map(render));
+        }
+        if state.actions
+            && (rule.on_match.is_some() || rule.notify.is_some())
+            && let Some(caps) = rule.pattern.captures(subject.as_str())
+        {
+            if let Some(hook) = &rule.on_match {
+                hook.fire(&rule.pattern, &caps, rule.label());
+            }
+            if let Some(notifier) = &rule.notify {
+                notifier.fire(&caps, state.counters.peek(rule));
+            }
+        }
+        if state.actions
+            && output.abort.is_none()
+            && let Some(exit_code) = rule.abort
+            && rule.pattern.is_match(subject.as_str())
+        {
+            output.abort = Some(Abort {
+                rule: rule.label().to_string(),
+                exit_code,
+            });
+        }
+        let replaced = match &rule.replacement {
+            Replacement::Template(template) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    let counts = state.count(rule);
+                    rule.match_case(&caps[0], template.render(caps, counts, state.color))
+                })
+            }
+            Replacement::Choices(choices) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    let counts = state.count(rule);
+                    let choice = &choices[state.rng.random_range(..choices.len())];
+                    let expanded = choice.render(caps, counts, state.color);
+                    rule.match_case(&caps[0], expanded)
+                })
+            }
+            Replacement::Dictionary(dictionary) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    state.count(rule);
+                    rule.match_case(&caps[0], dictionary.lookup(&caps[0]).to_string())
+                })
+            }
+            Replacement::Redact(redaction) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    state.count(rule);
+                    redaction.apply(caps)
+                })
+            }
+            Replacement::Highlight(style) if state.color => {
+                let escapes: Vec<_> = style::ANSI_ESCAPE.find_iter(subject.as_str()).map(|m| m.range()).collect();
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    state.count(rule);
+                    let matched = caps.get(0).expect("捕获组 0 总是存在");
+                    style::highlight(matched, &escapes, style).unwrap_or_else(|| matched.as_str().to_string())
+                })
+            }
+            // 不输出颜色时 style 规则不改变文本, 但仍然算作匹配
+            Replacement::Highlight(_) | Replacement::Keep => {
+                let limit = if rule.max_replacements == 0 { usize::MAX } else { rule.max_replacements };
+                let matches = rule.pattern.find_iter(subject.as_str()).take(limit).count();
+                if matches == 0 {
+                    state.trace(rule, || TraceStep::NoMatch);
+                    continue;
+                }
+                for _ in 0..matches {
+                    state.count(rule);
+                }
+                Some(result.clone())
+            }
+            Replacement::Command(command) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    let counts = state.count(rule);
+                    let replaced = rule.sanitize(command.replace(caps, counts, rule.label()));
+                    rule.match_case(&caps[0], replaced)
+                })
+            }
+            Replacement::Script(script) => {
+                subject.replacen(&rule.pattern, rule.max_replacements, |caps: &Captures| {
+                    let counts = state.count(rule);
+                    let replaced = rule.sanitize(script.replace(&rule.pattern, caps, counts, rule.label()));
+                    rule.match_case(&caps[0], replaced)
+                })
+            }
+            Replacement::Drop if rule.pattern.is_match(subject.as_str()) => {
+                state.count(rule);
+                state.record(rule);
+                state.trace(rule, || TraceStep::Dropped);
+                output.rules.push(rule.label().to_string());
+                return output;
+            }
+            Replacement::Drop => {
+                state.trace(rule, || TraceStep::NoMatch);
+                continue;
+            }
+        };
+        let Some(replaced) = replaced else {
+            state.trace(rule, || TraceStep::NoMatch);
+            continue;
+        };
+        state.record(rule);
+        output.rules.push(rule.label().to_string());
+        result = if rule.keep_indent {
+            let indent = &result[..result.len() - result.trim_start().len()];
+            format!("{}{}", indent, replaced)
+        } else {
+            replaced
+        };
+        state.trace(rule, || TraceStep::Applied(result.clone()));
+        // first_match 模式下在第一条规则生效前文本都是原文, 因此相当于每条规则都是 final
+        if rule.stop || state.first_match {
+            break;
+        }
+    }
+    output.line = Some(result);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(json: serde_json::Value) -> ReplacementRule {
+        ReplacementRule::from_config(&serde_json::from_value(json).unwrap()).unwrap()
+    }
+
+    fn rules(json: serde_json::Value) -> Vec<ReplacementRule> {
+        serde_json::from_value::<Vec<serde_json::Value>>(json).unwrap().into_iter().map(rule).collect()
+    }
+
+    /// 作为 command 命令的输出应用规则, 返回处理后的行
+    fn apply_for(command: &str, rules: &[ReplacementRule], text: &str) -> String {
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let output = apply_replacements(text, &CommandContext::new(command, String::new()), rules, &mut state);
+        output.line.unwrap()
+    }
+
+    fn apply(rules: &[ReplacementRule], text: &str) -> String {
+        apply_for("", rules, text)
+    }
+
+    /// 优先级决定合并后规则的执行顺序, 同一输入因此得到不同的输出; 相同优先级保持原有顺序
+    #[test]
+    fn priority_changes_output() {
+        let theme = |priority: i32| {
+            let mut rules = rules(json!([
+                {"pattern": "cat", "replacement": "dog"},
+                {"pattern": "dog", "replacement": "wolf", "priority": priority}
+            ]));
+            sort_by_priority(&mut rules);
+            rules
+        };
+        assert_eq!(apply(&theme(0), "cat"), "wolf");
+        assert_eq!(apply(&theme(-1), "cat"), "wolf");
+        assert_eq!(apply(&theme(1), "cat"), "dog");
+    }
+
+    /// final 规则在本行匹配后跳过后续规则, 没有匹配时不影响后续规则
+    #[test]
+    fn final_rule_stops_later_rules() {
+        let rules = rules(json!([
+            {"pattern": "error", "replacement": "错误", "final": true},
+            {"pattern": "错误", "replacement": "杂鱼"},
+            {"pattern": "warn", "replacement": "警告"}
+        ]));
+        assert_eq!(apply(&rules, "error warn"), "错误 warn");
+        assert_eq!(apply(&rules, "错误 warn"), "杂鱼 警告");
+    }
+
+    #[test]
+    fn stop_is_an_alias_for_final() {
+        let rules = rules(json!([
+            {"pattern": "a", "replacement": "b", "stop": true},
+            {"pattern": "b", "replacement": "c"}
+        ]));
+        assert_eq!(apply(&rules, "a"), "b");
+    }
+
+    /// 不适用于当前命令或语言环境的 final 规则不会阻止后续规则
+    #[test]
+    fn inapplicable_final_rule_stops_nothing() {
+        let theme = rules(json!([
+            {"pattern": "error", "replacement": "错误", "final": true, "filter_commands": ["cargo"]},
+            {"pattern": "error", "replacement": "oops"},
+            {"pattern": "错误", "replacement": "杂鱼"}
+        ]));
+        assert_eq!(apply_for("cargo", &theme, "error"), "错误");
+        assert_eq!(apply_for("make", &theme, "error"), "oops");
+
+        let theme = rules(json!([
+            {"pattern": "error", "replacement": "错误", "final": true, "locale": "zh_CN"},
+            {"pattern": "error", "replacement": "oops", "locale": "*"}
+        ]));
+        assert_eq!(apply(&crate::filter::select_locale(theme.clone(), "zh_CN", true), "error"), "错误");
+        assert_eq!(apply(&crate::filter::select_locale(theme, "en_US", true), "error"), "oops");
+    }
+
+    /// 一行中有三处匹配时只替换第一处, 捕获组引用按被替换的匹配展开
+    #[test]
+    fn max_replacements_limits_matches() {
+        let rules = rules(json!([
+            {"pattern": "(\\w+)\\.rs", "replacement": "<$1>", "max_replacements": 1},
+            {"pattern": "x", "replacement": "y", "max_replacements": 0}
+        ]));
+        assert_eq!(apply(&rules, "main.rs lib.rs mod.rs"), "<main> lib.rs mod.rs");
+        assert_eq!(apply(&rules, "x x x"), "y y y");
+    }
+
+    /// literal 规则的 pattern 和替换文本中的正则元字符、`$` 引用都按字面处理
+    #[test]
+    fn literal_pattern_and_replacement() {
+        let rules = rules(json!([
+            {"pattern": "a.b(c)*[1]?", "replacement": "$1 ${name} $$ \\d ${env:HOME}", "literal": true}
+        ]));
+        assert_eq!(apply(&rules, "a.b(c)*[1]? axb(c)c1"), "$1 ${name} $$ \\d ${env:HOME} axb(c)c1");
+        assert_eq!(apply(&rules, "axbcc1"), "axbcc1");
+
+        let anchors = [rule(json!({"pattern": "^$", "replacement": "|", "literal": true}))];
+        assert_eq!(apply(&anchors, "cost: ^$5"), "cost: |5");
+        assert_eq!(apply(&anchors, ""), "");
+    }
+
+    #[test]
+    fn case_insensitive_matches_every_case() {
+        let rules = rules(json!([{"pattern": "error", "replacement": "错误", "case_insensitive": true}]));
+        assert_eq!(apply(&rules, "ERROR Error error eRrOr"), "错误 错误 错误 错误");
+    }
+
+    /// 规则字段设置默认的标志, pattern 中的内联标志仍然生效并优先
+    #[test]
+    fn inline_flags_combine_with_fields() {
+        let pattern = |json| rule(json).pattern;
+        let partly = pattern(json!({"pattern": "(?-i:E)rror", "replacement": "", "case_insensitive": true}));
+        assert!(partly.is_match("ERROR") && partly.is_match("Error"));
+        assert!(!partly.is_match("error"));
+        let inline = pattern(json!({"pattern": "(?i)error", "replacement": ""}));
+        assert!(inline.is_match("ERROR"));
+        let dotall = pattern(json!({"pattern": "a.b", "replacement": "", "dotall": true}));
+        assert!(dotall.is_match("a\nb"));
+        assert!(!pattern(json!({"pattern": "a.b", "replacement": ""})).is_match("a\nb"));
+        let ascii = pattern(json!({"pattern": "^\\w+$", "replacement": "", "unicode": false}));
+        assert!(ascii.is_match("cat") && !ascii.is_match("猫"));
+    }
+
+    #[test]
+    fn whole_word_skips_partial_words() {
+        let theme = rules(json!([{"pattern": "cat", "replacement": "dog", "whole_word": true}]));
+        assert_eq!(apply(&theme, "cat concatenate cats cat."), "dog concatenate cats dog.");
+    }
+
+    /// 以非单词字符开头或结尾的一侧不加边界, 否则 "-v" 要求前面紧挨着单词字符
+    #[test]
+    fn whole_word_only_bounds_word_edges() {
+        let theme = rules(json!([{"pattern": "-v", "replacement": "--verbose", "whole_word": true}]));
+        assert_eq!(apply(&theme, "ls -v -vv"), "ls --verbose -vv");
+        let theme = rules(json!([{"pattern": "(foo)", "replacement": "bar", "whole_word": true, "literal": true}]));
+        assert_eq!(apply(&theme, "x(foo)y"), "xbary");
+    }
+
+    /// 汉字和假名不视为单词字符: 中文不以空格分词, 由汉字组成的 pattern 不加边界, 在句中也能匹配;
+    /// 但 regex 的 \b 仍把汉字当作单词字符, 紧挨着汉字的英文单词不满足边界
+    #[test]
+    fn whole_word_with_cjk() {
+        let theme = rules(json!([{"pattern": "错误", "replacement": "失败", "whole_word": true}]));
+        assert_eq!(apply(&theme, "编译错误了"), "编译失败了");
+        let theme = rules(json!([{"pattern": "エラー", "replacement": "失败", "whole_word": true}]));
+        assert_eq!(apply(&theme, "重大なエラーです"), "重大な失败です");
+        assert!(!is_word_char('猫') && !is_word_char('ア') && is_word_char('é') && is_word_char('_'));
+
+        let theme = rules(json!([{"pattern": "cat", "replacement": "dog", "whole_word": true}]));
+        assert_eq!(apply(&theme, "猫 cat 猫cat"), "猫 dog 猫cat");
+    }
+
+    #[test]
+    fn whole_word_with_literal_and_case_insensitive() {
+        let theme = rules(json!([
+            {"pattern": "a.b", "replacement": "X", "whole_word": true, "literal": true, "case_insensitive": true}
+        ]));
+        assert_eq!(apply(&theme, "a.b A.B a.bc axb"), "X X a.bc axb");
+        let theme = rules(json!([{"pattern": "CAT", "replacement": "dog", "whole_word": true, "case_insensitive": true}]));
+        assert_eq!(apply(&theme, "Cat cat CONCAT"), "dog dog CONCAT");
+    }
+
+    /// 两个列表同时存在时, 命令须在 filter_commands 中且不在 exclude_commands 中; 比较不区分大小写
+    #[test]
+    fn filter_and_exclude_commands() {
+        let applies = |json, command: &str| rule(json).applies_to(&CommandContext::new(command, String::new()));
+        let both = json!({"pattern": "a", "replacement": "b", "filter_commands": ["python*", "Cargo"], "exclude_commands": ["python2"]});
+        assert!(applies(both.clone(), "python3"));
+        assert!(applies(both.clone(), "PYTHON3"));
+        assert!(applies(both.clone(), "cargo"));
+        assert!(!applies(both.clone(), "python2"));
+        assert!(!applies(both.clone(), "Python2"));
+        assert!(!applies(both, "make"));
+
+        let exclude_only = json!({"pattern": "a", "replacement": "b", "exclude_commands": ["Git"]});
+        assert!(applies(exclude_only.clone(), "make"));
+        assert!(!applies(exclude_only.clone(), "GIT"));
+        assert!(!applies(exclude_only, "git"));
+
+        // 同时出现在两个列表中时排除优先
+        let conflicting = json!({"pattern": "a", "replacement": "b", "filter_commands": ["npm"], "exclude_commands": ["re:^NPM$"]});
+        assert!(!applies(conflicting, "npm"));
+    }
+
+    /// 只作用于 stderr 的规则不会出现在 stdout 的规则集中
+    #[test]
+    fn stream_rules_are_partitioned() {
+        let shared = SharedRules::new(rules(json!([
+            {"id": "err", "pattern": "error", "replacement": "错误", "streams": "stderr"},
+            {"id": "out", "pattern": "error", "replacement": "出错", "streams": "stdout"},
+            {"id": "both", "pattern": "warn", "replacement": "警告"}
+        ])));
+        let ids = |stream| -> Vec<_> { shared.load(stream).iter().map(|rule| rule.label().to_string()).collect() };
+        assert_eq!(ids(Stream::Stdout), ["out", "both"]);
+        assert_eq!(ids(Stream::Stderr), ["err", "both"]);
+        assert_eq!(apply(&shared.load(Stream::Stdout).lines, "error warn"), "出错 警告");
+        assert_eq!(apply(&shared.load(Stream::Stderr).lines, "error warn"), "错误 警告");
+    }
+
+    /// 概率按匹配的行计算, 同一种子得到相同的生效序列, 未匹配的行不消耗随机数
+    #[test]
+    fn seeded_probability_sequence() {
+        let rules = [rule(json!({"pattern": "cat", "replacement": "猫", "probability": 0.5}))];
+        let command = CommandContext::new("", String::new());
+        let fired = |lines: &[&str]| -> String {
+            let mut state = ReplaceState::new(Some(42), Counters::default(), false).with_actions(false);
+            lines
+                .iter()
+                .filter_map(|line| {
+                    let output = apply_replacements(line, &command, &rules, &mut state).line.unwrap();
+                    (*line == "cat").then(|| if output == "猫" { '1' } else { '0' })
+                })
+                .collect()
+        };
+        assert_eq!(fired(&["cat"; 12]), "000111001100");
+        let mut mixed = vec!["cat", "dog", "cat", "dog", "dog"];
+        mixed.extend(["cat"; 10]);
+        assert_eq!(fired(&mixed), "000111001100");
+    }
+
+
+    /// 多条规则插入的行按规则顺序叠加, 插入的行不经过后续规则
+    #[test]
+    fn inserted_lines_stack_and_are_not_reprocessed() {
+        let rules = rules(json!([
+            {"pattern": "error (\\d+)", "insert_before": "--- error $1 ---", "insert_after": "hint: error"},
+            {"pattern": "error", "insert_before": "error!", "replacement": "错误"},
+            {"pattern": "hint", "replacement": "提示"}
+        ]));
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let output = apply_replacements("error 42", &CommandContext::new("", String::new()), &rules, &mut state);
+        assert_eq!(output.lines().collect::<Vec<_>>(), ["--- error 42 ---", "error!", "错误 42", "hint: error"]);
+    }
+
+    /// 同一输入在两种模式下的输出: 累积模式中后面的规则作用于替换后的文本, first_match 只应用第一条匹配原文的规则
+    #[test]
+    fn first_match_versus_cumulative() {
+        let rules = rules(json!([
+            {"pattern": "hello", "replacement": "hi"},
+            {"pattern": "hi", "replacement": "hey"},
+            {"pattern": "world", "replacement": "地球"},
+            {"pattern": "drop me", "drop": true}
+        ]));
+        let cases = [
+            ("hello world", "hey 地球", "hi world"),
+            ("hi world", "hey 地球", "hey world"),
+            ("world hello", "地球 hey", "world hi"),
+            ("world", "地球", "地球"),
+            ("other text", "other text", "other text"),
+        ];
+        let run = |mode, text| {
+            let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false).with_mode(mode);
+            apply_replacements(text, &CommandContext::new("", String::new()), &rules, &mut state).line
+        };
+        for (input, cumulative, first_match) in cases {
+            assert_eq!(run(MatchMode::Cumulative, input).as_deref(), Some(cumulative), "{}", input);
+            assert_eq!(run(MatchMode::FirstMatch, input).as_deref(), Some(first_match), "{}", input);
+        }
+        // 前面的规则生效后, first_match 模式不再检查后面的 drop 规则
+        assert_eq!(run(MatchMode::Cumulative, "hello drop me"), None);
+        assert_eq!(run(MatchMode::FirstMatch, "hello drop me").as_deref(), Some("hi drop me"));
+        assert_eq!(run(MatchMode::FirstMatch, "drop me"), None);
+    }
+
+    /// 命令把后台进程留在输出管道上时, 超时后连同后台进程一起终止并保留原文,
+    /// 之后这条规则不再运行命令; 正常结束的命令输出作为替换文本
+    #[cfg(unix)]
+    #[test]
+    fn replacement_command_timeout_covers_background_processes() {
+        use std::time::Instant;
+
+        let echo = rules(json!([{"pattern": "\\d+", "replacement_command": ["sh", "-c", "echo n$0"]}]));
+        assert_eq!(apply(&echo, "a 1 b 22"), "a n1 b n22");
+
+        let hung = rules(json!([{
+            "pattern": "\\d+",
+            "replacement_command": ["sh", "-c", "echo x; sleep 30 &"],
+            "command_timeout_ms": 200
+        }]));
+        let start = Instant::now();
+        assert_eq!(apply(&hung, "1 2 3"), "1 2 3");
+        assert_eq!(apply(&hung, "4"), "4");
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Result};
+use regex::{Captures, Regex};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::template::Counts;
+
+/// 每次执行脚本的时间上限
+const TIME_LIMIT: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// 当前线程正在执行的脚本的开始时间, 供进度回调判断是否超时
+    static STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// script 规则编译后的 rhai 脚本
+///
+/// 脚本中可以使用 `captures` (按序号的捕获组数组, 未参与匹配的为空字符串)、
+/// `groups` (命名捕获组) 和 `count`, 返回值转换为字符串作为替换文本;
+/// 引擎不提供文件或进程访问, `print`/`debug` 的输出被丢弃
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    /// 每条规则只警告一次, 避免每处匹配都输出警告
+    warned: AtomicBool,
+}
+
+impl Script {
+    pub fn compile(source: &str) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine
+            .on_print(|_| {})
+            .on_debug(|_, _, _| {})
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .register_fn("human_bytes", human_bytes);
+        engine.disable_symbol("eval");
+        engine.on_progress(|_| {
+            let timed_out = STARTED.get().is_some_and(|started| started.elapsed() > TIME_LIMIT);
+            timed_out.then(|| Dynamic::from("timeout"))
+        });
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow!("脚本编译失败: {}", e))?;
+        Ok(Self {
+            engine,
+            ast,
+            warned: AtomicBool::new(false),
+        })
+    }
+
+    /// 执行脚本得到替换文本, 失败或超时时警告并保留原文
+    pub fn replace(&self, pattern: &Regex, caps: &Captures, counts: Counts, label: &str) -> String {
+        match self.run(pattern, caps, counts) {
+            Ok(output) => output,
+            Err(e) => {
+                if !self.warned.swap(true, Ordering::Relaxed) {
+                    eprintln!("警告: 规则 {} 的 script 执行失败, 保留原文: {}", label, e);
+                }
+                caps[0].to_string()
+            }
+        }
+    }
+
+    fn run(&self, pattern: &Regex, caps: &Captures, counts: Counts) -> Result<String> {
+        let captures: Array = caps
+            .iter()
+            .map(|m| Dynamic::from(m.map_or("", |m| m.as_str()).to_string()))
+            .collect();
+        let groups: Map = pattern
+            .capture_names()
+            .flatten()
+            .map(|name| {
+                let text = caps.name(name).map_or("", |m| m.as_str());
+                (name.into(), Dynamic::from(text.to_string()))
+            })
+            .collect();
+        let mut scope = Scope::new();
+        scope.push("captures", captures);
+        scope.push("groups", groups);
+        scope.push("count", counts.count as i64);
+
+        STARTED.set(Some(Instant::now()));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+        STARTED.set(None);
+        match result {
+            Ok(value) => Ok(value.to_string()),
+            Err(e) if matches!(*e, rhai::EvalAltResult::ErrorTerminated(..)) => {
+                Err(anyhow!("执行超过 {} 毫秒", TIME_LIMIT.as_millis()))
+            }
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+/// 把字节数转换为 `1.5 KiB` 形式
+fn human_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
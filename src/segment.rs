@@ -0,0 +1,459 @@
+use anyhow::{anyhow, bail, Result};
+use encoding_rs::{CoderResult, Decoder, Encoder, EncoderResult, Encoding, UTF_8};
+use regex::Regex;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// 每次从子进程读取的字节数
+const CHUNK_SIZE: usize = 8192;
+
+/// 超过这个长度的尚未结束的一行不再检查是否为提示符
+const MAX_PROMPT_LENGTH: usize = 4096;
+
+/// 按行读取时一行的默认最大字节数
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 4 * 1024 * 1024;
+
+/// 一段输出末尾的分隔符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// 还没有读到分隔符的部分内容
+    None,
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl Terminator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+            Self::Cr => "\r",
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        self.as_str().as_bytes()
+    }
+}
+
+/// 以换行或回车分隔的一段输出
+#[derive(Debug)]
+pub struct Segment {
+    pub text: Vec<u8>,
+    pub terminator: Terminator,
+    /// 属于超过长度上限的行, 不应用规则直接输出
+    pub oversized: bool,
+    /// 一段时间内没有读到分隔符时先返回的部分内容, 同一行其余的内容之后再返回
+    pub partial: bool,
+}
+
+/// 按名称 (如 `gbk`、`shift_jis`) 查找编码
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| anyhow!("未知的编码 '{}'", label))
+}
+
+/// 解析 `4096`、`64K`、`4M` 形式的字节数
+pub fn parse_size(value: &str) -> Result<usize> {
+    let value = value.trim();
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => bail!("无法识别的大小 '{}'", value),
+    };
+    let size = number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("无法识别的大小 '{}'", value))?;
+    if size == 0 {
+        bail!("大小必须大于 0");
+    }
+    Ok(size)
+}
+
+/// 输出使用的编码, 以及无法用该编码表示的字符 (如 emoji) 的替代文本
+#[derive(Debug, Clone)]
+pub struct OutputEncoding {
+    encoding: &'static Encoding,
+    fallback: Vec<u8>,
+}
+
+impl OutputEncoding {
+    pub fn new(encoding: &'static Encoding, fallback: &str) -> Result<Self> {
+        if encoding.output_encoding() != encoding {
+            bail!("不支持以 {} 编码输出", encoding.name());
+        }
+        let (fallback, _, unmappable) = encoding.encode(fallback);
+        if unmappable {
+            bail!("替代文本无法用 {} 编码表示", encoding.name());
+        }
+        Ok(Self {
+            encoding,
+            fallback: fallback.into_owned(),
+        })
+    }
+
+    /// 每个输出流使用各自的编码器, 有状态的编码 (如 ISO-2022-JP) 在行之间保持状态
+    pub fn encoder(&self) -> OutputEncoder {
+        OutputEncoder {
+            encoder: self.encoding.new_encoder(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+/// 把 UTF-8 文本转换为输出编码
+pub struct OutputEncoder {
+    encoder: Encoder,
+    fallback: Vec<u8>,
+}
+
+impl OutputEncoder {
+    pub fn encode(&mut self, text: &str, dst: &mut Vec<u8>) {
+        self.encode_part(text, dst, false);
+    }
+
+    /// 输出流结束时调用, 使有状态的编码回到初始状态
+    pub fn finish(&mut self, dst: &mut Vec<u8>) {
+        self.encode_part("", dst, true);
+    }
+
+    fn encode_part(&mut self, mut text: &str, dst: &mut Vec<u8>, last: bool) {
+        loop {
+            let start = dst.len();
+            let capacity = self
+                .encoder
+                .max_buffer_length_from_utf8_without_replacement(text.len())
+                .unwrap_or(text.len() * 4 + 16);
+            dst.resize(start + capacity, 0);
+            let (result, read, written) =
+                self.encoder.encode_from_utf8_without_replacement(text, &mut dst[start..], last);
+            dst.truncate(start + written);
+            text = &text[read..];
+            match result {
+                EncoderResult::InputEmpty => break,
+                EncoderResult::OutputFull => {}
+                EncoderResult::Unmappable(_) => dst.extend_from_slice(&self.fallback),
+            }
+        }
+    }
+}
+
+/// 按块读取子进程输出并切分为片段, 不要求输出以换行结束
+pub struct SegmentReader<R> {
+    reader: R,
+    /// 子进程输出不是 UTF-8 时使用的解码器, 读到结尾后为 None
+    decoder: Option<Decoder>,
+    buffer: Vec<u8>,
+    /// 按行读取时一行的最大字节数
+    max_line_length: usize,
+    /// 正在分块返回一个过长的行
+    in_long_line: bool,
+    /// 读到没有分隔符的内容后, 等待多久没有新的输出就先返回这部分内容
+    idle_flush: Option<Duration>,
+    /// 尚未结束的一行的末尾与其中之一匹配时立即返回这部分内容
+    prompt_patterns: Arc<[Regex]>,
+}
+
+impl<R: AsyncRead + Unpin> SegmentReader<R> {
+    /// 指定 UTF-8 以外的编码时先把读到的内容转换为 UTF-8, 无法解码的字节转换为替换字符;
+    /// UTF-8 输出不做转换, 其中的无效字节原样保留
+    pub fn new(reader: R, encoding: Option<&'static Encoding>) -> Self {
+        Self {
+            reader,
+            decoder: encoding
+                .filter(|&encoding| encoding != UTF_8)
+                .map(Encoding::new_decoder_without_bom_handling),
+            buffer: Vec::new(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            in_long_line: false,
+            idle_flush: None,
+            prompt_patterns: Arc::new([]),
+        }
+    }
+
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn with_idle_flush(mut self, idle_flush: Option<Duration>) -> Self {
+        self.idle_flush = idle_flush;
+        self
+    }
+
+    pub fn with_prompt_patterns(mut self, prompt_patterns: Arc<[Regex]>) -> Self {
+        self.prompt_patterns = prompt_patterns;
+        self
+    }
+
+    /// 读取一块输出追加到缓冲区, 读到结尾时返回 false
+    async fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; CHUNK_SIZE];
+        let read = self.reader.read(&mut chunk).await?;
+        let last = read == 0;
+        let Some(decoder) = &mut self.decoder else {
+            self.buffer.extend_from_slice(&chunk[..read]);
+            return Ok(!last);
+        };
+        // 被读取边界拆开的多字节字符由解码器保留, 与下一块拼接后再解码
+        let mut input = &chunk[..read];
+        loop {
+            let start = self.buffer.len();
+            let capacity = decoder.max_utf8_buffer_length(input.len()).unwrap_or(CHUNK_SIZE * 4);
+            self.buffer.resize(start + capacity, 0);
+            let (result, consumed, written, _) = decoder.decode_to_utf8(input, &mut self.buffer[start..], last);
+            self.buffer.truncate(start + written);
+            input = &input[consumed..];
+            if result == CoderResult::InputEmpty {
+                break;
+            }
+        }
+        if last {
+            self.decoder = None;
+        }
+        Ok(!last)
+    }
+
+    /// 读取下一个以 `\n`、`\r\n` 或 `\r` 结束的片段, 结尾处没有分隔符的部分也作为一个片段返回
+    ///
+    /// 超过 max_line_length 的行分成多块返回, 每块都标记为 oversized, 缓冲区的大小因此有上限;
+    /// 设置了 idle_flush 时, 没有分隔符的内容 (如交互式命令的提示符) 等待一段时间后标记为 partial 返回;
+    /// 与 prompt_patterns 匹配时不再等待
+    pub async fn next_segment(&mut self) -> io::Result<Option<Segment>> {
+        let mut scanned = 0;
+        let mut idle_flush = self.idle_flush;
+        loop {
+            // 末尾的 `\r` 可能是 `\r\n` 的一部分, 要等读到下一个字节 (或结尾) 再决定
+            let found = self.buffer[scanned..]
+                .iter()
+                .position(|&b| b == b'\n' || b == b'\r')
+                .map(|offset| scanned + offset)
+                .filter(|&index| self.buffer[index] == b'\n' || index + 1 < self.buffer.len());
+            match found {
+                Some(index) if index <= self.max_line_length => {
+                    let terminator = match (self.buffer[index], self.buffer.get(index + 1)) {
+                        (b'\n', _) => Terminator::Lf,
+                        (_, Some(b'\n')) => Terminator::CrLf,
+                        _ => Terminator::Cr,
+                    };
+                    let text = self.buffer[..index].to_vec();
+                    self.buffer.drain(..index + terminator.as_bytes().len());
+                    return Ok(Some(self.segment(text, terminator)));
+                }
+                // 分隔符之前的内容超过上限, 或者尚未读到分隔符时已经超过上限
+                _ if found.is_some() || self.buffer.len() > self.max_line_length + 1 => {
+                    let end = char_boundary(&self.buffer, self.max_line_length);
+                    let text: Vec<u8> = self.buffer.drain(..end).collect();
+                    self.in_long_line = true;
+                    return Ok(Some(Segment { text, terminator: Terminator::None, oversized: true, partial: false }));
+                }
+                _ => {}
+            }
+            scanned = self.buffer.len().saturating_sub(1);
+            if self.at_prompt()
+                && let Some(partial) = self.take_partial()
+            {
+                return Ok(Some(partial));
+            }
+
+            let more = match idle_flush.filter(|_| !self.buffer.is_empty()) {
+                Some(idle) => match tokio::time::timeout(idle, self.fill()).await {
+                    Ok(more) => more?,
+                    Err(_) => match self.take_partial() {
+                        Some(partial) => return Ok(Some(partial)),
+                        // 只有 `\r` 或不完整的字符, 继续等待
+                        None => {
+                            idle_flush = None;
+                            continue;
+                        }
+                    },
+                },
+                None => self.fill().await?,
+            };
+            if !more {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let mut text = std::mem::take(&mut self.buffer);
+                let terminator = if text.last() == Some(&b'\r') {
+                    text.pop();
+                    Terminator::Cr
+                } else {
+                    Terminator::None
+                };
+                return Ok(Some(self.segment(text, terminator)));
+            }
+        }
+    }
+
+    /// 缓冲区中 (已经解码为 UTF-8 的) 尚未结束的一行是否以提示符结尾
+    fn at_prompt(&self) -> bool {
+        if self.prompt_patterns.is_empty() || self.buffer.is_empty() || self.buffer.len() > MAX_PROMPT_LENGTH {
+            return false;
+        }
+        let text = String::from_utf8_lossy(&self.buffer);
+        self.prompt_patterns.iter().any(|pattern| pattern.is_match(&text))
+    }
+
+    /// 取出尚未读到分隔符的内容, 末尾的 `\r` 和不完整的 UTF-8 字符留在缓冲区中
+    fn take_partial(&mut self) -> Option<Segment> {
+        let mut end = self.buffer.len();
+        if self.buffer.last() == Some(&b'\r') {
+            end -= 1;
+        }
+        if let Err(e) = std::str::from_utf8(&self.buffer[..end])
+            && e.error_len().is_none()
+        {
+            end = e.valid_up_to();
+        }
+        if end == 0 {
+            return None;
+        }
+        let text = self.buffer.drain(..end).collect();
+        Some(Segment { text, terminator: Terminator::None, oversized: self.in_long_line, partial: true })
+    }
+
+    /// 以分隔符结束的片段, 是过长行的最后一块时同样标记为 oversized
+    fn segment(&mut self, text: Vec<u8>, terminator: Terminator) -> Segment {
+        let oversized = std::mem::take(&mut self.in_long_line);
+        Segment { text, terminator, oversized, partial: false }
+    }
+
+    /// 读取一块输出, 返回其中所有以 `\n`、`\r\n` 或 `\r` 结束的片段以及末尾尚未结束的部分
+    ///
+    /// 末尾不完整的 UTF-8 字符留到下一块, 避免把一个字符拆开; 读到结尾时返回 None
+    pub async fn read_chunk(&mut self) -> io::Result<Option<Vec<Segment>>> {
+        if !self.fill().await? {
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+            let text = std::mem::take(&mut self.buffer);
+            return Ok(Some(vec![Segment { text, terminator: Terminator::None, oversized: false, partial: false }]));
+        }
+
+        let mut segments = Vec::new();
+        let mut start = 0;
+        let mut index = 0;
+        while index < self.buffer.len() {
+            let terminator = match self.buffer[index] {
+                b'\n' => Terminator::Lf,
+                b'\r' if self.buffer.get(index + 1) == Some(&b'\n') => Terminator::CrLf,
+                b'\r' => Terminator::Cr,
+                _ => {
+                    index += 1;
+                    continue;
+                }
+            };
+            segments.push(Segment {
+                text: self.buffer[start..index].to_vec(),
+                terminator,
+                oversized: false,
+                partial: false,
+            });
+            index += terminator.as_bytes().len();
+            start = index;
+        }
+
+        let rest = &self.buffer[start..];
+        let complete = match std::str::from_utf8(rest) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => rest.len(),
+        };
+        if complete > 0 {
+            segments.push(Segment {
+                text: rest[..complete].to_vec(),
+                terminator: Terminator::None,
+                oversized: false,
+                partial: false,
+            });
+        }
+        self.buffer.drain(..start + complete);
+        Ok(Some(segments))
+    }
+}
+
+/// 不大于 index 的最近的 UTF-8 字符边界, 避免把一个字符拆到两块中
+fn char_boundary(bytes: &[u8], index: usize) -> usize {
+    let is_continuation = |i: usize| bytes.get(i).is_some_and(|&b| b & 0xC0 == 0x80);
+    (index.saturating_sub(3)..=index)
+        .rev()
+        .find(|&i| !is_continuation(i))
+        .filter(|&i| i > 0)
+        .unwrap_or(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{CommandContext, ReplacementRule, SharedRules, Stream};
+    use crate::{process_stream, RunOptions, DEFAULT_MULTILINE_HOLD};
+
+    const PROGRESS: &[u8] = b"downloading 10%\rdownloading 99%\rdone\n";
+
+    async fn segments(input: &[u8]) -> Vec<(String, Terminator)> {
+        let mut reader = SegmentReader::new(input, None);
+        let mut segments = Vec::new();
+        while let Some(segment) = reader.next_segment().await.unwrap() {
+            segments.push((String::from_utf8(segment.text).unwrap(), segment.terminator));
+        }
+        segments
+    }
+
+    /// 作为标准输出按行处理, 返回输出的字节
+    async fn theme(input: &[u8], rules: serde_json::Value) -> Vec<u8> {
+        let rules = serde_json::from_value::<Vec<serde_json::Value>>(rules)
+            .unwrap()
+            .into_iter()
+            .map(|rule| ReplacementRule::from_config(&serde_json::from_value(rule).unwrap()).unwrap())
+            .collect();
+        let options = RunOptions {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            multiline_hold: DEFAULT_MULTILINE_HOLD,
+            ..Default::default()
+        };
+        let (abort, _) = tokio::sync::mpsc::unbounded_channel();
+        let mut output = Vec::new();
+        let command = CommandContext::new("", String::new());
+        process_stream(input, &mut output, &command, SharedRules::new(rules), Stream::Stdout, &options, abort)
+            .await
+            .unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn carriage_return_splits_segments() {
+        assert_eq!(
+            segments(PROGRESS).await,
+            [
+                ("downloading 10%".to_string(), Terminator::Cr),
+                ("downloading 99%".to_string(), Terminator::Cr),
+                ("done".to_string(), Terminator::Lf),
+            ]
+        );
+        assert_eq!(
+            segments(b"a\r\nb\rc").await,
+            [("a".to_string(), Terminator::CrLf), ("b".to_string(), Terminator::Cr), ("c".to_string(), Terminator::None)]
+        );
+    }
+
+    /// 每次重绘分别应用规则, 输出时保留原来的分隔符
+    #[tokio::test]
+    async fn progress_redraws_are_themed_separately() {
+        let rules = serde_json::json!([
+            {"pattern": "^downloading (\\d+)%", "replacement": "下载中 $1%"},
+            {"pattern": "^done$", "replacement": "完成"}
+        ]);
+        assert_eq!(theme(PROGRESS, rules).await, "下载中 10%\r下载中 99%\r完成\n".as_bytes());
+    }
+}
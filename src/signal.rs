@@ -0,0 +1,140 @@
+use std::io;
+use std::process::ExitStatus;
+use tokio::process::Child;
+
+/// 运行命令期间收到后转发给子进程的信号: SIGINT、SIGTERM 和 SIGQUIT
+#[cfg(unix)]
+pub struct Forwarded {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+    quit: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Forwarded {
+    /// 注册后这些信号不再终止 clitheme 自身
+    pub fn new() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            quit: signal(SignalKind::quit())?,
+        })
+    }
+
+    /// 等待下一个信号, 返回信号值
+    pub async fn recv(&mut self) -> i32 {
+        tokio::select! {
+            _ = self.interrupt.recv() => libc::SIGINT,
+            _ = self.terminate.recv() => libc::SIGTERM,
+            _ = self.quit.recv() => libc::SIGQUIT,
+        }
+    }
+}
+
+/// 向子进程发送信号, 子进程有单独的进程组时发给整个进程组
+///
+/// 与子进程同在终端的前台进程组时, 终端的 Ctrl-C 和 Ctrl-\ 已经发给了包括子进程在内的整个进程组,
+/// 不再重复发送 SIGINT 和 SIGQUIT, 否则把第二次 Ctrl-C 当作强制退出的程序无法正常清理
+#[cfg(unix)]
+pub fn forward(child: &Child, process_group: bool, signal: i32) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+    if !process_group && matches!(signal, libc::SIGINT | libc::SIGQUIT) && terminal_foreground() {
+        return;
+    }
+    // SAFETY: kill 和 killpg 只发送信号, 不涉及内存访问
+    unsafe {
+        if process_group {
+            libc::killpg(pid as libc::pid_t, signal);
+        } else {
+            libc::kill(pid as libc::pid_t, signal);
+        }
+    }
+}
+
+/// clitheme 是否在控制终端的前台进程组中; 不在时终端发出的信号不会到达子进程,
+/// 子进程使用单独的进程组, 转发的信号也能到达 `sh -c` 启动的进程
+#[cfg(unix)]
+pub fn terminal_foreground() -> bool {
+    use std::os::fd::AsRawFd;
+
+    let Ok(tty) = std::fs::File::open("/dev/tty") else {
+        return false;
+    };
+    // SAFETY: tcgetpgrp 和 getpgrp 只查询进程组
+    unsafe { libc::tcgetpgrp(tty.as_raw_fd()) == libc::getpgrp() }
+}
+
+/// 子进程的退出码; 被信号终止时与 shell 相同为 128 加信号值, 并在标准错误说明;
+/// 与 shell 一样, Ctrl-C 的 SIGINT 和下游关闭产生的 SIGPIPE 不作说明
+#[cfg(unix)]
+pub fn exit_code(status: ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal @ (libc::SIGINT | libc::SIGPIPE))) => 128 + signal,
+        (None, Some(signal)) => {
+            match name(signal) {
+                Some(name) => eprintln!("子进程被信号 {} ({}) 终止", signal, name),
+                None => eprintln!("子进程被信号 {} 终止", signal),
+            }
+            128 + signal
+        }
+        (None, None) => 1,
+    }
+}
+
+/// 常见信号的名称, 信号值因平台而异
+#[cfg(unix)]
+fn name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Windows 上不转发信号, Ctrl-C 照常由控制台发给所有进程
+#[cfg(not(unix))]
+pub struct Forwarded;
+
+#[cfg(not(unix))]
+impl Forwarded {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self)
+    }
+
+    pub async fn recv(&mut self) -> i32 {
+        std::future::pending().await
+    }
+}
+
+#[cfg(not(unix))]
+pub fn forward(_child: &Child, _process_group: bool, _signal: i32) {}
+
+/// Windows 上没有信号, 没有退出码时为 1
+#[cfg(not(unix))]
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
@@ -0,0 +1,264 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::Streams;
+use crate::rule::{CommandContext, ReplacementRule, RuleCount, Stream};
+
+/// --stats-json 输出的格式版本, 字段有不兼容的变化时加 1
+const SCHEMA_VERSION: u32 = 1;
+
+/// --stats 和 --stats-json: 每个输出流结束时合并进来的统计, 命令结束后输出
+#[derive(Debug)]
+pub struct Stats {
+    /// 在标准错误输出统计表
+    table: bool,
+    /// 在标准错误列出适用于本次命令却从未生效的规则
+    unused: bool,
+    /// JSON 报告的路径, `-` 表示标准错误
+    json: Option<PathBuf>,
+    locale: String,
+    /// 筛选之前加载的规则数
+    loaded: usize,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// 按规则的加载位置记录, 依次为标准输出和标准错误
+    rules: HashMap<usize, [RuleCount; 2]>,
+    /// 两个输出流的行数
+    lines: [usize; 2],
+}
+
+/// --stats-json 的报告
+#[derive(Serialize)]
+struct Report<'a> {
+    schema_version: u32,
+    command: Vec<String>,
+    locale: &'a str,
+    /// 子进程的退出码, 被信号终止时为 null
+    exit_code: Option<i32>,
+    /// 耗时 (秒)
+    elapsed: f64,
+    lines: StreamValues<usize>,
+    rules: Vec<RuleReport>,
+    /// 适用于本次命令却没有匹配任何行的规则
+    unused: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RuleReport {
+    /// 规则的 id, 没有 id 时为 `@N (pattern)`
+    rule: String,
+    /// 规则的加载位置, 与 `--only @N` 相同
+    position: usize,
+    #[serde(flatten)]
+    counts: StreamValues<RuleCount>,
+}
+
+#[derive(Serialize)]
+struct StreamValues<T> {
+    stdout: T,
+    stderr: T,
+}
+
+impl<T: Copy> From<[T; 2]> for StreamValues<T> {
+    fn from([stdout, stderr]: [T; 2]) -> Self {
+        Self { stdout, stderr }
+    }
+}
+
+fn index(stream: Stream) -> usize {
+    match stream {
+        Stream::Stdout => 0,
+        Stream::Stderr => 1,
+    }
+}
+
+impl Stats {
+    pub fn new(table: bool, unused: bool, json: Option<PathBuf>, locale: &str, loaded: usize) -> Self {
+        Self {
+            table,
+            unused,
+            json,
+            locale: locale.to_string(),
+            loaded,
+            counts: Mutex::default(),
+        }
+    }
+
+    pub fn merge(&self, stream: Stream, lines: usize, rules: HashMap<usize, RuleCount>) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts.lines[index(stream)] += lines;
+        for (position, count) in rules {
+            let total = &mut counts.rules.entry(position).or_default()[index(stream)];
+            total.lines += count.lines;
+            total.substitutions += count.substitutions;
+        }
+    }
+
+    /// 规则 (按 id; 为 None 时任一规则) 是否在某一行生效过
+    pub fn matched(&self, rules: &[ReplacementRule], id: Option<&str>) -> bool {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        counts
+            .rules
+            .iter()
+            .filter(|(position, _)| {
+                id.is_none_or(|id| rules.iter().any(|rule| rule.position == **position && rule.id.as_deref() == Some(id)))
+            })
+            .any(|(_, [stdout, stderr])| stdout.lines + stderr.lines > 0)
+    }
+
+    /// 命令结束后输出统计表、未生效的规则和 JSON 报告, themed 为两个输出流是否应用了规则
+    pub fn report(
+        &self,
+        rules: &[ReplacementRule],
+        context: &CommandContext,
+        themed: [bool; 2],
+        command: &[OsString],
+        status: ExitStatus,
+        elapsed: Duration,
+    ) {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let rows = rows(rules, &counts);
+        let (unused, skipped) = unused(rules, &counts, context, themed);
+        if self.table {
+            let cell = |count: RuleCount| format!("{}/{}", count.lines, count.substitutions);
+            eprintln!("规则统计 (生效行数/替换次数):");
+            eprintln!("  {:>12} {:>12}  规则", "stdout", "stderr");
+            for (position, label) in &rows {
+                let [stdout, stderr] = counts.rules.get(position).copied().unwrap_or_default();
+                eprintln!("  {:>12} {:>12}  {}", cell(stdout), cell(stderr), label);
+            }
+        }
+        if self.unused {
+            if unused.is_empty() {
+                eprintln!("所有适用于本次命令的规则都生效过");
+            } else {
+                eprintln!("从未生效的规则 (适用于本次命令, 但没有匹配任何行, 可能是程序的输出文字有变化):");
+                for label in &unused {
+                    eprintln!("  {}", label);
+                }
+            }
+            let filtered = self.loaded.saturating_sub(rules.len());
+            if skipped + filtered > 0 {
+                eprintln!(
+                    "另有 {} 条规则不适用于本次命令或输出流, {} 条规则被语言环境、分组等条件筛选掉, 这些规则不算作未生效",
+                    skipped, filtered
+                );
+            }
+        }
+        if let Some(path) = &self.json {
+            let report = Report {
+                schema_version: SCHEMA_VERSION,
+                command: command.iter().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+                locale: &self.locale,
+                exit_code: status.code(),
+                elapsed: elapsed.as_secs_f64(),
+                lines: counts.lines.into(),
+                rules: rows
+                    .iter()
+                    .map(|(position, label)| RuleReport {
+                        rule: label.clone(),
+                        position: *position,
+                        counts: counts.rules.get(position).copied().unwrap_or_default().into(),
+                    })
+                    .collect(),
+                unused,
+            };
+            if let Err(e) = write_json(path, &report) {
+                eprintln!("警告: 无法写入统计报告: {:#}", e);
+            }
+        }
+    }
+}
+
+/// 统计表的每一行 (规则的加载位置和名称): 先按执行顺序列出当前的规则 (没有生效过的计数为 0),
+/// 再列出运行中重新加载后已经不存在的规则
+fn rows(rules: &[ReplacementRule], counts: &Counts) -> Vec<(usize, String)> {
+    let mut rows: Vec<(usize, String)> = rules.iter().map(|rule| (rule.position, rule.display_label())).collect();
+    let mut removed: Vec<usize> =
+        counts.rules.keys().copied().filter(|position| !rules.iter().any(|rule| rule.position == *position)).collect();
+    removed.sort_unstable();
+    rows.extend(removed.into_iter().map(|position| (position, format!("@{}", position))));
+    rows
+}
+
+/// 适用于本次命令 (命令筛选和输出流) 却没有匹配任何行的规则, 以及不适用的规则数
+fn unused(rules: &[ReplacementRule], counts: &Counts, context: &CommandContext, themed: [bool; 2]) -> (Vec<String>, usize) {
+    let mut unused = Vec::new();
+    let mut skipped = 0;
+    for rule in rules {
+        let streams = match rule.streams {
+            Streams::Both => themed[0] || themed[1],
+            Streams::Stdout => themed[0],
+            Streams::Stderr => themed[1],
+        };
+        if !streams || !rule.applies_to(context) {
+            skipped += 1;
+            continue;
+        }
+        let matched = counts.rules.get(&rule.position).is_some_and(|[stdout, stderr]| stdout.lines + stderr.lines > 0);
+        if !matched {
+            unused.push(rule.display_label());
+        }
+    }
+    (unused, skipped)
+}
+
+/// 写到标准错误而不是标准输出, 避免与处理后的输出混在一起
+fn write_json(path: &Path, report: &Report) -> Result<()> {
+    let mut json = serde_json::to_string_pretty(report)?;
+    json.push('\n');
+    if path == Path::new("-") {
+        std::io::stderr().write_all(json.as_bytes())?;
+    } else {
+        std::fs::write(path, json).with_context(|| path.display().to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{apply_replacements, Counters, ReplaceState};
+
+    fn rules(json: serde_json::Value) -> Vec<ReplacementRule> {
+        let configs: Vec<crate::config::ReplacementConfig> = serde_json::from_value(json).unwrap();
+        configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| ReplacementRule { position: i + 1, ..ReplacementRule::from_config(config).unwrap() })
+            .collect()
+    }
+
+    /// 没有 id、pattern 相同的两条规则分别统计, 被 stop 挡住的第二条规则报告为未生效
+    #[test]
+    fn same_pattern_rules_counted_separately() {
+        let rules = rules(serde_json::json!([
+            {"pattern": "warn", "replacement": "W1", "stop": true},
+            {"pattern": "warn", "replacement": "W2"}
+        ]));
+        let context = CommandContext::new("make", String::new());
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let output = apply_replacements("warn", &context, &rules, &mut state);
+        assert_eq!(output.line.as_deref(), Some("W1"));
+
+        let stats = Stats::new(false, false, None, "default", rules.len());
+        stats.merge(Stream::Stdout, 1, state.take_stats());
+        assert!(stats.matched(&rules, None));
+        let counts = stats.counts.lock().unwrap();
+        assert_eq!(counts.rules[&1][0], RuleCount { lines: 1, substitutions: 1 });
+        assert!(!counts.rules.contains_key(&2));
+        let (unused, skipped) = unused(&rules, &counts, &context, [true, true]);
+        assert_eq!(unused, ["@2 (warn)"]);
+        assert_eq!(skipped, 0);
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, bail, Result};
+use regex::{Captures, Match, Regex};
+use std::{borrow::Cow, ops::Range, sync::LazyLock};
+
+const COLORS: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+const STYLES: [(&str, u8); 9] = [
+    ("reset", 0),
+    ("bold", 1),
+    ("dim", 2),
+    ("italic", 3),
+    ("underline", 4),
+    ("blink", 5),
+    ("reverse", 7),
+    ("hidden", 8),
+    ("strikethrough", 9),
+];
+
+/// 把单个颜色转换为 SGR 参数, `background` 为 true 时生成背景色
+///
+/// 支持 `red`、`bright_red`、`gray`、`color:208` (256 色) 和 `rgb:255,105,180` (真彩色)
+fn color_code(spec: &str, background: bool) -> Result<Option<String>> {
+    let base = if background { 40 } else { 30 };
+    if let Some(index) = spec.strip_prefix("color:") {
+        let index: u8 = index
+            .parse()
+            .map_err(|_| anyhow!("256 色编号应为 0 到 255: {}", spec))?;
+        return Ok(Some(format!("{};5;{}", base + 8, index)));
+    }
+    if let Some(rgb) = spec.strip_prefix("rgb:") {
+        let channels = rgb
+            .split(',')
+            .map(|c| c.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|channels| channels.len() == 3)
+            .ok_or_else(|| anyhow!("真彩色应写作 rgb:R,G,B (0 到 255): {}", spec))?;
+        return Ok(Some(format!(
+            "{};2;{};{};{}",
+            base + 8,
+            channels[0],
+            channels[1],
+            channels[2]
+        )));
+    }
+    let (name, offset) = match spec.strip_prefix("bright_") {
+        Some(name) => (name, base + 60),
+        None if matches!(spec, "gray" | "grey") => ("black", base + 60),
+        None => (spec, base),
+    };
+    Ok(COLORS
+        .iter()
+        .position(|&color| color == name)
+        .map(|index| (offset + index as u8).to_string()))
+}
+
+/// 单个颜色或样式名对应的 SGR 参数, 不是颜色或样式名时返回 `None`
+fn sgr_code(body: &str) -> Result<Option<String>> {
+    Ok(Some(match body.strip_prefix("bg:") {
+        Some(color) => match color_code(color, true)? {
+            Some(code) => code,
+            None => bail!("未知的背景色: {}", color),
+        },
+        None => match STYLES.iter().find(|(name, _)| *name == body) {
+            Some((_, code)) => code.to_string(),
+            None => match color_code(body, false)? {
+                Some(code) => code,
+                None => return Ok(None),
+            },
+        },
+    }))
+}
+
+/// 解析替换文本中的 `{red}`、`{bold}`、`{bg:blue}`、`{color:208}` 等标签, 返回对应的 ANSI 转义序列
+///
+/// 不是颜色标签时返回 `None`, `{bg:...}` 等形式写错时报错
+pub fn tag(body: &str) -> Result<Option<String>> {
+    Ok(sgr_code(body)?.map(|code| format!("\x1b[{}m", code)))
+}
+
+/// 解析 style 规则的 `"red bold bg:blue"`, 返回合并后的 ANSI 转义序列
+pub fn parse(spec: &str) -> Result<String> {
+    let codes = spec
+        .split_whitespace()
+        .map(|word| sgr_code(word)?.ok_or_else(|| anyhow!("未知的颜色或样式: {}", word)))
+        .collect::<Result<Vec<_>>>()?;
+    if codes.is_empty() {
+        bail!("style 不能为空");
+    }
+    Ok(format!("\x1b[{}m", codes.join(";")))
+}
+
+/// 匹配终端输出中的 CSI 转义序列 (颜色等) 和 OSC 序列 (标题、超链接等)
+pub static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)").unwrap()
+});
+
+/// 清理主题提供的替换文本中的控制字符, 防止主题移动光标、清屏或修改终端标题
+///
+/// 转义序列整个去掉, 其余控制字符 (保留换行和制表符, 去掉回车) 改写为 `^[` 形式的可见文本,
+/// C1 控制字符去掉; 颜色标签和 style 规则生成的转义序列不经过这里
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    let unsafe_char = |c: char| c != '\n' && c != '\t' && c.is_control();
+    if !text.contains(unsafe_char) {
+        return Cow::Borrowed(text);
+    }
+    let stripped = ANSI_ESCAPE.replace_all(text, "");
+    let mut result = String::with_capacity(stripped.len());
+    for c in stripped.chars() {
+        match c {
+            c if !unsafe_char(c) => result.push(c),
+            '\r' | '\u{80}'..='\u{9f}' => {}
+            '\x7f' => result.push_str("^?"),
+            c => {
+                result.push('^');
+                result.push(char::from(c as u8 + b'@'));
+            }
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// 去掉文本中完整的转义序列, 不属于转义序列的 ESC 字符保持不变
+pub fn strip_ansi(text: &str) -> Cow<'_, str> {
+    ANSI_ESCAPE.replace_all(text, "")
+}
+
+/// 把文本拆分为去掉转义序列的纯文本, 以及每个转义序列在纯文本中的位置
+pub fn split_ansi(text: &str) -> (String, Vec<(usize, &str)>) {
+    let mut plain = String::with_capacity(text.len());
+    let mut escapes = Vec::new();
+    let mut last = 0;
+    for escape in ANSI_ESCAPE.find_iter(text) {
+        plain.push_str(&text[last..escape.start()]);
+        escapes.push((plain.len(), escape.as_str()));
+        last = escape.end();
+    }
+    plain.push_str(&text[last..]);
+    (plain, escapes)
+}
+
+const RESET: &str = "\x1b[0m";
+
+/// 给匹配的文本加上样式, 不破坏行中已有的转义序列
+///
+/// `escapes` 为行中已有转义序列的位置, 与转义序列交叉的匹配返回 `None` 保持原样;
+/// 匹配内部已有的重置序列之后重新应用本样式, 这样多条 style 规则的样式可以嵌套
+pub fn highlight(matched: Match, escapes: &[Range<usize>], style: &str) -> Option<String> {
+    let inside = |pos: usize| escapes.iter().any(|e| e.start < pos && pos < e.end);
+    if matched.is_empty() || inside(matched.start()) || inside(matched.end()) {
+        return None;
+    }
+    let restored = format!("{}{}", RESET, style);
+    let text = ANSI_ESCAPE.replace_all(matched.as_str(), |caps: &Captures| match &caps[0] {
+        "\x1b[0m" | "\x1b[m" => restored.clone(),
+        escape => escape.to_string(),
+    });
+    Some(format!("{}{}{}", style, text, RESET))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_nasty_sequences() {
+        let cases = [
+            // CSI: 清屏、移动光标、切换备用屏幕
+            ("\x1b[2J\x1b[Hhi", "hi"),
+            ("\x1b[?1049hx\x1b[10;20H", "x"),
+            // OSC 修改终端标题, 以 BEL 或 ST 结尾
+            ("\x1b]0;pwned\x07ok", "ok"),
+            ("\x1b]2;title\x1b\\ok", "ok"),
+            // 不完整的序列和单独的 ESC 改写为可见文本
+            ("\x1b]0;title", "^[]0;title"),
+            ("a\x1bb", "a^[b"),
+            ("end\x1b", "end^["),
+            ("\x1b[", "^[["),
+            // C1 控制字符 (8 位的 CSI、OSC、ST) 去掉
+            ("\u{9b}2Jx", "2Jx"),
+            ("\u{9d}0;t\u{9c}y", "0;ty"),
+            // 回车去掉, 不能覆盖本行已输出的内容
+            ("100%\rdone", "100%done"),
+            ("\x07\x08\x00\x7f", "^G^H^@^?"),
+            ("a\tb\nc", "a\tb\nc"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(sanitize(input), expected, "{:?}", input);
+        }
+        assert!(matches!(sanitize("普通文本 plain\ttext\n"), Cow::Borrowed(_)));
+    }
+}
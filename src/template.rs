@@ -0,0 +1,420 @@
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local,
+};
+use regex::Captures;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::{style, terminal};
+
+/// 替换文本中引用的捕获组
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureRef {
+    Index(usize),
+    Name(String),
+}
+
+impl CaptureRef {
+    fn parse(name: &str) -> Self {
+        match name.parse() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(name.to_string()),
+        }
+    }
+
+    fn get<'h>(&self, caps: &Captures<'h>) -> Option<regex::Match<'h>> {
+        match self {
+            Self::Index(index) => caps.get(*index),
+            Self::Name(name) => caps.name(name),
+        }
+    }
+}
+
+/// 作用于捕获组文本的变换, 写作 `${1:upper}`
+///
+/// 宽度按终端显示列数计算, 中日韩字符占 2 列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Upper,
+    Lower,
+    Title,
+    /// `pad<N` 左对齐, 在右侧补空格
+    PadRight(usize),
+    /// `pad>N` 右对齐, 在左侧补空格
+    PadLeft(usize),
+    /// `truncN` 超出宽度时截断并加省略号
+    Truncate(usize),
+}
+
+impl Transform {
+    fn parse(name: &str) -> Result<Self> {
+        let width = |digits: &str| {
+            digits
+                .parse::<usize>()
+                .map_err(|_| anyhow!("捕获组变换 '{}' 中的宽度无效", name))
+        };
+        Ok(match name {
+            "upper" => Self::Upper,
+            "lower" => Self::Lower,
+            "title" => Self::Title,
+            _ if name.starts_with("pad<") => Self::PadRight(width(&name[4..])?),
+            _ if name.starts_with("pad>") => Self::PadLeft(width(&name[4..])?),
+            _ if name.starts_with("trunc") => Self::Truncate(width(&name[5..])?),
+            _ => bail!(
+                "未知的捕获组变换 '{}' (可用: upper, lower, title, pad<N, pad>N, truncN)",
+                name
+            ),
+        })
+    }
+
+    fn apply(self, text: &str) -> String {
+        match self {
+            Self::Upper => text.to_uppercase(),
+            Self::Lower => text.to_lowercase(),
+            Self::Title => {
+                let mut result = String::with_capacity(text.len());
+                let mut word_start = true;
+                for c in text.chars() {
+                    if word_start {
+                        result.extend(c.to_uppercase());
+                    } else {
+                        result.extend(c.to_lowercase());
+                    }
+                    word_start = !c.is_alphanumeric();
+                }
+                result
+            }
+            Self::PadRight(width) => {
+                let padding = width.saturating_sub(text.width());
+                format!("{}{}", text, " ".repeat(padding))
+            }
+            Self::PadLeft(width) => {
+                let padding = width.saturating_sub(text.width());
+                format!("{}{}", " ".repeat(padding), text)
+            }
+            Self::Truncate(width) => truncate(text, width),
+        }
+    }
+}
+
+/// 让 `replacement` 沿用 `matched` 的大小写形式
+///
+/// 匹配文本全大写时替换文本转为大写, 首字母大写时只大写首字母, 否则转为小写;
+/// 匹配文本没有大小写之分 (如汉字) 时替换文本保持不变, 颜色标签生成的转义序列也保持不变
+pub fn match_case(matched: &str, replacement: &str) -> String {
+    let mut cased = matched.chars().filter(|c| c.is_uppercase() || c.is_lowercase());
+    let Some(first) = cased.next() else {
+        return replacement.to_string();
+    };
+    let rest: Vec<char> = cased.collect();
+    let all_upper = first.is_uppercase() && !rest.is_empty() && rest.iter().all(|c| c.is_uppercase());
+    // 首字母大写时只有第一个字母转为大写
+    let mut capitalize = first.is_uppercase() && !all_upper;
+    let mut result = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            result.push(c);
+            skip_escape(&mut chars, &mut result);
+        } else if all_upper || std::mem::take(&mut capitalize) {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+    result
+}
+
+/// 原样复制 ESC 之后的 CSI 序列 (`[...m` 等)
+fn skip_escape(chars: &mut std::str::Chars, dst: &mut String) {
+    if chars.as_str().starts_with('[') {
+        for c in chars.by_ref() {
+            dst.push(c);
+            if c != '[' && ('\x40'..='\x7e').contains(&c) {
+                break;
+            }
+        }
+    }
+}
+
+/// 重复填充文本直到 `width` 列, 宽字符不会超出该宽度
+fn horizontal_rule(fill: &str, width: usize) -> String {
+    fill.repeat(width / fill.width().max(1))
+}
+
+const ELLIPSIS: char = '…';
+
+/// 按显示宽度截断, 截断时以省略号结尾且总宽度不超过 `width`
+fn truncate(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let budget = width.saturating_sub(ELLIPSIS.width().unwrap_or(1));
+    let mut result = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > budget {
+            break;
+        }
+        used += w;
+        result.push(c);
+    }
+    if width > 0 {
+        result.push(ELLIPSIS);
+    }
+    result
+}
+
+/// 展开模板时 `{count}`/`{total_count}` 的值
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    /// 本规则的匹配次数
+    pub count: usize,
+    /// 所有规则的匹配次数
+    pub total: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Capture(CaptureRef, Vec<Transform>),
+    Count,
+    TotalCount,
+    /// `{time:%H:%M}`/`{date:%Y-%m-%d}`, 在匹配时按本地时间展开
+    DateTime(Vec<Item<'static>>),
+    /// `{hr}`/`{hr:═}`, 用填充字符铺满终端宽度
+    Rule(String),
+    /// `{red}`、`{bold}` 等标签对应的 ANSI 转义序列, 关闭颜色输出时省略
+    Style(String),
+}
+
+const DEFAULT_TIME_FORMAT: &str = "%H:%M:%S";
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// 解析 `{...}` 占位符的内容, 不是已知的占位符时返回 `None`, 按字面输出
+fn placeholder(body: &str) -> Result<Option<Segment>> {
+    let (name, argument) = match body.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (body, None),
+    };
+    Ok(Some(match (name, argument) {
+        ("count", None) => Segment::Count,
+        ("hr", fill) => match fill {
+            Some("") => bail!("{{hr:}} 缺少填充字符"),
+            fill => Segment::Rule(fill.unwrap_or("─").to_string()),
+        },
+        ("total_count", None) => Segment::TotalCount,
+        ("time" | "date", format) => {
+            let default = if name == "time" { DEFAULT_TIME_FORMAT } else { DEFAULT_DATE_FORMAT };
+            let format = format.unwrap_or(default);
+            let items = StrftimeItems::new(format)
+                .parse_to_owned()
+                .map_err(|_| anyhow!("{{{}}} 中的时间格式无效: {}", name, format))?;
+            Segment::DateTime(items)
+        }
+        _ => match style::tag(body)? {
+            Some(escape) => Segment::Style(escape),
+            None => return Ok(None),
+        },
+    }))
+}
+
+/// 预先解析的替换模板
+///
+/// 语法与 regex crate 的 `$1`、`${1}`、`$name`、`${name}`、`$$` 一致,
+/// 另外支持在花括号中追加变换, 如 `${1:upper}`、`${name:lower:title}`,
+/// 以及匹配计数 `{count}`、`{total_count}`, 当前时间 `{time:FORMAT}`、`{date:FORMAT}`
+/// 铺满终端宽度的分隔线 `{hr}` 和颜色标签 `{red}`、`{bg:blue}`, `{{` 表示字面的 `{`
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// 原样输出的文本, 不解析任何引用
+    pub fn literal(text: &str) -> Self {
+        Self {
+            segments: vec![Segment::Literal(text.to_string())],
+        }
+    }
+
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(pos) = rest.find(['$', '{']) {
+            literal.push_str(&rest[..pos]);
+            if let Some(braced) = rest[pos..].strip_prefix('{') {
+                if let Some(after) = braced.strip_prefix('{') {
+                    literal.push('{');
+                    rest = after;
+                    continue;
+                }
+                let segment = match braced.find('}') {
+                    Some(end) => placeholder(&braced[..end])?.map(|segment| (segment, end)),
+                    None => None,
+                };
+                match segment {
+                    Some((segment, end)) => {
+                        if !literal.is_empty() {
+                            segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                        }
+                        segments.push(segment);
+                        rest = &braced[end + 1..];
+                    }
+                    None => {
+                        literal.push('{');
+                        rest = braced;
+                    }
+                }
+                continue;
+            }
+            rest = &rest[pos + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            let (name, transforms) = if let Some(braced) = rest.strip_prefix('{') {
+                let Some(end) = braced.find('}') else {
+                    literal.push('$');
+                    continue;
+                };
+                rest = &braced[end + 1..];
+                let mut parts = braced[..end].split(':');
+                let name = parts.next().unwrap_or_default();
+                let transforms = parts.map(Transform::parse).collect::<Result<Vec<_>>>()?;
+                (name, transforms)
+            } else {
+                let end = rest
+                    .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                (name, Vec::new())
+            };
+            if name.is_empty() {
+                literal.push('$');
+                continue;
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Capture(CaptureRef::parse(name), transforms));
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self { segments })
+    }
+
+    /// 清理文字部分中的控制字符, 捕获组的内容来自命令输出, 不做处理
+    pub fn sanitized(mut self) -> Self {
+        for segment in &mut self.segments {
+            if let Segment::Literal(text) = segment
+                && let Cow::Owned(clean) = style::sanitize(text)
+            {
+                *text = clean;
+            }
+        }
+        self
+    }
+
+    /// 模板中引用的所有捕获组
+    pub fn references(&self) -> impl Iterator<Item = &CaptureRef> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Capture(reference, _) => Some(reference),
+            _ => None,
+        })
+    }
+
+    /// 模板中的文字部分, 即不依赖匹配内容、一定会出现在输出中的文本
+    pub fn literals(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Literal(text) if !text.is_empty() => Some(text.as_str()),
+            _ => None,
+        })
+    }
+
+    /// 展开模板, 不存在或未参与匹配的捕获组展开为空字符串
+    /// `color` 为 false 时省略颜色标签
+    pub fn expand(&self, caps: &Captures, counts: Counts, color: bool, dst: &mut String) {
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => dst.push_str(text),
+                Segment::Count => dst.push_str(&counts.count.to_string()),
+                Segment::TotalCount => dst.push_str(&counts.total.to_string()),
+                Segment::Style(escape) if color => dst.push_str(escape),
+                Segment::Style(_) => {}
+                Segment::Rule(fill) => dst.push_str(&horizontal_rule(fill, terminal::width())),
+                Segment::DateTime(items) => {
+                    dst.push_str(&Local::now().format_with_items(items.iter()).to_string());
+                }
+                Segment::Capture(reference, transforms) => {
+                    let text = reference.get(caps).map_or("", |m| m.as_str());
+                    match transforms.as_slice() {
+                        [] => dst.push_str(text),
+                        [first, others @ ..] => {
+                            let text = others.iter().fold(first.apply(text), |t, f| f.apply(&t));
+                            dst.push_str(&text);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn render(&self, caps: &Captures, counts: Counts, color: bool) -> String {
+        let mut expanded = String::new();
+        self.expand(caps, counts, color, &mut expanded);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    /// 用捕获了整个 text 的第 1 组展开模板
+    fn render(template: &str, text: &str) -> String {
+        let caps = Regex::new("(?s)(.*)").unwrap().captures(text).unwrap();
+        Template::parse(template).unwrap().render(&caps, Counts::default(), false)
+    }
+
+    /// 宽度按显示列计算, 汉字占 2 列
+    #[test]
+    fn pad_mixed_ascii_and_cjk() {
+        assert_eq!(render("[${1:pad<8}]", "ab猫"), "[ab猫    ]");
+        assert_eq!(render("[${1:pad>8}]", "ab猫"), "[    ab猫]");
+        assert_eq!(render("[${1:pad<4}]", "猫猫猫"), "[猫猫猫]");
+        assert_eq!(render("[${1:pad>3}]", "猫a"), "[猫a]");
+    }
+
+    /// 截断后连同省略号不超过指定宽度, 放不下的汉字整个去掉, 不会截断在字符中间
+    #[test]
+    fn truncate_mixed_ascii_and_cjk() {
+        assert_eq!(render("${1:trunc8}", "ab猫猫猫"), "ab猫猫猫");
+        assert_eq!(render("${1:trunc5}", "ab猫猫猫"), "ab猫…");
+        assert_eq!(render("${1:trunc4}", "猫猫猫"), "猫…");
+        assert_eq!(render("${1:trunc4}", "a猫猫"), "a猫…");
+        assert_eq!(render("${1:trunc1}", "猫猫"), "…");
+        assert_eq!(render("${1:trunc0}", "猫猫"), "");
+        for width in 0..8 {
+            assert!(render(&format!("${{1:trunc{}}}", width), "a猫b猫c猫").width() <= width);
+        }
+    }
+
+    #[test]
+    fn truncate_then_pad_aligns_columns() {
+        let column = |text| render("${1:trunc6:pad<6}|", text);
+        assert_eq!(column("main.rs"), "main.…|");
+        assert_eq!(column("猫猫猫猫"), "猫猫… |");
+        assert_eq!(column("猫"), "猫    |");
+        assert!(["main.rs", "猫猫猫猫", "猫", "ab猫"].iter().all(|text| column(text).width() == 7));
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 标准输出不是终端时使用的宽度和高度
+const FALLBACK_WIDTH: usize = 80;
+const FALLBACK_HEIGHT: usize = 24;
+
+static WIDTH: AtomicUsize = AtomicUsize::new(0);
+
+/// 当前终端宽度 (显示列数), 首次调用时查询
+pub fn width() -> usize {
+    match WIDTH.load(Ordering::Relaxed) {
+        0 => refresh(),
+        width => width,
+    }
+}
+
+/// 重新查询终端宽度, 在终端大小变化时调用
+pub fn refresh() -> usize {
+    let width = terminal_size::terminal_size_of(std::io::stdout())
+        .map(|(terminal_size::Width(w), _)| w as usize)
+        .filter(|&w| w > 0)
+        .unwrap_or(FALLBACK_WIDTH);
+    WIDTH.store(width, Ordering::Relaxed);
+    width
+}
+
+/// 当前终端的宽度和高度 (行数)
+pub fn size() -> (usize, usize) {
+    let height = terminal_size::terminal_size_of(std::io::stdout())
+        .map(|(_, terminal_size::Height(h))| h as usize)
+        .filter(|&h| h > 0)
+        .unwrap_or(FALLBACK_HEIGHT);
+    (width(), height)
+}
+
+/// 在后台监听 SIGWINCH, 终端大小变化后刷新宽度
+#[cfg(unix)]
+pub fn watch_resize() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut resized) = signal(SignalKind::window_change()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        while resized.recv().await.is_some() {
+            refresh();
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn watch_resize() {}
@@ -0,0 +1,35 @@
+use anyhow::{bail, Result};
+use chrono::format::{Item, StrftimeItems};
+use std::time::Instant;
+
+/// --timestamps 的默认格式
+pub const DEFAULT_FORMAT: &str = "%H:%M:%S%.3f ";
+
+/// 加在每行输出开头的时间戳
+#[derive(Debug, Clone)]
+pub enum Timestamps {
+    /// 按 strftime 格式输出的本地时间
+    Clock(String),
+    /// 从启动开始经过的秒数
+    Elapsed(Instant),
+}
+
+impl Timestamps {
+    /// 解析 --timestamps 的值, `elapsed` 表示相对时间, 其余为 strftime 格式
+    pub fn parse(value: &str) -> Result<Self> {
+        if value == "elapsed" {
+            return Ok(Self::Elapsed(Instant::now()));
+        }
+        if StrftimeItems::new(value).any(|item| item == Item::Error) {
+            bail!("无效的时间格式 '{}'", value);
+        }
+        Ok(Self::Clock(value.to_string()))
+    }
+
+    pub fn prefix(&self) -> String {
+        match self {
+            Self::Clock(format) => chrono::Local::now().format(format).to_string(),
+            Self::Elapsed(start) => format!("{:>8.3} ", start.elapsed().as_secs_f64()),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::rule::{Stream, TraceEvent, TraceStep};
+
+/// --trace: 在标准错误逐行输出每条规则的处理过程
+#[derive(Debug)]
+pub struct Tracer {
+    /// 最多跟踪的行数, 两个输出流共用
+    limit: Option<usize>,
+    traced: AtomicUsize,
+}
+
+impl Tracer {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            traced: AtomicUsize::new(0),
+        }
+    }
+
+    /// 是否跟踪下一行, 第一次超过 limit 时提示不再输出
+    pub fn admit(&self) -> bool {
+        let traced = self.traced.fetch_add(1, Ordering::Relaxed);
+        match self.limit {
+            Some(limit) if traced >= limit => {
+                if traced == limit {
+                    eprintln!("[trace] 已跟踪 {} 行 (--trace-limit), 不再输出", limit);
+                }
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// 输出一行的原文和每条规则的处理; other_stream 是只作用于另一个输出流而跳过的规则
+    pub fn line<'a>(&self, stream: Stream, text: &str, events: &[TraceEvent], other_stream: impl Iterator<Item = &'a str>) {
+        let stream = match stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        let mut trace = format!("[trace] {} {:?}\n", stream, text);
+        for event in events {
+            let _ = match &event.step {
+                TraceStep::Command => writeln!(trace, "[trace]   {}: 跳过 (命令筛选)", event.rule),
+                TraceStep::Probability => writeln!(trace, "[trace]   {}: 跳过 (probability)", event.rule),
+                TraceStep::NoMatch => writeln!(trace, "[trace]   {}: 未匹配", event.rule),
+                TraceStep::Applied(text) => writeln!(trace, "[trace]   {}: 生效 -> {:?}", event.rule, text),
+                TraceStep::Dropped => writeln!(trace, "[trace]   {}: 丢弃这一行", event.rule),
+            };
+        }
+        for rule in other_stream {
+            let _ = writeln!(trace, "[trace]   {}: 跳过 (输出流)", rule);
+        }
+        // 一次写出, 避免两个输出流的跟踪交错
+        let _ = std::io::stderr().write_all(trace.as_bytes());
+    }
+}
@@ -0,0 +1,99 @@
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+/// 等待文件写入完成的时间, 编辑器保存时常会产生一连串事件
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// 正在监视主题文件, 被丢弃后停止监视
+pub struct SourceWatcher {
+    _watcher: Arc<Mutex<RecommendedWatcher>>,
+}
+
+/// 监视主题文件, 有变化时调用 reload; reload 成功时返回新的主题文件列表,
+/// 之后按新的列表监视, 使新加入的 include 或 extends 目标的修改也能生效
+pub fn watch_sources<F>(sources: &[PathBuf], mut reload: F) -> notify::Result<SourceWatcher>
+where
+    F: FnMut() -> Option<Vec<PathBuf>> + Send + 'static,
+{
+    let mut sources = absolute_all(sources);
+    let mut dirs = watched_dirs(&sources);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let _ = tx.send(event);
+        }
+    })?;
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    let watcher = Arc::new(Mutex::new(watcher));
+    // 只持有弱引用, 否则 watcher 永远不会被丢弃, 线程也不会结束
+    let weak = Arc::downgrade(&watcher);
+
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            if !relevant(&event, &sources) {
+                continue;
+            }
+            // 合并短时间内的后续事件后再重新加载
+            while rx.recv_timeout(DEBOUNCE).is_ok() {}
+            let Some(reloaded) = reload() else {
+                continue;
+            };
+            let Some(watcher) = weak.upgrade() else {
+                break;
+            };
+            let mut watcher = watcher.lock().unwrap_or_else(|e| e.into_inner());
+            sources = absolute_all(&reloaded);
+            let new_dirs = watched_dirs(&sources);
+            for dir in dirs.difference(&new_dirs) {
+                let _ = watcher.unwatch(dir);
+            }
+            for dir in new_dirs.difference(&dirs) {
+                if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    eprintln!("警告: 无法监视 {}: {}", dir.display(), e);
+                }
+            }
+            dirs = new_dirs;
+        }
+    });
+    Ok(SourceWatcher { _watcher: watcher })
+}
+
+fn relevant(event: &notify::Event, sources: &HashSet<PathBuf>) -> bool {
+    !event.kind.is_access()
+        && event
+            .paths
+            .iter()
+            .any(|p| sources.contains(p) || p.parent().is_some_and(|d| sources.contains(d)))
+}
+
+fn absolute_all(sources: &[PathBuf]) -> HashSet<PathBuf> {
+    sources.iter().map(|p| absolute(p)).collect()
+}
+
+/// 编辑器常以"写入临时文件再重命名"的方式保存, 因此监视所在目录而不是文件本身
+fn watched_dirs(sources: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+    sources
+        .iter()
+        .map(|p| {
+            if p.is_dir() {
+                p.clone()
+            } else {
+                p.parent().map(Path::to_path_buf).unwrap_or_default()
+            }
+        })
+        .collect()
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
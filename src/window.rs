@@ -0,0 +1,241 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use crate::rule::{apply_replacements, Abort, CommandContext, ReplaceState, ReplacementRule};
+use crate::segment::{Segment, Terminator};
+
+/// multiline 规则的滑动窗口
+///
+/// 保留最近读到的几行, 窗口填满 (或需要立即输出) 时用 multiline 规则匹配从第一行开始的文本,
+/// 匹配的几行整体替换后离开窗口, 否则只有第一行离开窗口
+#[derive(Default)]
+pub struct Window {
+    pending: VecDeque<Segment>,
+    /// 被 drop 规则丢弃的行数
+    pub dropped: usize,
+}
+
+impl Window {
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// 加入一行, 离开窗口的行 (可能已被改写) 追加到 released
+    pub fn push(
+        &mut self,
+        segment: Segment,
+        rules: &[ReplacementRule],
+        command: &CommandContext,
+        state: &mut ReplaceState,
+        released: &mut Vec<Segment>,
+    ) -> Option<Abort> {
+        self.pending.push_back(segment);
+        let size = rules.iter().filter_map(|rule| rule.window).max().unwrap_or(1);
+        let mut abort = None;
+        while self.pending.len() >= size {
+            abort = abort.or(self.release(rules, command, state, released));
+        }
+        abort
+    }
+
+    /// 不再等待后面的行, 窗口中所有的行都离开窗口
+    pub fn flush(
+        &mut self,
+        rules: &[ReplacementRule],
+        command: &CommandContext,
+        state: &mut ReplaceState,
+        released: &mut Vec<Segment>,
+    ) -> Option<Abort> {
+        let mut abort = None;
+        while !self.pending.is_empty() {
+            abort = abort.or(self.release(rules, command, state, released));
+        }
+        abort
+    }
+
+    /// 按规则顺序找到第一条从窗口第一行开始匹配的规则, 替换匹配涉及的几行; 都不匹配时输出第一行
+    fn release(
+        &mut self,
+        rules: &[ReplacementRule],
+        command: &CommandContext,
+        state: &mut ReplaceState,
+        released: &mut Vec<Segment>,
+    ) -> Option<Abort> {
+        for rule in rules {
+            let Some(window) = rule.window else {
+                continue;
+            };
+            let texts: Vec<Cow<str>> = self
+                .pending
+                .iter()
+                .take(window)
+                .map(|segment| String::from_utf8_lossy(&segment.text))
+                .collect();
+            let joined = texts.join("\n");
+            // 最左的匹配不在第一行开始时, 留到这一行成为窗口第一行时再匹配
+            let Some(found) = rule
+                .pattern
+                .find(&joined)
+                .filter(|found| !found.is_empty() && found.start() <= texts[0].len())
+            else {
+                continue;
+            };
+            // 匹配以换行结束时, 下一行不算作匹配涉及的行
+            let matched = &joined[..found.end()];
+            let count = matched.strip_suffix('\n').unwrap_or(matched).matches('\n').count() + 1;
+            let block = texts[..count].join("\n");
+            let output = apply_replacements(&block, command, std::slice::from_ref(rule), state);
+            if output.is_unchanged(&block) && output.abort.is_none() {
+                continue;
+            }
+
+            let lines: Vec<Segment> = self.pending.drain(..count).collect();
+            if output.line.is_none() {
+                self.dropped += count;
+            }
+            // 替换得到的行沿用第一行的换行方式, 最后一行使用最后一个匹配行原来的分隔符
+            let line_break = if lines[0].terminator == Terminator::CrLf { Terminator::CrLf } else { Terminator::Lf };
+            let terminator = lines[count - 1].terminator;
+            let texts: Vec<&str> = output.lines().flat_map(|line| line.split('\n')).collect();
+            released.extend(texts.iter().enumerate().map(|(index, text)| Segment {
+                text: text.as_bytes().to_vec(),
+                terminator: if index + 1 == texts.len() { terminator } else { line_break },
+                oversized: false,
+                partial: false,
+            }));
+            return output.abort;
+        }
+        released.extend(self.pending.pop_front());
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Counters;
+
+    fn rule(json: serde_json::Value) -> ReplacementRule {
+        ReplacementRule::from_config(&serde_json::from_value(json).unwrap()).unwrap()
+    }
+
+    fn segment(text: &str) -> Segment {
+        Segment {
+            text: text.as_bytes().to_vec(),
+            terminator: Terminator::Lf,
+            oversized: false,
+            partial: false,
+        }
+    }
+
+    fn texts(segments: &[Segment]) -> Vec<String> {
+        segments.iter().map(|segment| String::from_utf8_lossy(&segment.text).into_owned()).collect()
+    }
+
+    fn run(rules: &[ReplacementRule], lines: &[&str]) -> Vec<String> {
+        let command = CommandContext::new("", String::new());
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let mut window = Window::default();
+        let mut released = Vec::new();
+        for line in lines {
+            window.push(segment(line), rules, &command, &mut state, &mut released);
+        }
+        window.flush(rules, &command, &mut state, &mut released);
+        texts(&released)
+    }
+
+    /// 窗口填满前不输出; 没有匹配时每加入一行, 最早的一行离开窗口; flush 时其余的行按顺序离开
+    #[test]
+    fn push_and_flush_keep_order() {
+        let rules = [rule(serde_json::json!({"pattern": "never\\nmatches", "replacement": "x", "multiline": true}))];
+        let command = CommandContext::new("", String::new());
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let mut window = Window::default();
+        let mut released = Vec::new();
+        let mut progress = Vec::new();
+        for line in ["a", "b", "c", "d"] {
+            window.push(segment(line), &rules, &command, &mut state, &mut released);
+            progress.push(texts(&released).join(","));
+        }
+        assert_eq!(progress, ["", "", "a", "a,b"]);
+        assert!(!window.is_empty());
+        window.flush(&rules, &command, &mut state, &mut released);
+        assert_eq!(texts(&released), ["a", "b", "c", "d"]);
+        assert!(window.is_empty());
+    }
+
+    /// 匹配的几行可以替换为更少或更多的行; 替换得到的行沿用第一行的换行方式,
+    /// 最后一行沿用最后一个匹配行的分隔符
+    #[test]
+    fn match_changes_line_count() {
+        let shrink = [rule(serde_json::json!({
+            "pattern": "^error\\n  at .*\\n  at .*$",
+            "replacement": "错误",
+            "multiline": true
+        }))];
+        assert_eq!(run(&shrink, &["before", "error", "  at a", "  at b", "after"]), ["before", "错误", "after"]);
+
+        let grow = [rule(serde_json::json!({"pattern": "^a\\nb$", "replacement": "x\ny\nz", "multiline": true, "window": 2}))];
+        let command = CommandContext::new("", String::new());
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false).with_actions(false);
+        let mut window = Window::default();
+        let mut released = Vec::new();
+        for (text, terminator) in [("start", Terminator::Lf), ("a", Terminator::CrLf), ("b", Terminator::None)] {
+            let segment = Segment { terminator, ..segment(text) };
+            window.push(segment, &grow, &command, &mut state, &mut released);
+        }
+        window.flush(&grow, &command, &mut state, &mut released);
+        let lines: Vec<(String, Terminator)> =
+            released.iter().map(|s| (String::from_utf8_lossy(&s.text).into_owned(), s.terminator)).collect();
+        assert_eq!(
+            lines,
+            [
+                ("start".to_string(), Terminator::Lf),
+                ("x".to_string(), Terminator::CrLf),
+                ("y".to_string(), Terminator::CrLf),
+                ("z".to_string(), Terminator::None),
+            ]
+        );
+    }
+
+    /// drop 规则丢弃匹配的几行并计数; abort 规则在窗口中匹配时返回 Abort, 匹配的行照常输出
+    #[test]
+    fn drop_and_abort_inside_window() {
+        let drop = [rule(serde_json::json!({"pattern": "^noise\\n.*$", "drop": true, "multiline": true, "window": 2}))];
+        let command = CommandContext::new("", String::new());
+        let mut state = ReplaceState::new(Some(0), Counters::default(), false);
+        let mut window = Window::default();
+        let mut released = Vec::new();
+        for line in ["keep", "noise", "more noise", "tail"] {
+            assert!(window.push(segment(line), &drop, &command, &mut state, &mut released).is_none());
+        }
+        window.flush(&drop, &command, &mut state, &mut released);
+        assert_eq!(texts(&released), ["keep", "tail"]);
+        assert_eq!(window.dropped, 2);
+
+        let abort = [rule(serde_json::json!({
+            "pattern": "^panicked\\n.*backtrace",
+            "abort": true,
+            "abort_exit_code": 101,
+            "multiline": true,
+            "window": 2
+        }))];
+        let mut window = Window::default();
+        let mut released = Vec::new();
+        assert!(window.push(segment("panicked"), &abort, &command, &mut state, &mut released).is_none());
+        let aborted = window.push(segment("note: run with backtrace"), &abort, &command, &mut state, &mut released);
+        assert_eq!(aborted.map(|abort| abort.exit_code), Some(101));
+        assert_eq!(texts(&released), ["panicked", "note: run with backtrace"]);
+    }
+
+    #[test]
+    fn match_ending_in_multibyte_character() {
+        let rules = [rule(serde_json::json!({
+            "pattern": "错误\\n详情",
+            "replacement": "出错了",
+            "multiline": true,
+            "window": 2
+        }))];
+        assert_eq!(run(&rules, &["错误", "详情", "其他"]), ["出错了", "其他"]);
+    }
+}
@@ -0,0 +1,41 @@
+//! 子进程用 yes, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, wait_timeout, TempDir};
+use std::io::Read;
+use std::process::Stdio;
+use std::time::Duration;
+
+/// 不停输出的 yes 在 abort 规则匹配后被终止, clitheme 以规则指定的退出码退出
+#[test]
+fn abort_stops_yes() {
+    let dir = TempDir::new("abort");
+    let theme = dir.write("theme.json", r#"[{"pattern": "^y$", "abort": true, "abort_exit_code": 70}]"#);
+
+    let mut child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "yes"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // 一边读取一边等待, 避免管道写满后 clitheme 阻塞
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).unwrap();
+        output
+    });
+    let status = wait_timeout(&mut child, Duration::from_secs(10));
+    let output = reader.join().unwrap();
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+
+    assert_eq!(status.code(), Some(70));
+    assert!(stderr.contains("规则 ^y$ 匹配, 终止命令"), "{}", stderr);
+    // 已经读到的输出完整写出, 不会截断在行中间
+    assert!(output.starts_with(b"y\n") && output.ends_with(b"\n"));
+}
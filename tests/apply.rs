@@ -0,0 +1,50 @@
+mod common;
+
+use common::{clitheme, TempDir};
+
+/// 子命令用 echo, 只在 Unix 上运行
+#[cfg(unix)]
+fn run(themes: &[&std::path::Path], command: &[&str]) -> String {
+    let mut cmd = clitheme();
+    for theme in themes {
+        cmd.arg("-a").arg(theme);
+    }
+    let output = cmd.arg("--").args(command).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// 后面的主题的规则排在前面的主题之后, 作用于前面规则替换后的文本
+#[cfg(unix)]
+#[test]
+fn later_themes_refine_earlier_output() {
+    let dir = TempDir::new("apply-order");
+    let base = dir.write("base.json", r#"[{"pattern": "hello", "replacement": "hi there"}]"#);
+    let project = dir.write("project.json", r#"[{"pattern": "hi", "replacement": "HI"}]"#);
+
+    assert_eq!(run(&[&base, &project], &["echo", "hello"]), "HI there\n");
+    assert_eq!(run(&[&project, &base], &["echo", "hello"]), "hi there\n");
+}
+
+/// 同一个文件 (包括写法不同的同一路径) 只加载一次, 规则不会重复应用
+#[cfg(unix)]
+#[test]
+fn same_theme_is_loaded_once() {
+    let dir = TempDir::new("apply-once");
+    let theme = dir.write("theme.json", r#"[{"pattern": "a", "replacement": "aa"}]"#);
+    let other_spelling = dir.path().join(".").join("theme.json");
+
+    assert_eq!(run(&[&theme, &theme, &other_spelling], &["echo", "a"]), "aa\n");
+}
+
+#[test]
+fn failing_theme_is_named() {
+    let dir = TempDir::new("apply-broken");
+    let good = dir.write("good.json", r#"[{"pattern": "a", "replacement": "b"}]"#);
+    let broken = dir.write("broken.json", r#"[{"pattern": "("}"#);
+
+    let output = clitheme().arg("-a").arg(&good).arg("-a").arg(&broken).args(["--", "echo", "a"]).output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("无法加载主题 {}", broken.display())), "{}", stderr);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{clitheme, TempDir};
+
+/// 不同语言环境把 "cat" 替换成不同的文本
+const THEME: &str = r#"[
+    {"pattern": "cat", "replacement": "猫", "locale": "zh_CN"},
+    {"pattern": "cat", "replacement": "Katze", "locale": "de_DE"},
+    {"pattern": "cat", "replacement": "CAT", "locale": "default"}
+]"#;
+
+#[cfg(unix)]
+fn stdout(cmd: &mut std::process::Command) -> String {
+    let output = cmd.args(["--", "echo", "cat"]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[cfg(unix)]
+#[test]
+fn theme_from_environment() {
+    let dir = TempDir::new("env-theme");
+    let theme = dir.write("theme.json", THEME);
+    let other = dir.write("other.json", r#"[{"pattern": "cat", "replacement": "dog"}]"#);
+
+    assert_eq!(stdout(clitheme().env("CLITHEME_THEME", &theme)), "CAT\n");
+    // --apply 优先于环境变量
+    assert_eq!(stdout(clitheme().env("CLITHEME_THEME", &theme).arg("-a").arg(&other)), "dog\n");
+}
+
+#[test]
+fn missing_theme_is_an_error() {
+    let output = clitheme().args(["--", "echo", "cat"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("必须通过 --apply 或 CLITHEME_THEME 环境变量指定主题"));
+
+    // 空的环境变量等同于没有设置
+    let output = clitheme().env("CLITHEME_THEME", "").args(["--", "echo", "cat"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn locale_precedence() {
+    let dir = TempDir::new("env-locale");
+    let theme = dir.write("theme.json", THEME);
+    let cmd = || {
+        let mut cmd = clitheme();
+        cmd.arg("-a").arg(&theme).env("LANG", "de_DE.UTF-8");
+        cmd
+    };
+
+    // --locale 优先于 CLITHEME_LOCALE, 其次才是 LANG
+    assert_eq!(stdout(cmd().env("CLITHEME_LOCALE", "zh_CN").args(["-l", "default"])), "CAT\n");
+    assert_eq!(stdout(cmd().env("CLITHEME_LOCALE", "zh_CN")), "猫\n");
+    assert_eq!(stdout(cmd().env("CLITHEME_LOCALE", "")), "Katze\n");
+    assert_eq!(stdout(&mut cmd()), "Katze\n");
+}
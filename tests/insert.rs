@@ -0,0 +1,26 @@
+mod common;
+
+use common::{clitheme, TempDir};
+
+/// 插入的行直接输出, 不会再经过任何规则, 即使它们能被规则匹配
+#[cfg(unix)]
+#[test]
+fn inserted_lines_are_not_reprocessed() {
+    let dir = TempDir::new("insert");
+    let theme = dir.write(
+        "theme.json",
+        r#"[
+            {"pattern": "^Running (.*)", "insert_before": "Running: $1"},
+            {"pattern": "Running", "replacement": "运行"}
+        ]"#,
+    );
+
+    let output = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "env", "sh", "-c", "echo Running test; echo done"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Running: test\n运行 test\ndone\n");
+}
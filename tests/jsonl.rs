@@ -0,0 +1,46 @@
+//! 子进程用 sh, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, TempDir};
+use serde_json::{json, Value};
+
+/// 每一行输出都是一条完整的 JSON 记录, 引号、反斜杠、控制字符和非 ASCII 文本都正确转义
+#[test]
+fn every_line_is_valid_json() {
+    let dir = TempDir::new("jsonl");
+    let theme = dir.write(
+        "theme.json",
+        r#"[
+            {"id": "quote", "pattern": "quote", "replacement": "引号 \"q\""},
+            {"id": "drop", "pattern": "^secret", "drop": true}
+        ]"#,
+    );
+    let script = r#"echo 'say "quote" \ 猫'; printf 'tab\there\033[1m\n'; echo secret; echo 错误 >&2; printf 'no newline'"#;
+    let output = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--output-format", "jsonl", "--", "env", "sh", "-c", script])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records: Vec<Value> = stdout.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let fields = |record: &Value| json!([record["stream"], record["raw"], record["themed"], record["rules"]]);
+    let mut records: Vec<_> = records.iter().inspect(|record| assert!(record["ts"].is_f64())).map(fields).collect();
+    // 两个输出流并发处理, stderr 的记录可能出现在任意位置
+    let stderr = records.iter().position(|record| record[0] == "stderr").unwrap();
+    assert_eq!(records.remove(stderr), json!(["stderr", "错误", "错误", []]));
+    assert_eq!(
+        records,
+        [
+            json!(["stdout", "say \"quote\" \\ 猫", "say \"引号 \"q\"\" \\ 猫", ["quote"]]),
+            json!(["stdout", "tab\there\x1b[1m", "tab\there\x1b[1m", []]),
+            json!(["stdout", "secret", null, ["drop"]]),
+            json!(["stdout", "no newline", "no newline", []]),
+        ]
+    );
+}
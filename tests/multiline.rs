@@ -0,0 +1,74 @@
+//! 子进程用 sh 和 sleep, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, wait_timeout, TempDir};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+
+/// 永远不匹配的 multiline 规则, 只让读到的行进入窗口
+const MULTILINE: &str = r#"[{"pattern": "never\\nmatches", "replacement": "x", "multiline": true}]"#;
+
+fn spawn(name: &str, theme: &str, options: &[&str], script: &str) -> (TempDir, Child) {
+    let dir = TempDir::new(name);
+    let theme = dir.write("theme.json", theme);
+    let child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(options)
+        .args(["--", "env", "sh", "-c", script])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    (dir, child)
+}
+
+/// 读取第一行, 返回这一行和从启动到读到它的时间; 读到时子进程应该仍在运行
+fn first_line(child: &mut Child, start: Instant) -> (String, Duration) {
+    let mut line = String::new();
+    BufReader::new(child.stdout.as_mut().unwrap()).read_line(&mut line).unwrap();
+    let elapsed = start.elapsed();
+    assert!(child.try_wait().unwrap().is_none(), "子进程已经退出");
+    (line, elapsed)
+}
+
+/// 窗口中的行在没有新的行 --multiline-hold 毫秒后输出, 不等子进程的下一行或退出
+#[test]
+fn held_line_appears_after_hold() {
+    let start = Instant::now();
+    let (_dir, mut child) = spawn("multiline-hold", MULTILINE, &["--multiline-hold", "300"], "echo first; sleep 3; echo done");
+    let (line, elapsed) = first_line(&mut child, start);
+    assert_eq!(line, "first\n");
+    assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    let _ = child.kill();
+}
+
+/// 没有 multiline 规则时每行立即输出, 与 --multiline-hold 无关
+#[test]
+fn single_line_themes_add_no_delay() {
+    let theme = r#"[{"pattern": "first", "replacement": "第一"}]"#;
+    let start = Instant::now();
+    let (_dir, mut child) = spawn("multiline-none", theme, &["--multiline-hold", "5000"], "echo first; sleep 3; echo done");
+    let (line, elapsed) = first_line(&mut child, start);
+    assert_eq!(line, "第一\n");
+    assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    let _ = child.kill();
+}
+
+/// 子进程退出时窗口中的行立即输出, 不等待 --multiline-hold; 过长的行不进入窗口, 先输出窗口中的行
+#[test]
+fn window_flushed_at_eof_and_before_oversized_lines() {
+    let long = "x".repeat(100);
+    let script = format!("printf 'a\\nb\\n{}\\nc\\nd'", long);
+    let start = Instant::now();
+    let (_dir, mut child) = spawn("multiline-eof", MULTILINE, &["--multiline-hold", "10000", "--max-line-length", "32"], &script);
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(wait_timeout(&mut child, Duration::from_secs(5)).success());
+    assert_eq!(output, format!("a\nb\n{}\nc\nd", long));
+    assert!(start.elapsed() < Duration::from_secs(5), "{:?}", start.elapsed());
+}
@@ -0,0 +1,119 @@
+//! 子进程用 sh 和 printf, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, TempDir};
+use std::process::{Command, Output};
+
+/// 用只替换 "newline" 的主题运行 sh 脚本
+fn themed(dir: &TempDir, script: &str, options: &[&str]) -> Output {
+    let theme = dir.write("theme.json", r#"[{"pattern": "newline", "replacement": "换行"}]"#);
+    let output = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(options)
+        .args(["--", "env", "sh", "-c", script])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+fn direct(script: &str) -> Output {
+    Command::new("sh").args(["-c", script]).output().unwrap()
+}
+
+/// 最后一行没有换行时, 输出也不补上换行
+#[test]
+fn final_line_without_newline() {
+    let dir = TempDir::new("no-newline");
+    let script = r#"echo first; printf "no end""#;
+    assert_eq!(themed(&dir, script, &[]).stdout, direct(script).stdout);
+    assert_eq!(themed(&dir, script, &[]).stdout, b"first\nno end");
+    assert_eq!(themed(&dir, script, &["--raw"]).stdout, b"first\nno end");
+
+    let script = r#"printf "no newline""#;
+    assert_eq!(themed(&dir, script, &[]).stdout, "no 换行".as_bytes());
+    assert_eq!(themed(&dir, r#"printf "no newline" >&2"#, &[]).stderr, "no 换行".as_bytes());
+}
+
+/// 无效的 UTF-8 字节原样输出, 之后的输出照常处理
+#[test]
+fn invalid_utf8_passes_through() {
+    let dir = TempDir::new("invalid-utf8");
+    let script = r"printf 'newline\n\377\376\nplain \377 text\nnewline again\n\376'";
+    let expected = ["换行\n".as_bytes(), b"\xff\xfe\nplain \xff text\n", "换行 again\n".as_bytes(), b"\xfe"].concat();
+    assert_eq!(themed(&dir, script, &[]).stdout, expected);
+    assert_eq!(themed(&dir, script, &["--raw"]).stdout, expected);
+    assert_eq!(themed(&dir, &format!("{} >&2", script), &[]).stderr, expected);
+}
+
+/// 100 MB 的单行分块直接输出, 内存占用不随行的长度增长, 之后的行照常处理
+#[cfg(target_os = "linux")]
+#[test]
+fn huge_line_keeps_memory_bounded() {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    const SIZE: usize = 100 * 1024 * 1024;
+    let dir = TempDir::new("huge-line");
+    let theme = dir.write("theme.json", r#"[{"pattern": "^end$", "replacement": "结束"}]"#);
+    let script = format!(r"head -c {} /dev/zero | tr '\0' a; echo; echo end", SIZE);
+    let mut child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "env", "sh", "-c", &script])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let status = format!("/proc/{}/status", child.id());
+    let mut stdout = child.stdout.take().unwrap();
+
+    // 边读边检查输出, 同时记录 clitheme 的内存峰值 (VmHWM)
+    let (mut total, mut tail, mut peak_kb) = (0, Vec::new(), 0);
+    let mut chunk = vec![0; 1 << 20];
+    loop {
+        let read = stdout.read(&mut chunk).unwrap();
+        if read == 0 {
+            break;
+        }
+        let data = &chunk[..read];
+        let body = data.len().min(SIZE.saturating_sub(total));
+        assert!(data[..body].iter().all(|&b| b == b'a'));
+        tail.extend_from_slice(&data[body..]);
+        total += read;
+        if let Some(kb) = std::fs::read_to_string(&status).ok().and_then(|status| {
+            let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+            line.split_whitespace().nth(1)?.parse::<usize>().ok()
+        }) {
+            peak_kb = peak_kb.max(kb);
+        }
+    }
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(tail, "\n结束\n".as_bytes());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("超过"));
+    assert!(peak_kb > 0 && peak_kb < 64 * 1024, "内存峰值 {} KB", peak_kb);
+}
+
+/// --no-theme-stdout/--no-theme-stderr 的输出流按字节原样复制: NUL、回车、无效的 UTF-8 和
+/// 能被规则匹配的文本都不变, 另一个输出流照常处理
+#[test]
+fn untouched_streams_are_binary_safe() {
+    let dir = TempDir::new("no-theme");
+    let mut bytes: Vec<u8> = (0..=255).collect();
+    bytes.extend_from_slice(b"\r\nnewline\r\n\x1b[2J newline");
+    let escaped: String = bytes.iter().map(|b| format!("\\{:03o}", b)).collect();
+    let script = |fd: u8, other: u8| format!("printf '{}' >&{}; echo newline >&{}", escaped, fd, other);
+
+    let output = themed(&dir, &script(1, 2), &["--no-theme-stdout"]);
+    assert_eq!(output.stdout, bytes);
+    assert_eq!(output.stdout, direct(&script(1, 2)).stdout);
+    assert_eq!(output.stderr, "换行\n".as_bytes());
+
+    let output = themed(&dir, &script(2, 1), &["--no-theme-stderr"]);
+    assert_eq!(output.stderr, bytes);
+    assert_eq!(output.stdout, "换行\n".as_bytes());
+}
@@ -0,0 +1,35 @@
+//! 子进程用 seq, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, wait_timeout, TempDir};
+use std::io::{BufRead, BufReader, Read};
+use std::process::Stdio;
+use std::time::Duration;
+
+/// 像 `| head -1` 一样读到第一行就关闭管道: clitheme 不输出任何错误,
+/// 继续读取并丢弃子进程的输出, 以子进程的退出码退出
+#[test]
+fn short_lived_reader() {
+    let dir = TempDir::new("pipe");
+    let theme = dir.write("theme.json", r#"[{"pattern": "^1$", "replacement": "一"}]"#);
+
+    let mut child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "seq", "200000"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut first = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut first).unwrap();
+    assert_eq!(first, "一\n");
+
+    let status = wait_timeout(&mut child, Duration::from_secs(30));
+    let mut stderr = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    assert_eq!(stderr, "");
+    assert!(status.success(), "{:?}", status);
+}
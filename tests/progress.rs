@@ -0,0 +1,58 @@
+//! 子进程用 sh 输出进度条, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, wait_timeout, TempDir};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// 用 `\r` 重绘的进度条, 每次重绘之间有停顿
+const PROGRESS: &str = r#"for p in 0 50 100; do printf 'progress %3d%%\r' $p; sleep 0.3; done; printf '\ndone\n'"#;
+
+/// 没有规则生效时, --raw 的输出与直接运行命令逐字节相同
+#[test]
+fn raw_mode_passes_progress_through() {
+    let dir = TempDir::new("progress-plain");
+    let theme = dir.write("theme.json", r#"[{"pattern": "unrelated", "replacement": "x"}]"#);
+    let direct = Command::new("sh").args(["-c", PROGRESS]).output().unwrap();
+    let themed = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--raw", "--", "env", "sh", "-c", PROGRESS])
+        .output()
+        .unwrap();
+    assert!(themed.status.success());
+    assert_eq!(themed.stdout, direct.stdout);
+}
+
+/// 每次重绘都立即输出并分别应用规则, 不会等到换行才一起输出
+#[test]
+fn raw_mode_themes_each_redraw_as_it_arrives() {
+    let dir = TempDir::new("progress-themed");
+    let theme = dir.write("theme.json", r#"[{"pattern": "^progress", "replacement": "进度"}]"#);
+    let mut child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--raw", "--", "env", "sh", "-c", PROGRESS])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let mut first = Vec::new();
+    let mut chunk = [0; 64];
+    while !first.ends_with(b"\r") {
+        let read = stdout.read(&mut chunk).unwrap();
+        assert!(read > 0, "输出提前结束: {:?}", String::from_utf8_lossy(&first));
+        first.extend_from_slice(&chunk[..read]);
+    }
+    assert_eq!(first, "进度   0%\r".as_bytes());
+    assert!(child.try_wait().unwrap().is_none(), "第一次重绘应在命令结束前输出");
+
+    let mut rest = Vec::new();
+    stdout.read_to_end(&mut rest).unwrap();
+    assert!(wait_timeout(&mut child, Duration::from_secs(10)).success());
+    assert_eq!(rest, "进度  50%\r进度 100%\r\ndone\n".as_bytes());
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::{clitheme, TempDir};
+
+/// --log-raw 记录的没有换行的提示符和 `\r` 刷新的进度条, 回放时逐字节还原
+#[cfg(unix)]
+#[test]
+fn log_raw_round_trip() {
+    let dir = TempDir::new("replay");
+    let theme = dir.write("theme.json", r#"{"replacements": []}"#);
+    let log = dir.path().join("raw.log");
+    let script = r#"printf '>>> '; sleep 0.2; printf 'hi\n'; printf 'downloading 10%%\rdownloading 99%%\rdone\n'; printf 'no newline'"#;
+    let expected = b">>> hi\ndownloading 10%\rdownloading 99%\rdone\nno newline";
+
+    let run = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .arg("--log-raw")
+        .arg(&log)
+        .args(["--", "env", "sh", "-c", script])
+        .output()
+        .unwrap();
+    assert!(run.status.success());
+    assert_eq!(run.stdout, expected);
+
+    let replay = clitheme().arg("replay").arg(&log).arg("-a").arg(&theme).output().unwrap();
+    assert!(replay.status.success());
+    assert_eq!(replay.stdout, expected);
+}
@@ -0,0 +1,147 @@
+//! 子进程用 sh、sleep 和 kill, 只在 Unix 上运行
+#![cfg(unix)]
+
+mod common;
+
+use common::{clitheme, wait_timeout, TempDir};
+use std::io::{BufRead, BufReader, Read};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::time::Duration;
+
+/// 在 clitheme 中运行 sh 脚本, 读到第一行 (子进程已经启动) 后向 clitheme 发送 SIGINT,
+/// 返回退出码、第一行之后的标准输出和标准错误
+fn interrupt(dir: &TempDir, script: &str) -> (Option<i32>, String, String) {
+    let theme = dir.write("theme.json", r#"[{"pattern": "^(\\w+)$", "replacement": "[$1]"}]"#);
+    let mut child = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "env", "sh", "-c", script])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut first = String::new();
+    stdout.read_line(&mut first).unwrap();
+    assert_eq!(first, "[start]\n");
+
+    // SAFETY: kill 只发送信号
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) }, 0);
+    let status = wait_timeout(&mut child, Duration::from_secs(5));
+    let (mut rest, mut stderr) = (String::new(), String::new());
+    stdout.read_to_string(&mut rest).unwrap();
+    child.stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+    (status.code(), rest, stderr)
+}
+
+/// SIGINT 转发给 sleep, sleep 被终止后 clitheme 立即以 128 + 2 退出, 而不是等 sleep 结束;
+/// 与 shell 一样不说明子进程被 SIGINT 终止
+#[test]
+fn sigint_terminates_sleep() {
+    let dir = TempDir::new("sigint-sleep");
+    let (code, rest, stderr) = interrupt(&dir, "echo start; exec sleep 30");
+    assert_eq!(code, Some(130));
+    assert_eq!(rest, "");
+    assert_eq!(stderr, "");
+}
+
+/// 子进程收到 SIGINT 后清理时的输出照常处理并全部写出, clitheme 以子进程的退出码退出
+#[test]
+fn sigint_lets_child_clean_up() {
+    let dir = TempDir::new("sigint-trap");
+    let script = "trap 'kill $!; echo cleanup; exit 3' INT; sleep 30 & echo start; wait";
+    let (code, rest, stderr) = interrupt(&dir, script);
+    assert_eq!(code, Some(3));
+    assert_eq!(rest, "[cleanup]\n");
+    assert_eq!(stderr, "");
+}
+
+/// 记录收到几次 SIGINT 的脚本: 收到后再等一会儿, 之后才到达的 SIGINT 也会被计入
+const COUNT_SIGINT: &str = "n=0; trap 'n=$((n+1))' INT; echo start; \
+    while [ $n -eq 0 ]; do sleep 0.05; done; sleep 1; echo \"count $n\"";
+
+/// 向 clitheme 所在的进程组发送 SIGINT (与终端的 Ctrl-C 相同), 返回退出码和第一行之后的标准输出
+///
+/// 发送期间 clitheme 处于暂停状态, 直接发给子进程的信号先被处理, clitheme 恢复后才转发;
+/// 否则两次 SIGINT 可能同时等待处理而被内核合并为一次, 无法区分
+fn interrupt_group(mut cmd: Command) -> (Option<i32>, String) {
+    let mut child = cmd
+        .args(["--", "env", "sh", "-c", COUNT_SIGINT])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut first = String::new();
+    stdout.read_line(&mut first).unwrap();
+    assert_eq!(first, "start\n");
+
+    let pid = child.id() as libc::pid_t;
+    // SAFETY: kill 和 killpg 只发送信号
+    unsafe {
+        assert_eq!(libc::kill(pid, libc::SIGSTOP), 0);
+        assert_eq!(libc::killpg(pid, libc::SIGINT), 0);
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(libc::kill(pid, libc::SIGCONT), 0);
+    }
+    let status = wait_timeout(&mut child, Duration::from_secs(5));
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    (status.code(), rest)
+}
+
+fn theme(dir: &TempDir) -> Command {
+    let theme = dir.write("theme.json", r#"[{"pattern": "unrelated", "replacement": "x"}]"#);
+    let mut cmd = clitheme();
+    cmd.arg("-a").arg(&theme);
+    cmd
+}
+
+/// 没有终端时子进程有单独的进程组, 发给 clitheme 进程组的 SIGINT 只经转发到达子进程一次
+#[test]
+fn group_sigint_without_terminal_reaches_child_once() {
+    let dir = TempDir::new("sigint-group");
+    let mut cmd = theme(&dir);
+    cmd.process_group(0);
+    assert_eq!(interrupt_group(cmd), (Some(0), "count 1\n".to_string()));
+}
+
+/// 在终端前台运行时子进程与 clitheme 同在前台进程组, 终端的 Ctrl-C 已经到达子进程, 不再重复转发
+#[test]
+fn terminal_sigint_is_not_forwarded_again() {
+    let dir = TempDir::new("sigint-terminal");
+    let (mut master, mut slave) = (-1, -1);
+    // SAFETY: openpty 只写入两个文件描述符
+    let result = unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()) };
+    assert_eq!(result, 0);
+    // SAFETY: openpty 成功时两个描述符都是新打开的; 主设备保持打开, 否则从设备挂断
+    let (_master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+    let mut cmd = theme(&dir);
+    cmd.stdin(slave);
+    // SAFETY: 闭包在 fork 之后的子进程中运行, 只调用异步信号安全的 setsid 和 ioctl;
+    // clitheme 成为新会话的首进程, 以伪终端为控制终端并在它的前台进程组中
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    assert_eq!(interrupt_group(cmd), (Some(0), "count 1\n".to_string()));
+}
+
+/// 子进程被 SIGSEGV 终止时与 shell 一样以 128 + 11 退出, 并在标准错误说明
+#[test]
+fn segfault_exit_code() {
+    let dir = TempDir::new("sigsegv");
+    let theme = dir.write("theme.json", r#"[{"pattern": "unrelated", "replacement": "x"}]"#);
+    let output = clitheme().arg("-a").arg(&theme).args(["--", "env", "sh", "-c", "kill -SEGV $$"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(139));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("子进程被信号 11 (SIGSEGV) 终止"), "{}", stderr);
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use common::{clitheme, TempDir};
+
+/// 只作用于 stderr 的规则不改变 stdout 上的同一行
+#[cfg(unix)]
+#[test]
+fn stderr_rule_never_touches_stdout() {
+    let dir = TempDir::new("streams");
+    let theme = dir.write("theme.json", r#"[{"pattern": "error", "replacement": "错误", "streams": "stderr"}]"#);
+
+    let output = clitheme()
+        .arg("-a")
+        .arg(&theme)
+        .args(["--", "env", "sh", "-c", "echo error; echo error >&2"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"error\n");
+    assert_eq!(output.stderr, "错误\n".as_bytes());
+}